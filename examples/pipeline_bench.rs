@@ -0,0 +1,79 @@
+//! Measures throughput of pipelined commands against a running server.
+//!
+//! `cargo run --release --example pipeline_bench -- [port] [pipeline length]`
+
+use std::{
+    env,
+    io::{Read, Write},
+    net::TcpStream,
+    time::Instant,
+};
+
+fn encode(arguments: &[&str]) -> String {
+    let mut result = format!("*{}\r\n", arguments.len());
+    for argument in arguments {
+        result += &format!("${}\r\n{argument}\r\n", argument.len());
+    }
+    result
+}
+
+/// reads until `count` complete top level replies have arrived, replies here are all single line or bulk strings
+fn read_replies(stream: &mut TcpStream, count: usize) {
+    let mut buffer = vec![0u8; 1 << 16];
+    let mut pending: Vec<u8> = Vec::new();
+    let mut replies = 0;
+    while replies < count {
+        let read = stream
+            .read(&mut buffer)
+            .expect("server closed the connection");
+        pending.extend_from_slice(&buffer[..read]);
+        let mut position = 0;
+        while let Some(offset) = pending[position..].windows(2).position(|w| w == b"\r\n") {
+            let line_end = position + offset + 2;
+            let mut reply_end = line_end;
+            if pending[position] == b'$' {
+                let length: i64 = std::str::from_utf8(&pending[position + 1..line_end - 2])
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if length >= 0 {
+                    reply_end += length as usize + 2;
+                }
+            }
+            if reply_end > pending.len() {
+                break;
+            }
+            position = reply_end;
+            replies += 1;
+        }
+        pending.drain(..position);
+    }
+}
+
+/// a name and how to build the command for pipeline slot `i`
+type Benchmark = (&'static str, fn(usize) -> String);
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let port = args.get(1).map_or("6379", |s| s.as_str());
+    let length: usize = args.get(2).map_or(10_000, |s| s.parse().unwrap());
+    let mut stream = TcpStream::connect(format!("127.0.0.1:{port}")).unwrap();
+
+    let benchmarks: [Benchmark; 3] = [
+        ("SET", |i| encode(&["SET", &format!("key:{i}"), "value"])),
+        ("GET", |i| encode(&["GET", &format!("key:{i}")])),
+        ("PING", |_| encode(&["PING"])),
+    ];
+    for (name, command) in benchmarks {
+        let pipeline: String = (0..length).map(command).collect();
+        let start = Instant::now();
+        stream.write_all(pipeline.as_bytes()).unwrap();
+        read_replies(&mut stream, length);
+        let elapsed = start.elapsed();
+        println!(
+            "{name}: {length} command pipeline in {:.1} ms, {:.0} ops/s",
+            elapsed.as_secs_f64() * 1000.0,
+            length as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use thiserror::Error;
+
+/// Everything that can go wrong while handling a request. The display strings are exactly what
+/// gets sent back to the client as a simple error.
+#[derive(Debug, Error)]
+pub enum RedisError {
+    #[error("ERR Protocol error: {0}")]
+    Protocol(String),
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR Invalid stream ID specified as stream command argument")]
+    InvalidStreamId,
+    #[error("ERR unknown command '{0}', with args beginning with: {1}")]
+    UnknownCommand(String, String),
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    #[error("ERR unknown subcommand '{0}'. Try {1} HELP.")]
+    UnknownSubcommand(String, String),
+    #[error("NOPROTO unsupported protocol version")]
+    NoProto,
+    #[error("WRONGPASS invalid username-password pair or user is disabled.")]
+    WrongPass,
+    #[error("NOAUTH {0}")]
+    NoAuth(String),
+    #[error("NOPERM {0}")]
+    NoPerm(String),
+    #[error("ERR {0}")]
+    Generic(String),
+    #[error("replication error: {0}")]
+    Replication(String),
+    #[error("ERR Bad RDB format: {0}")]
+    Rdb(String),
+    #[error("ERR Bad file format reading the append only file: {0}")]
+    Aof(String),
+    #[error("ERR I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl RedisError {
+    /// the peer went away (or a non blocking read had nothing for us), as opposed to sending something bad
+    pub fn is_disconnect(&self) -> bool {
+        matches!(self, RedisError::Io(_))
+    }
+}
+
+pub type RedisResult<T> = Result<T, RedisError>;
@@ -0,0 +1,209 @@
+use std::{sync::Arc, time::SystemTime};
+
+use crate::{
+    error::{RedisError, RedisResult},
+    structs::{client::Client, server::Server},
+    utils::{arg_parse, resp_parser::RedisType},
+};
+
+use super::{
+    command_table::{self, ACL_CATEGORIES, SUBCOMMAND_ACL_CATEGORIES},
+    utils::convert_to_redis_bulk_string_array,
+};
+
+/// ACL and its subcommands, WHOAMI needs the connection so the connection loop runs them
+pub fn acl(
+    arguments: &[Vec<u8>],
+    client: &Arc<Client>,
+    server: &Arc<Server>,
+) -> RedisResult<RedisType> {
+    let subcommand = arg_parse::to_text(&arguments[1]).to_ascii_lowercase();
+    match subcommand.as_str() {
+        "setuser" if arguments.len() >= 3 => {
+            server
+                .acl
+                .set_user(&arg_parse::to_text(&arguments[2]), &arguments[3..])?;
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "getuser" if arguments.len() == 3 => {
+            Ok(get_user(&arg_parse::to_text(&arguments[2]), server))
+        }
+        "deluser" if arguments.len() >= 3 => {
+            let deleted = server.acl.delete_users(&arguments[2..])?;
+            disconnect_removed_users(client, server);
+            Ok(RedisType::Integer(deleted.len() as i64))
+        }
+        "list" if arguments.len() == 2 => {
+            let lines: Vec<String> = server
+                .acl
+                .users()
+                .iter()
+                .map(|user| user.describe())
+                .collect();
+            Ok(convert_to_redis_bulk_string_array(lines))
+        }
+        "users" if arguments.len() == 2 => {
+            let users = server.acl.users();
+            Ok(convert_to_redis_bulk_string_array(
+                users.iter().map(|user| user.name.as_str()).collect(),
+            ))
+        }
+        "whoami" if arguments.len() == 2 => Ok(RedisType::BulkString(Some(
+            client.user.read().unwrap().clone().into(),
+        ))),
+        "cat" if arguments.len() <= 3 => category(arguments),
+        "log" if arguments.len() <= 3 => log(arguments, server),
+        "dryrun" if arguments.len() >= 4 => dry_run(arguments, server),
+        "load" if arguments.len() == 2 => {
+            server.acl.load()?;
+            disconnect_removed_users(client, server);
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "save" if arguments.len() == 2 => {
+            server.acl.save()?;
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "setuser" | "getuser" | "deluser" | "list" | "users" | "whoami" | "cat" | "log"
+        | "dryrun" | "load" | "save" => Err(RedisError::WrongArity(format!("acl|{subcommand}"))),
+        _ => Err(RedisError::UnknownSubcommand(
+            arg_parse::to_text(&arguments[1]),
+            "ACL".to_owned(),
+        )),
+    }
+}
+
+fn get_user(name: &str, server: &Arc<Server>) -> RedisType {
+    let user_option = server.acl.get_user(name);
+    if user_option.is_none() {
+        return RedisType::Null;
+    }
+    let user = user_option.unwrap();
+    let field = |name: &str| RedisType::BulkString(Some(name.into()));
+    RedisType::Map(vec![
+        (
+            field("flags"),
+            convert_to_redis_bulk_string_array(user.flags()),
+        ),
+        (
+            field("passwords"),
+            convert_to_redis_bulk_string_array(user.passwords().clone()),
+        ),
+        (field("commands"), field(&user.command_rules())),
+        (field("keys"), field(&user.key_rules())),
+        (field("channels"), field(&user.channel_rules())),
+        (field("selectors"), RedisType::Array(Vec::new())),
+    ])
+}
+
+/// connections logged in as a user that no longer exists get closed, like redis
+fn disconnect_removed_users(client: &Arc<Client>, server: &Arc<Server>) {
+    let clients = server.clients.read().unwrap();
+    for target in clients.values() {
+        let user = target.user.read().unwrap().clone();
+        if server.acl.get_user(&user).is_none() {
+            target.kill(target.id == client.id);
+        }
+    }
+}
+
+/// ACL CAT [category], every category or the commands in one
+fn category(arguments: &[Vec<u8>]) -> RedisResult<RedisType> {
+    if arguments.len() == 2 {
+        return Ok(convert_to_redis_bulk_string_array(Vec::from(
+            ACL_CATEGORIES,
+        )));
+    }
+    let category = arg_parse::to_text(&arguments[2]).to_ascii_lowercase();
+    if !ACL_CATEGORIES.contains(&category.as_str()) {
+        return Err(RedisError::Generic(format!(
+            "Unknown category '{}'",
+            arg_parse::to_text(&arguments[2])
+        )));
+    }
+    let mut names: Vec<&str> = command_table::all()
+        .iter()
+        .filter(|spec| spec.acl_categories().contains(&category.as_str()))
+        .map(|spec| spec.name)
+        .collect();
+    for (subcommand, categories) in SUBCOMMAND_ACL_CATEGORIES {
+        if categories.contains(&category.as_str()) {
+            names.push(subcommand);
+        }
+    }
+    Ok(convert_to_redis_bulk_string_array(names))
+}
+
+/// ACL LOG [count | RESET]
+fn log(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
+    let mut count = 10;
+    if arguments.len() == 3 {
+        if arguments[2].eq_ignore_ascii_case(b"reset") {
+            server.acl.reset_log();
+            return Ok(RedisType::SimpleString("OK".to_owned()));
+        }
+        count = arg_parse::parse::<usize>(&arguments[2]).ok_or(RedisError::NotInteger)?;
+    }
+
+    let now = SystemTime::now();
+    let milliseconds = |time: SystemTime| {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64
+    };
+    let field = |name: &str| RedisType::BulkString(Some(name.into()));
+    let entries = server
+        .acl
+        .log_entries(count)
+        .into_iter()
+        .map(|entry| {
+            RedisType::Map(vec![
+                (field("count"), RedisType::Integer(entry.count as i64)),
+                (field("reason"), field(&entry.reason)),
+                (field("context"), field(&entry.context)),
+                (field("object"), field(&entry.object)),
+                (field("username"), field(&entry.username)),
+                (
+                    field("age-seconds"),
+                    RedisType::Double(
+                        now.duration_since(entry.created)
+                            .unwrap_or_default()
+                            .as_secs_f64(),
+                    ),
+                ),
+                (field("client-info"), field(&entry.client_info)),
+                (field("entry-id"), RedisType::Integer(entry.entry_id as i64)),
+                (
+                    field("timestamp-created"),
+                    RedisType::Integer(milliseconds(entry.created)),
+                ),
+                (
+                    field("timestamp-last-updated"),
+                    RedisType::Integer(milliseconds(entry.updated)),
+                ),
+            ])
+        })
+        .collect();
+    Ok(RedisType::Array(entries))
+}
+
+/// ACL DRYRUN username command [arg ...], whether the user could run the command, without running it
+fn dry_run(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
+    let username = &arg_parse::to_text(&arguments[2]);
+    if server.acl.get_user(username).is_none() {
+        return Err(RedisError::Generic(format!("User '{username}' not found")));
+    }
+    let command = Vec::from(&arguments[3..]);
+    let name = arg_parse::to_text(&command[0]);
+    let spec_option = command_table::lookup(&name.to_ascii_lowercase());
+    if spec_option.is_none() {
+        return Err(RedisError::Generic(format!("Command '{name}' not found")));
+    }
+    let spec = spec_option.unwrap();
+    if !command_table::arity_matches(spec, &command) {
+        return Err(RedisError::WrongArity(spec.name.to_owned()));
+    }
+    if let Some(denial) = server.acl.check(username, spec, &command) {
+        return Ok(RedisType::BulkString(Some(denial.message.into())));
+    }
+    Ok(RedisType::SimpleString("OK".to_owned()))
+}
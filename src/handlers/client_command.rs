@@ -0,0 +1,646 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    error::{RedisError, RedisResult},
+    structs::{
+        client::{Client, ReplyMode, TrackingState},
+        server::Server,
+    },
+    utils::{arg_parse, resp_parser::RedisType},
+};
+
+use super::utils::convert_to_redis_bulk_strings;
+
+/// CLIENT and its subcommands, they all act on the calling connection so the connection loop runs them
+pub fn client(
+    arguments: &[Vec<u8>],
+    client: &Arc<Client>,
+    server: &Arc<Server>,
+) -> RedisResult<RedisType> {
+    let subcommand = arg_parse::to_text(&arguments[1]).to_ascii_lowercase();
+    match subcommand.as_str() {
+        "id" if arguments.len() == 2 => Ok(RedisType::Integer(client.id as i64)),
+        "setname" if arguments.len() == 3 => {
+            client.set_name(&arguments[2])?;
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "getname" if arguments.len() == 2 => Ok(RedisType::BulkString(
+            client.name.read().unwrap().clone().map(String::into_bytes),
+        )),
+        "list" => list(arguments, server),
+        "info" if arguments.len() == 2 => Ok(RedisType::VerbatimString(format!(
+            "txt:{}\n",
+            describe(client, server)
+        ))),
+        "kill" if arguments.len() >= 3 => kill(arguments, client, server),
+        "pause" if arguments.len() == 3 || arguments.len() == 4 => pause(arguments, server),
+        "unpause" if arguments.len() == 2 => {
+            server.unpause_clients();
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "no-evict" if arguments.len() == 3 => {
+            // there's no eviction yet, the flag only shows up in CLIENT LIST
+            *client.no_evict.write().unwrap() = on_or_off(&arguments[2])?;
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "reply" if arguments.len() == 3 => reply(arguments, client),
+        "tracking" if arguments.len() >= 3 => tracking(arguments, client, server),
+        "caching" if arguments.len() == 3 => caching(arguments, client),
+        "trackinginfo" if arguments.len() == 2 => Ok(tracking_info(client)),
+        "getredir" if arguments.len() == 2 => Ok(RedisType::Integer(redirect_id(
+            &client.tracking.read().unwrap(),
+        ))),
+        "id" | "setname" | "getname" | "info" | "kill" | "pause" | "unpause" | "no-evict"
+        | "reply" | "tracking" | "caching" | "trackinginfo" | "getredir" => {
+            Err(RedisError::WrongArity(format!("client|{subcommand}")))
+        }
+        _ => Err(RedisError::UnknownSubcommand(
+            arg_parse::to_text(&arguments[1]),
+            "CLIENT".to_owned(),
+        )),
+    }
+}
+
+fn on_or_off(argument: &[u8]) -> RedisResult<bool> {
+    match argument.to_ascii_lowercase().as_slice() {
+        b"on" => Ok(true),
+        b"off" => Ok(false),
+        _ => Err(RedisError::Syntax),
+    }
+}
+
+fn parse_client_id(argument: &[u8]) -> RedisResult<u64> {
+    arg_parse::parse::<u64>(argument)
+        .ok_or_else(|| RedisError::Generic("Invalid client ID".to_owned()))
+}
+
+/// normal, pubsub or replica. Replicas leave the registry once they PSYNC so they're never listed.
+fn client_type(client: &Client) -> &'static str {
+    if client.is_subscribed() {
+        return "pubsub";
+    }
+    "normal"
+}
+
+fn parse_client_type(argument: &[u8]) -> RedisResult<&'static str> {
+    let argument = arg_parse::to_text(argument);
+    match argument.to_ascii_lowercase().as_str() {
+        "normal" => Ok("normal"),
+        "pubsub" => Ok("pubsub"),
+        "replica" | "slave" => Ok("replica"),
+        "master" => Ok("master"),
+        _ => Err(RedisError::Generic(format!(
+            "Unknown client type '{argument}'"
+        ))),
+    }
+}
+
+/// the single letter flags redis shows in CLIENT LIST
+fn flags(client: &Client, server: &Server) -> String {
+    let mut flags = String::new();
+    if server
+        .monitors
+        .read()
+        .unwrap()
+        .iter()
+        .any(|monitor| monitor.id == client.id)
+    {
+        flags.push('O');
+    }
+    if client.is_subscribed() {
+        flags.push('P');
+    }
+    if client.multi.read().unwrap().is_some() {
+        flags.push('x');
+    }
+    let tracking = client.tracking.read().unwrap();
+    if tracking.enabled {
+        flags.push('t');
+    }
+    if tracking.broken_redirect {
+        flags.push('R');
+    }
+    if tracking.bcast {
+        flags.push('B');
+    }
+    drop(tracking);
+    if *client.no_evict.read().unwrap() {
+        flags.push('e');
+    }
+    if flags.is_empty() {
+        flags.push('N');
+    }
+    flags
+}
+
+/// a client's line in CLIENT LIST and CLIENT INFO
+pub fn describe(client: &Client, server: &Server) -> String {
+    let now = SystemTime::now();
+    let age = now.duration_since(client.created).unwrap_or_default();
+    let idle = now
+        .duration_since(*client.last_interaction.read().unwrap())
+        .unwrap_or_default();
+    let multi = client
+        .multi
+        .read()
+        .unwrap()
+        .map_or(-1, |queued| queued as i64);
+    let redirect = client.tracking.read().unwrap().redirect;
+    format!(
+        "id={} addr={} laddr={} name={} age={} idle={} flags={} db=0 sub={} psub={} ssub={} multi={multi} cmd={} user={} redir={} resp={}",
+        client.id,
+        client.addr,
+        client.laddr,
+        client.name.read().unwrap().clone().unwrap_or_default(),
+        age.as_secs(),
+        idle.as_secs(),
+        flags(client, server),
+        client.channels.read().unwrap().len(),
+        client.patterns.read().unwrap().len(),
+        client.shard_subscription_count(),
+        client.last_command.read().unwrap(),
+        client.user.read().unwrap(),
+        redirect.map_or(-1, |id| id as i64),
+        client.protover(),
+    )
+}
+
+/// CLIENT LIST [TYPE type] [ID id [id ...]]
+fn list(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
+    let mut type_filter: Option<&str> = None;
+    let mut ids: Option<Vec<u64>> = None;
+    let mut i = 2;
+    while i < arguments.len() {
+        match arg_parse::to_text(&arguments[i])
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "type" if i + 1 < arguments.len() => {
+                type_filter = Some(parse_client_type(&arguments[i + 1])?);
+                i += 2;
+            }
+            "id" if i + 1 < arguments.len() => {
+                let mut id_list = Vec::new();
+                for argument in &arguments[i + 1..] {
+                    id_list.push(parse_client_id(argument)?);
+                }
+                ids = Some(id_list);
+                i = arguments.len();
+            }
+            _ => return Err(RedisError::Syntax),
+        }
+    }
+
+    let clients = server.clients.read().unwrap();
+    let mut listed: Vec<&Arc<Client>> = clients
+        .values()
+        .filter(|client| type_filter.is_none() || type_filter == Some(client_type(client)))
+        .filter(|client| ids.is_none() || ids.as_ref().unwrap().contains(&client.id))
+        .collect();
+    listed.sort_by_key(|client| client.id);
+    let mut result = String::from("txt:");
+    for client in listed {
+        result += &describe(client, server);
+        result.push('\n');
+    }
+    Ok(RedisType::VerbatimString(result))
+}
+
+/// CLIENT KILL addr:port, or the filter form that replies with how many connections it closed
+fn kill(
+    arguments: &[Vec<u8>],
+    client: &Arc<Client>,
+    server: &Arc<Server>,
+) -> RedisResult<RedisType> {
+    if arguments.len() == 3 {
+        let clients = server.clients.read().unwrap();
+        let target_option = clients
+            .values()
+            .find(|target| target.addr.as_bytes() == arguments[2].as_slice());
+        if target_option.is_none() {
+            return Err(RedisError::Generic("No such client".to_owned()));
+        }
+        let target = target_option.unwrap();
+        target.kill(target.id == client.id);
+        return Ok(RedisType::SimpleString("OK".to_owned()));
+    }
+
+    let mut id: Option<u64> = None;
+    let mut addr: Option<&Vec<u8>> = None;
+    let mut laddr: Option<&Vec<u8>> = None;
+    let mut user: Option<&Vec<u8>> = None;
+    let mut type_filter: Option<&str> = None;
+    let mut max_age: Option<u64> = None;
+    let mut skip_me = true;
+    for pair in arguments[2..].chunks(2) {
+        if pair.len() != 2 {
+            return Err(RedisError::Syntax);
+        }
+        let value = &pair[1];
+        match arg_parse::to_text(&pair[0]).to_ascii_lowercase().as_str() {
+            "id" => id = Some(parse_client_id(value)?),
+            "addr" => addr = Some(value),
+            "laddr" => laddr = Some(value),
+            "user" => user = Some(value),
+            "type" => type_filter = Some(parse_client_type(value)?),
+            "maxage" => {
+                max_age = Some(arg_parse::parse::<u64>(value).ok_or(RedisError::NotInteger)?)
+            }
+            "skipme" => {
+                skip_me = match value.to_ascii_lowercase().as_slice() {
+                    b"yes" => true,
+                    b"no" => false,
+                    _ => return Err(RedisError::Syntax),
+                }
+            }
+            _ => return Err(RedisError::Syntax),
+        }
+    }
+
+    let now = SystemTime::now();
+    let clients = server.clients.read().unwrap();
+    let mut killed = 0;
+    for target in clients.values() {
+        let age = now.duration_since(target.created).unwrap_or_default();
+        let matches = id.is_none_or(|id| target.id == id)
+            && addr.is_none_or(|addr| target.addr.as_bytes() == addr.as_slice())
+            && laddr.is_none_or(|laddr| target.laddr.as_bytes() == laddr.as_slice())
+            && user.is_none_or(|user| target.user.read().unwrap().as_bytes() == user.as_slice())
+            && type_filter.is_none_or(|type_filter| client_type(target) == type_filter)
+            && max_age.is_none_or(|max_age| age.as_secs() >= max_age)
+            && !(skip_me && target.id == client.id);
+        if matches {
+            target.kill(target.id == client.id);
+            killed += 1;
+        }
+    }
+    drop(clients);
+
+    // replicas aren't in the registry, their connection is owned by the replication loop
+    if type_filter == Some("replica") && id.is_none() && laddr.is_none() && user.is_none() {
+        let replication_vec = server.connected_replications.read().unwrap();
+        for replication in replication_vec.iter() {
+            let peer = replication.stream.peer_addr().map(|peer| peer.to_string());
+            if addr.is_none_or(|addr| {
+                peer.as_ref()
+                    .is_ok_and(|peer| peer.as_bytes() == addr.as_slice())
+            }) {
+                let _ = replication.stream.shutdown(std::net::Shutdown::Both);
+                killed += 1;
+            }
+        }
+    }
+    Ok(RedisType::Integer(killed))
+}
+
+/// CLIENT PAUSE timeout [WRITE | ALL]
+fn pause(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
+    let timeout = arg_parse::parse::<u64>(&arguments[2]).ok_or_else(|| {
+        RedisError::Generic("timeout is not an integer or out of range".to_owned())
+    })?;
+    let all = match arguments.get(3).map(|mode| mode.to_ascii_lowercase()) {
+        None => true,
+        Some(mode) if mode == b"all" => true,
+        Some(mode) if mode == b"write" => false,
+        _ => return Err(RedisError::Syntax),
+    };
+    server.pause_clients(SystemTime::now() + Duration::from_millis(timeout), all);
+    Ok(RedisType::SimpleString("OK".to_owned()))
+}
+
+/// CLIENT REPLY ON | OFF | SKIP, only ON gets a reply
+fn reply(arguments: &[Vec<u8>], client: &Arc<Client>) -> RedisResult<RedisType> {
+    let mode = match arguments[2].to_ascii_lowercase().as_slice() {
+        b"on" => ReplyMode::On,
+        b"off" => ReplyMode::Off,
+        b"skip" => ReplyMode::SkipNext,
+        _ => return Err(RedisError::Syntax),
+    };
+    *client.reply_mode.write().unwrap() = mode;
+    Ok(RedisType::SimpleString("OK".to_owned()))
+}
+
+fn tracking(
+    arguments: &[Vec<u8>],
+    client: &Arc<Client>,
+    server: &Arc<Server>,
+) -> RedisResult<RedisType> {
+    let on = on_or_off(&arguments[2])?;
+
+    let mut redirect: Option<u64> = None;
+    let mut bcast = false;
+    let mut optin = false;
+    let mut optout = false;
+    let mut prefixes: Vec<Vec<u8>> = Vec::new();
+    let mut i = 3;
+    while i < arguments.len() {
+        match arg_parse::to_text(&arguments[i])
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "redirect" if i + 1 < arguments.len() => {
+                redirect = Some(parse_client_id(&arguments[i + 1])?);
+                i += 2;
+            }
+            "prefix" if i + 1 < arguments.len() => {
+                prefixes.push(arguments[i + 1].clone());
+                i += 2;
+            }
+            "bcast" => {
+                bcast = true;
+                i += 1;
+            }
+            "optin" => {
+                optin = true;
+                i += 1;
+            }
+            "optout" => {
+                optout = true;
+                i += 1;
+            }
+            _ => return Err(RedisError::Syntax),
+        }
+    }
+
+    if !on {
+        server.tracking.remove_client(client);
+        *client.tracking.write().unwrap() = TrackingState::default();
+        return Ok(RedisType::SimpleString("OK".to_owned()));
+    }
+
+    if !prefixes.is_empty() && !bcast {
+        return Err(RedisError::Generic(
+            "PREFIX option requires BCAST mode to be enabled".to_owned(),
+        ));
+    }
+    if optin && optout {
+        return Err(RedisError::Generic(
+            "You can't use both OPTIN and OPTOUT".to_owned(),
+        ));
+    }
+    if bcast && (optin || optout) {
+        return Err(RedisError::Generic(
+            "OPTIN and OPTOUT are not compatible with BCAST".to_owned(),
+        ));
+    }
+    if redirect.is_some()
+        && !server
+            .clients
+            .read()
+            .unwrap()
+            .contains_key(&redirect.unwrap())
+    {
+        return Err(RedisError::Generic(
+            "The client ID you want redirect to does not exist".to_owned(),
+        ));
+    }
+
+    let mut state = client.tracking.write().unwrap();
+    if state.enabled && state.bcast != bcast {
+        return Err(RedisError::Generic("You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.".to_owned()));
+    }
+    if state.enabled && (state.optin != optin || state.optout != optout) {
+        return Err(RedisError::Generic("You can't switch OPTIN/OPTOUT mode before disabling tracking for this client, and then re-enabling it with a different mode.".to_owned()));
+    }
+
+    if bcast && prefixes.is_empty() {
+        prefixes.push(Vec::new()); // no prefix means every key
+    }
+    for prefix in &prefixes {
+        if !state.prefixes.contains(prefix) {
+            state.prefixes.push(prefix.clone());
+        }
+    }
+    state.enabled = true;
+    state.bcast = bcast;
+    state.optin = optin;
+    state.optout = optout;
+    state.redirect = redirect;
+    state.broken_redirect = false;
+    state.caching = None;
+    drop(state);
+
+    server.tracking.add_prefixes(client, &prefixes);
+    Ok(RedisType::SimpleString("OK".to_owned()))
+}
+
+fn caching(arguments: &[Vec<u8>], client: &Arc<Client>) -> RedisResult<RedisType> {
+    let mut state = client.tracking.write().unwrap();
+    if !state.enabled || !(state.optin || state.optout) {
+        return Err(RedisError::Generic("CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled".to_owned()));
+    }
+    match arguments[2].to_ascii_lowercase().as_slice() {
+        b"yes" if state.optin => state.caching = Some(true),
+        b"no" if state.optout => state.caching = Some(false),
+        b"yes" => {
+            return Err(RedisError::Generic(
+                "CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode."
+                    .to_owned(),
+            ))
+        }
+        b"no" => {
+            return Err(RedisError::Generic(
+                "CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode."
+                    .to_owned(),
+            ))
+        }
+        _ => return Err(RedisError::Syntax),
+    }
+    Ok(RedisType::SimpleString("OK".to_owned()))
+}
+
+fn tracking_info(client: &Arc<Client>) -> RedisType {
+    let state = client.tracking.read().unwrap();
+    let mut flags: Vec<&str> = Vec::new();
+    if !state.enabled {
+        flags.push("off");
+    } else {
+        flags.push("on");
+        if state.bcast {
+            flags.push("bcast");
+        }
+        if state.optin {
+            flags.push("optin");
+            if state.caching == Some(true) {
+                flags.push("caching-yes");
+            }
+        }
+        if state.optout {
+            flags.push("optout");
+            if state.caching == Some(false) {
+                flags.push("caching-no");
+            }
+        }
+        if state.broken_redirect {
+            flags.push("broken_redirect");
+        }
+    }
+
+    RedisType::Map(vec![
+        (
+            RedisType::BulkString(Some("flags".into())),
+            RedisType::Set(convert_to_redis_bulk_strings(flags)),
+        ),
+        (
+            RedisType::BulkString(Some("redirect".into())),
+            RedisType::Integer(redirect_id(&state)),
+        ),
+        (
+            RedisType::BulkString(Some("prefixes".into())),
+            RedisType::Array(convert_to_redis_bulk_strings(
+                state.prefixes.iter().collect(),
+            )),
+        ),
+    ])
+}
+
+/// -1 when tracking is off, 0 when invalidations go to the client itself
+fn redirect_id(state: &TrackingState) -> i64 {
+    if !state.enabled {
+        return -1;
+    }
+    state.redirect.map_or(0, |id| id as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        handlers::server_handler::tests::{
+            assert_received, assert_reply, connect, send_command, start_server, test_config,
+        },
+        utils::resp_parser::{self, RedisType},
+    };
+    use std::{
+        io::Read,
+        net::TcpStream,
+        time::{Duration, Instant},
+    };
+
+    fn hello_3(stream: &mut TcpStream) {
+        send_command(stream, &["HELLO", "3"]);
+        resp_parser::decode(stream).unwrap();
+    }
+
+    #[test]
+    fn tracking_invalidates_keys_the_client_read_once() {
+        let (_server, port) = start_server(test_config("tracking"));
+        let mut tracker = connect(port);
+        let mut writer = connect(port);
+        hello_3(&mut tracker);
+        assert_reply(&mut tracker, &["CLIENT", "TRACKING", "ON"], b"+OK\r\n");
+        assert_reply(&mut tracker, &["GET", "k"], b"_\r\n");
+
+        assert_reply(&mut writer, &["SET", "k", "1"], b"+OK\r\n");
+        assert_received(
+            &mut tracker,
+            b">2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\nk\r\n",
+        );
+        // it hasn't read the key since, so the next change isn't sent
+        assert_reply(&mut writer, &["SET", "k", "2"], b"+OK\r\n");
+        assert_reply(&mut tracker, &["PING"], b"+PONG\r\n");
+
+        assert_reply(&mut writer, &["FLUSHALL"], b"+OK\r\n");
+        assert_received(&mut tracker, b">2\r\n$10\r\ninvalidate\r\n_\r\n");
+    }
+
+    #[test]
+    fn tracking_redirects_to_a_resp2_subscriber() {
+        let (_server, port) = start_server(test_config("tracking-redirect"));
+        let mut tracker = connect(port);
+        let mut redirect = connect(port);
+        let mut writer = connect(port);
+        send_command(&mut redirect, &["CLIENT", "ID"]);
+        let redirect_id = match resp_parser::decode(&mut redirect).unwrap().0 {
+            RedisType::Integer(id) => id.to_string(),
+            reply => panic!("unexpected reply {reply:?}"),
+        };
+        assert_reply(
+            &mut redirect,
+            &["SUBSCRIBE", "__redis__:invalidate"],
+            b"*3\r\n$9\r\nsubscribe\r\n$20\r\n__redis__:invalidate\r\n:1\r\n",
+        );
+        assert_reply(
+            &mut tracker,
+            &["CLIENT", "TRACKING", "ON", "REDIRECT", &redirect_id],
+            b"+OK\r\n",
+        );
+        assert_reply(&mut tracker, &["GET", "k"], b"$-1\r\n");
+
+        assert_reply(&mut writer, &["SET", "k", "1"], b"+OK\r\n");
+        assert_received(
+            &mut redirect,
+            b"*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*1\r\n$1\r\nk\r\n",
+        );
+        assert_reply(&mut tracker, &["PING"], b"+PONG\r\n"); // nothing went to the tracker itself
+    }
+
+    #[test]
+    fn bcast_tracking_invalidates_every_key_under_its_prefixes() {
+        let (_server, port) = start_server(test_config("tracking-bcast"));
+        let mut tracker = connect(port);
+        let mut writer = connect(port);
+        hello_3(&mut tracker);
+        assert_reply(
+            &mut tracker,
+            &["CLIENT", "TRACKING", "ON", "BCAST", "PREFIX", "user:"],
+            b"+OK\r\n",
+        );
+
+        // no reads needed, and keys outside the prefix are left alone
+        assert_reply(&mut writer, &["SET", "other", "1"], b"+OK\r\n");
+        assert_reply(&mut writer, &["SET", "user:1", "1"], b"+OK\r\n");
+        assert_reply(&mut writer, &["SET", "user:1", "2"], b"+OK\r\n");
+        let invalidation = b">2\r\n$10\r\ninvalidate\r\n*1\r\n$6\r\nuser:1\r\n";
+        assert_received(&mut tracker, &invalidation.repeat(2));
+        assert_reply(&mut tracker, &["PING"], b"+PONG\r\n");
+    }
+
+    #[test]
+    fn client_kill_closes_the_connection() {
+        let (_server, port) = start_server(test_config("client-kill"));
+        let mut victim = connect(port);
+        let mut killer = connect(port);
+        send_command(&mut victim, &["CLIENT", "ID"]);
+        let victim_id = match resp_parser::decode(&mut victim).unwrap().0 {
+            RedisType::Integer(id) => id.to_string(),
+            reply => panic!("unexpected reply {reply:?}"),
+        };
+
+        assert_reply(
+            &mut killer,
+            &["CLIENT", "KILL", "ID", &victim_id],
+            b":1\r\n",
+        );
+        let mut buffer = [0; 16];
+        assert_eq!(victim.read(&mut buffer).unwrap(), 0);
+        assert_reply(
+            &mut killer,
+            &["CLIENT", "KILL", "ID", &victim_id],
+            b":0\r\n",
+        );
+    }
+
+    #[test]
+    fn client_pause_write_holds_back_writes_but_not_reads() {
+        let (_server, port) = start_server(test_config("client-pause"));
+        let mut pauser = connect(port);
+        let mut writer = connect(port);
+        let mut reader = connect(port);
+        let start = Instant::now();
+        assert_reply(
+            &mut pauser,
+            &["CLIENT", "PAUSE", "1000", "WRITE"],
+            b"+OK\r\n",
+        );
+
+        send_command(&mut writer, &["SET", "k", "1"]);
+        assert_reply(&mut reader, &["GET", "k"], b"$-1\r\n");
+        assert!(start.elapsed() < Duration::from_millis(1000));
+        assert_received(&mut writer, b"+OK\r\n");
+        assert!(start.elapsed() >= Duration::from_millis(1000));
+        assert_reply(&mut reader, &["GET", "k"], b"$1\r\n1\r\n");
+    }
+}
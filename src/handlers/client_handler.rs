@@ -1,74 +1,137 @@
-use std::{io::Read, net::TcpStream};
+use std::{io::Read, net::TcpStream, sync::Arc};
 
 use super::utils::{convert_to_redis_bulk_string_array, send};
 use crate::{
+    error::{RedisError, RedisResult},
     macros::option_type_guard,
-    utils::resp_parser::{self, RedisType},
+    utils::{
+        rdb,
+        resp_parser::{self, RedisType},
+    },
+    Server,
 };
 
 fn expect_response(host_stream: &mut impl Read, expected_response: &str) -> bool {
-    let (response, _) = resp_parser::decode(host_stream).unwrap();
+    let response_result = resp_parser::decode(host_stream);
+    if response_result.is_err() {
+        return false;
+    }
+    let (response, _) = response_result.unwrap();
     option_type_guard!(response_simple_string, response, RedisType::SimpleString);
-    return !response_simple_string.is_none()
-        && response_simple_string.unwrap().to_ascii_lowercase() == expected_response;
+    response_simple_string.is_some()
+        && response_simple_string.unwrap().to_ascii_lowercase() == expected_response
 }
 
+/// the handshake with the master, returns its replid, the offset the snapshot is at, the
+/// connection it keeps propagating writes on and the snapshot itself
 pub fn replicate_server(
-    replica_args: &Vec<String>,
+    replica_args: &[String],
     server_port: u64,
-) -> Result<(String, u64, TcpStream), String> {
+    masterauth: Option<&String>,
+) -> RedisResult<(String, u64, TcpStream, Vec<u8>)> {
+    if replica_args.len() != 2 {
+        return Err(RedisError::Replication(
+            "--replicaof expects \"<host> <port>\"".to_owned(),
+        ));
+    }
     let master_host = &replica_args[0];
     let master_port = &replica_args[1];
     let host_stream_result = TcpStream::connect(master_host.to_owned() + ":" + master_port);
     if host_stream_result.is_err() {
-        return Err("couldn't connect to master".to_owned());
+        return Err(RedisError::Replication(
+            "couldn't connect to master".to_owned(),
+        ));
     }
     let mut host_stream = host_stream_result.unwrap();
 
+    // a protected master answers everything else with NOAUTH, so this has to come first
+    if let Some(masterauth) = masterauth {
+        send(
+            &mut host_stream,
+            &resp_parser::encode(&convert_to_redis_bulk_string_array(vec![
+                "AUTH", masterauth,
+            ])),
+        )?;
+        if !expect_response(&mut host_stream, "ok") {
+            return Err(RedisError::Replication(
+                "master rejected masterauth".to_owned(),
+            ));
+        }
+    }
+
     send(
         &mut host_stream,
-        resp_parser::encode(&convert_to_redis_bulk_string_array(vec!["ping"])),
-    );
+        &resp_parser::encode(&convert_to_redis_bulk_string_array(vec!["ping"])),
+    )?;
     if !expect_response(&mut host_stream, "pong") {
-        return Err("master did not respond to ping".to_owned());
+        return Err(RedisError::Replication(
+            "master did not respond to ping".to_owned(),
+        ));
     }
 
     send(
         &mut host_stream,
-        resp_parser::encode(&convert_to_redis_bulk_string_array(vec![
+        &resp_parser::encode(&convert_to_redis_bulk_string_array(vec![
             "REPLCONF",
             "listening-port",
             &server_port.to_string(),
         ])),
-    );
+    )?;
     if !expect_response(&mut host_stream, "ok") {
-        return Err("master didn't respond ok to first REPLCONF".to_owned());
+        return Err(RedisError::Replication(
+            "master didn't respond ok to first REPLCONF".to_owned(),
+        ));
     }
 
     send(
         &mut host_stream,
-        resp_parser::encode(&convert_to_redis_bulk_string_array(vec![
-            "REPLCONF", "capa", "psync2",
+        &resp_parser::encode(&convert_to_redis_bulk_string_array(vec![
+            "REPLCONF", "capa", "eof", "capa", "psync2",
         ])),
-    );
+    )?;
     if !expect_response(&mut host_stream, "ok") {
-        return Err("master did't respond ok to second REPLCONF".to_owned());
+        return Err(RedisError::Replication(
+            "master did't respond ok to second REPLCONF".to_owned(),
+        ));
     }
 
     send(
         &mut host_stream,
-        resp_parser::encode(&convert_to_redis_bulk_string_array(vec![
+        &resp_parser::encode(&convert_to_redis_bulk_string_array(vec![
             "PSYNC", "?", "-1",
         ])),
-    );
-    let (response, _) = resp_parser::decode(&mut host_stream).unwrap();
+    )?;
+    let (response, _) = resp_parser::decode(&mut host_stream)?;
     option_type_guard!(response_option, response, RedisType::SimpleString);
-    let response = response_option.unwrap();
+    let response = response_option.unwrap_or_default();
     let parts = response.split(' ').collect::<Vec<&str>>();
+    if parts.len() != 3 || !parts[0].eq_ignore_ascii_case("FULLRESYNC") {
+        return Err(RedisError::Replication(
+            "master didn't respond to PSYNC with FULLRESYNC".to_owned(),
+        ));
+    }
     let master_replid = parts[1];
-    let master_repl_offset = str::parse::<u64>(parts[2]).unwrap();
+    let master_repl_offset = str::parse::<u64>(parts[2]).map_err(|_| {
+        RedisError::Replication("master sent an invalid replication offset".to_owned())
+    })?;
+
+    let rdb = resp_parser::decode_rdb(&mut host_stream)?;
 
-    resp_parser::decode_rdb(&mut host_stream); // do nothing with the rdb for now
+    Ok((
+        master_replid.to_owned(),
+        master_repl_offset,
+        host_stream,
+        rdb,
+    ))
+}
 
-    return Ok((master_replid.to_owned(), master_repl_offset, host_stream));
+/// a full resync replaces everything the replica had with the master's snapshot
+pub fn load_master_rdb(server: &Arc<Server>, rdb: &Vec<u8>) -> RedisResult<()> {
+    let checksum = *server.rdb_checksum.read().unwrap();
+    server.data_store.flush();
+    rdb::load_rdb(&mut rdb.as_slice(), &server.data_store, checksum)?;
+    if server.aof.is_enabled() {
+        server.start_aof()?; // what the AOF had is gone, it starts over from the new keyspace
+    }
+    Ok(())
 }
@@ -0,0 +1,965 @@
+use std::sync::Arc;
+
+use super::commands;
+use crate::{
+    error::{RedisError, RedisResult},
+    utils::{arg_parse, resp_parser::RedisType},
+    Server,
+};
+
+/// How a command is being run, for handlers whose behaviour depends on it.
+pub struct CommandContext {
+    pub is_replication_connection: bool, // writes applied from the master aren't propagated again
+    pub can_block: bool, // blocking commands act like their non blocking versions inside a transaction
+}
+
+pub type CommandHandler = fn(&[Vec<u8>], &Arc<Server>, &CommandContext) -> RedisResult<RedisType>;
+
+/// What the server knows about a command before running it. Arity follows the redis convention,
+/// a positive number is an exact argument count (including the command name) and a negative one is
+/// a minimum. Key positions are indexes into the arguments, a negative last key counts from the end.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i64,
+    pub flags: &'static [&'static str],
+    pub acl_categories: &'static [&'static str], // on top of the ones the flags imply
+    pub first_key: i64,
+    pub last_key: i64,
+    pub step: i64,
+    pub group: &'static str,
+    pub since: &'static str,
+    pub summary: &'static str,
+    pub handler: Option<CommandHandler>, // None for commands that need the connection, handled in server_handler
+}
+
+impl CommandSpec {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    /// the explicit categories plus the ones redis derives from the flags
+    pub fn acl_categories(&self) -> Vec<&'static str> {
+        let mut categories = Vec::from(self.acl_categories);
+        let implied = [
+            ("write", "write"),
+            ("readonly", "read"),
+            ("admin", "admin"),
+            ("admin", "dangerous"),
+            ("pubsub", "pubsub"),
+            ("fast", "fast"),
+            ("blocking", "blocking"),
+        ];
+        for (flag, category) in implied {
+            if self.has_flag(flag) && !categories.contains(&category) {
+                categories.push(category);
+            }
+        }
+        if !self.has_flag("fast") {
+            categories.push("slow");
+        }
+        categories
+    }
+
+    /// writes and anything else the replicas need to see
+    pub fn is_propagated(&self) -> bool {
+        self.has_flag("write") || self.has_flag("may_replicate")
+    }
+
+    /// whether this call can actually block, XREAD only does with a BLOCK option
+    pub fn may_block(&self, arguments: &[Vec<u8>]) -> bool {
+        if self.name == "xread" {
+            return arguments
+                .iter()
+                .take_while(|argument| !argument.eq_ignore_ascii_case(b"streams"))
+                .any(|argument| argument.eq_ignore_ascii_case(b"block"));
+        }
+        self.has_flag("blocking")
+    }
+}
+
+/// every ACL category, in the order ACL CAT lists them
+pub const ACL_CATEGORIES: &[&str] = &[
+    "keyspace",
+    "read",
+    "write",
+    "set",
+    "sortedset",
+    "list",
+    "hash",
+    "string",
+    "bitmap",
+    "hyperloglog",
+    "geo",
+    "stream",
+    "pubsub",
+    "admin",
+    "fast",
+    "slow",
+    "blocking",
+    "dangerous",
+    "connection",
+    "transaction",
+    "scripting",
+];
+
+/// subcommands whose categories differ from their container command's, ACL rules can name these
+/// as `command|subcommand`
+pub const SUBCOMMAND_ACL_CATEGORIES: &[(&str, &[&str])] = &[
+    ("acl|cat", &["slow"]),
+    ("acl|whoami", &["slow"]),
+    ("client|kill", &["admin", "slow", "dangerous", "connection"]),
+    ("client|list", &["admin", "slow", "dangerous", "connection"]),
+    (
+        "client|no-evict",
+        &["admin", "slow", "dangerous", "connection"],
+    ),
+    (
+        "client|pause",
+        &["admin", "slow", "dangerous", "connection"],
+    ),
+    (
+        "client|unpause",
+        &["admin", "slow", "dangerous", "connection"],
+    ),
+];
+
+/// commands whose first argument is a subcommand, which ACL rules and CLIENT LIST name as `command|subcommand`
+pub const CONTAINER_COMMANDS: &[&str] = &["acl", "client", "command", "config", "pubsub", "xinfo"];
+
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "acl",
+        arity: -2,
+        flags: &["admin", "noscript", "loading", "stale"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "6.0.0",
+        summary: "A container for Access List Control commands.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "auth",
+        arity: -2,
+        flags: &["noscript", "loading", "stale", "fast", "no_auth", "allow_busy"],
+        acl_categories: &["connection"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "connection",
+        since: "1.0.0",
+        summary: "Authenticates the connection.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "bgrewriteaof",
+        arity: 1,
+        flags: &["admin", "noscript", "no_async_loading"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "1.0.0",
+        summary: "Asynchronously rewrites the append-only file to disk.",
+        handler: Some(|_, server, _| commands::bgrewriteaof(server)),
+    },
+    CommandSpec {
+        name: "bgsave",
+        arity: -1,
+        flags: &["admin", "noscript", "no_async_loading"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "1.0.0",
+        summary: "Asynchronously saves the database(s) to disk.",
+        handler: Some(|arguments, server, _| commands::bgsave(arguments, server)),
+    },
+    CommandSpec {
+        name: "client",
+        arity: -2,
+        flags: &["noscript", "loading", "stale"],
+        acl_categories: &["connection"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "connection",
+        since: "2.4.0",
+        summary: "A container for client connection commands.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "command",
+        arity: -1,
+        flags: &["loading", "stale"],
+        acl_categories: &["connection"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "2.8.13",
+        summary: "Returns detailed information about all commands.",
+        handler: Some(|arguments, _, _| commands::command(arguments)),
+    },
+    CommandSpec {
+        name: "config",
+        arity: -2,
+        flags: &["admin", "noscript", "loading", "stale"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "2.0.0",
+        summary: "Gets or sets configuration parameters.",
+        handler: Some(|arguments, server, _| commands::config(arguments, server)),
+    },
+    CommandSpec {
+        name: "discard",
+        arity: 1,
+        flags: &["noscript", "loading", "stale", "fast", "allow_busy"],
+        acl_categories: &["transaction"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "transactions",
+        since: "2.0.0",
+        summary: "Discards a transaction.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "echo",
+        arity: 2,
+        flags: &["fast"],
+        acl_categories: &["connection"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "connection",
+        since: "1.0.0",
+        summary: "Returns the given string.",
+        handler: Some(|arguments, _, _| Ok(RedisType::BulkString(Some(arguments[1].to_owned())))),
+    },
+    CommandSpec {
+        name: "exec",
+        arity: 1,
+        flags: &["noscript", "loading", "stale", "skip_slowlog"],
+        acl_categories: &["transaction"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "transactions",
+        since: "1.2.0",
+        summary: "Executes all commands in a transaction.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "flushall",
+        arity: -1,
+        flags: &["write"],
+        acl_categories: &["keyspace", "dangerous"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "1.0.0",
+        summary: "Removes all keys from all databases.",
+        handler: Some(|arguments, server, context| commands::flushall(arguments, server, context.is_replication_connection)),
+    },
+    CommandSpec {
+        name: "flushdb",
+        arity: -1,
+        flags: &["write"],
+        acl_categories: &["keyspace", "dangerous"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "1.0.0",
+        summary: "Remove all keys from the current database.",
+        handler: Some(|arguments, server, context| commands::flushall(arguments, server, context.is_replication_connection)),
+    },
+    CommandSpec {
+        name: "get",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        acl_categories: &["string"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        group: "string",
+        since: "1.0.0",
+        summary: "Returns the string value of a key.",
+        handler: Some(|arguments, server, _| commands::get(arguments, server)),
+    },
+    CommandSpec {
+        name: "hello",
+        arity: -1,
+        flags: &["noscript", "loading", "stale", "fast", "no_auth", "allow_busy"],
+        acl_categories: &["connection"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "connection",
+        since: "6.0.0",
+        summary: "Handshakes with the Redis server.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "incr",
+        arity: 2,
+        flags: &["write", "denyoom", "fast"],
+        acl_categories: &["string"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        group: "string",
+        since: "1.0.0",
+        summary: "Increments the integer value of a key by one. Uses 0 as initial value if the key doesn't exist.",
+        handler: Some(|arguments, server, context| commands::incr(arguments, server, context.is_replication_connection)),
+    },
+    CommandSpec {
+        name: "info",
+        arity: -1,
+        flags: &["loading", "stale"],
+        acl_categories: &["dangerous"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "1.0.0",
+        summary: "Returns information and statistics about the server.",
+        handler: Some(|_, server, _| commands::info(server)),
+    },
+    CommandSpec {
+        name: "keys",
+        arity: 2,
+        flags: &["readonly"],
+        acl_categories: &["keyspace", "dangerous"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "generic",
+        since: "1.0.0",
+        summary: "Returns all key names that match a pattern.",
+        handler: Some(|_, server, _| commands::keys(server)),
+    },
+    CommandSpec {
+        name: "lastsave",
+        arity: 1,
+        flags: &["loading", "stale", "fast"],
+        acl_categories: &["admin", "dangerous"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "1.0.0",
+        summary: "Returns the Unix timestamp of the last successful save to disk.",
+        handler: Some(|_, server, _| commands::lastsave(server)),
+    },
+    CommandSpec {
+        name: "monitor",
+        arity: 1,
+        flags: &["admin", "noscript", "loading", "stale"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "1.0.0",
+        summary: "Listens for all requests received by the server in real-time.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "multi",
+        arity: 1,
+        flags: &["noscript", "loading", "stale", "fast", "allow_busy"],
+        acl_categories: &["transaction"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "transactions",
+        since: "1.2.0",
+        summary: "Starts a transaction.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "ping",
+        arity: -1,
+        flags: &["fast"],
+        acl_categories: &["connection"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "connection",
+        since: "1.0.0",
+        summary: "Returns the server's liveliness response.",
+        handler: Some(|_, _, _| Ok(RedisType::SimpleString("PONG".to_owned()))),
+    },
+    CommandSpec {
+        name: "psubscribe",
+        arity: -2,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "pubsub",
+        since: "2.0.0",
+        summary: "Listens for messages published to channels that match one or more patterns.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "psync",
+        arity: -3,
+        flags: &["admin", "noscript", "no_multi"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "2.8.0",
+        summary: "An internal command used in replication.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "publish",
+        arity: 3,
+        flags: &["pubsub", "loading", "stale", "fast", "may_replicate"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "pubsub",
+        since: "2.0.0",
+        summary: "Posts a message to a channel.",
+        handler: Some(|arguments, server, context| commands::publish(arguments, server, context.is_replication_connection)),
+    },
+    CommandSpec {
+        name: "pubsub",
+        arity: -2,
+        flags: &["pubsub", "loading", "stale"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "pubsub",
+        since: "2.8.0",
+        summary: "A container for Pub/Sub commands.",
+        handler: Some(|arguments, server, _| commands::pubsub(arguments, server)),
+    },
+    CommandSpec {
+        name: "punsubscribe",
+        arity: -1,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "pubsub",
+        since: "2.0.0",
+        summary: "Stops listening to messages published to channels that match one or more patterns.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "quit",
+        arity: -1,
+        flags: &["allow_busy", "noscript", "loading", "stale", "fast", "no_auth"],
+        acl_categories: &["connection"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "connection",
+        since: "1.0.0",
+        summary: "Closes the connection.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "replconf",
+        arity: -1,
+        flags: &["admin", "noscript", "loading", "stale", "allow_busy"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "3.0.0",
+        summary: "An internal command for configuring the replication stream.",
+        handler: Some(|arguments, server, _| commands::replconf(arguments, server)),
+    },
+    CommandSpec {
+        name: "reset",
+        arity: 1,
+        flags: &["noscript", "loading", "stale", "fast", "no_auth", "allow_busy"],
+        acl_categories: &["connection"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "connection",
+        since: "6.2.0",
+        summary: "Resets the connection.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "save",
+        arity: 1,
+        flags: &["admin", "noscript", "no_async_loading", "no_multi"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "1.0.0",
+        summary: "Synchronously saves the database(s) to disk.",
+        handler: Some(|_, server, _| commands::save(server)),
+    },
+    CommandSpec {
+        name: "set",
+        arity: -3,
+        flags: &["write", "denyoom"],
+        acl_categories: &["string"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        group: "string",
+        since: "1.0.0",
+        summary: "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist.",
+        handler: Some(|arguments, server, context| commands::set(arguments, server, context.is_replication_connection)),
+    },
+    CommandSpec {
+        name: "shutdown",
+        arity: -1,
+        flags: &["admin", "noscript", "loading", "stale", "no_multi", "allow_busy"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "server",
+        since: "1.0.0",
+        summary: "Synchronously saves the database(s) to disk and shuts down the Redis server.",
+        handler: Some(|arguments, server, _| commands::shutdown(arguments, server)),
+    },
+    CommandSpec {
+        name: "spublish",
+        arity: 3,
+        flags: &["pubsub", "loading", "stale", "fast", "may_replicate"],
+        acl_categories: &[],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        group: "pubsub",
+        since: "7.0.0",
+        summary: "Post a message to a shard channel",
+        handler: Some(|arguments, server, context| commands::publish(arguments, server, context.is_replication_connection)),
+    },
+    CommandSpec {
+        name: "ssubscribe",
+        arity: -2,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        acl_categories: &[],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        group: "pubsub",
+        since: "7.0.0",
+        summary: "Listens for messages published to shard channels.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "subscribe",
+        arity: -2,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "pubsub",
+        since: "2.0.0",
+        summary: "Listens for messages published to channels.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "sunsubscribe",
+        arity: -1,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        acl_categories: &[],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        group: "pubsub",
+        since: "7.0.0",
+        summary: "Stops listening to messages posted to shard channels.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "type",
+        arity: 2,
+        flags: &["readonly", "fast"],
+        acl_categories: &["keyspace"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        group: "generic",
+        since: "1.0.0",
+        summary: "Determines the type of value stored at a key.",
+        handler: Some(|arguments, server, _| commands::value_type(arguments, server)),
+    },
+    CommandSpec {
+        name: "unsubscribe",
+        arity: -1,
+        flags: &["pubsub", "noscript", "loading", "stale"],
+        acl_categories: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "pubsub",
+        since: "2.0.0",
+        summary: "Stops listening to messages posted to channels.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "unwatch",
+        arity: 1,
+        flags: &["noscript", "loading", "stale", "fast", "allow_busy"],
+        acl_categories: &["transaction"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "transactions",
+        since: "2.2.0",
+        summary: "Forgets about watched keys of a transaction.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "wait",
+        arity: 3,
+        flags: &["noscript", "blocking"],
+        acl_categories: &["connection"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "generic",
+        since: "3.0.0",
+        summary: "Blocks until the asynchronous replication of all preceding write commands sent by the connection is completed.",
+        handler: Some(|arguments, server, context| commands::wait(arguments, server, context.can_block)),
+    },
+    CommandSpec {
+        name: "watch",
+        arity: -2,
+        flags: &["noscript", "loading", "stale", "fast", "allow_busy"],
+        acl_categories: &["transaction"],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        group: "transactions",
+        since: "2.2.0",
+        summary: "Monitors changes to keys to determine the execution of a transaction.",
+        handler: None,
+    },
+    CommandSpec {
+        name: "xadd",
+        arity: -5,
+        flags: &["write", "denyoom", "fast"],
+        acl_categories: &["stream"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        group: "stream",
+        since: "5.0.0",
+        summary: "Appends a new message to a stream. Creates the key if it doesn't exist.",
+        handler: Some(|arguments, server, context| commands::xadd(arguments, server, context.is_replication_connection)),
+    },
+    CommandSpec {
+        name: "xinfo",
+        arity: -3,
+        flags: &["readonly"],
+        acl_categories: &["stream"],
+        first_key: 2,
+        last_key: 2,
+        step: 1,
+        group: "stream",
+        since: "5.0.0",
+        summary: "Returns information about a stream.",
+        handler: Some(|arguments, server, _| commands::xinfo(arguments, server)),
+    },
+    CommandSpec {
+        name: "xrange",
+        arity: -4,
+        flags: &["readonly"],
+        acl_categories: &["stream"],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        group: "stream",
+        since: "5.0.0",
+        summary: "Returns the messages from a stream within a range of IDs.",
+        handler: Some(|arguments, server, _| commands::xrange(arguments, server)),
+    },
+    CommandSpec {
+        name: "xread",
+        arity: -4,
+        flags: &["readonly", "blocking", "movablekeys"],
+        acl_categories: &["stream"],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        group: "stream",
+        since: "5.0.0",
+        summary: "Returns messages from multiple streams with IDs greater than the ones requested. Blocks until a message is available otherwise.",
+        handler: Some(|arguments, server, context| commands::xread(arguments, server, context.can_block)),
+    },
+];
+
+pub fn all() -> &'static [CommandSpec] {
+    COMMANDS
+}
+
+/// `name` is expected to already be lowercase
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+pub fn arity_matches(spec: &CommandSpec, arguments: &[Vec<u8>]) -> bool {
+    let count = arguments.len() as i64;
+    (spec.arity > 0 && count == spec.arity) || (spec.arity < 0 && count >= -spec.arity)
+}
+
+/// checks the command exists and has an acceptable number of arguments, returning the error to reply with if not
+pub fn validate(arguments: &[Vec<u8>]) -> RedisResult<&'static CommandSpec> {
+    let name = arg_parse::to_text(&arguments[0]).to_ascii_lowercase();
+    let spec_option = lookup(&name);
+    if spec_option.is_none() {
+        let args_preview: String = arguments[1..]
+            .iter()
+            .map(|argument| format!("'{}' ", arg_parse::to_text(argument)))
+            .collect();
+        return Err(RedisError::UnknownCommand(
+            arg_parse::to_text(&arguments[0]),
+            args_preview,
+        ));
+    }
+    let spec = spec_option.unwrap();
+
+    if !arity_matches(spec, arguments) {
+        return Err(RedisError::WrongArity(spec.name.to_owned()));
+    }
+    Ok(spec)
+}
+
+/// `command|subcommand` for container commands, otherwise just the command's name
+pub fn full_name(spec: &CommandSpec, arguments: &[Vec<u8>]) -> String {
+    if CONTAINER_COMMANDS.contains(&spec.name) && arguments.len() > 1 {
+        let subcommand = arg_parse::to_text(&arguments[1]).to_ascii_lowercase();
+        return format!("{}|{subcommand}", spec.name);
+    }
+    spec.name.to_owned()
+}
+
+/// the key arguments of an already validated command
+pub fn get_keys<'a>(spec: &CommandSpec, arguments: &'a [Vec<u8>]) -> Vec<&'a Vec<u8>> {
+    // xread's keys sit between STREAMS and the ids, one id per key
+    if spec.name == "xread" {
+        let streams_index_option = arguments
+            .iter()
+            .position(|argument| argument.eq_ignore_ascii_case(b"streams"));
+        if streams_index_option.is_none() {
+            return Vec::new();
+        }
+        let keys_and_ids = &arguments[streams_index_option.unwrap() + 1..];
+        return keys_and_ids[..keys_and_ids.len() / 2].iter().collect();
+    }
+
+    // the shard channel commands list their channels where keys go, like redis does for cluster
+    // routing, but they're channels (redis' NOT_KEY), so ACL checks them against `&` rules only
+    if spec.first_key == 0 || spec.has_flag("pubsub") {
+        return Vec::new();
+    }
+    let mut last_key = spec.last_key;
+    if last_key < 0 {
+        last_key += arguments.len() as i64;
+    }
+    let mut keys = Vec::new();
+    let mut i = spec.first_key;
+    while i <= last_key && (i as usize) < arguments.len() {
+        keys.push(&arguments[i as usize]);
+        i += spec.step;
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::server::ServerConfig;
+
+    fn arguments(parts: &[&[u8]]) -> Vec<Vec<u8>> {
+        parts.iter().map(|part| part.to_vec()).collect()
+    }
+
+    fn validate_error(parts: &[&[u8]]) -> String {
+        validate(&arguments(parts)).err().unwrap().to_string()
+    }
+
+    fn test_server() -> Arc<Server> {
+        let server = Server::new(ServerConfig {
+            dir: Some(std::env::temp_dir().to_string_lossy().into_owned()),
+            dbfilename: Some(format!("fuzz-{}.rdb", std::process::id())),
+            ..Default::default()
+        });
+        Arc::new(server.unwrap())
+    }
+
+    #[test]
+    fn validate_rejects_wrong_arity() {
+        assert_eq!(
+            validate_error(&[b"get"]),
+            "ERR wrong number of arguments for 'get' command"
+        );
+        assert_eq!(
+            validate_error(&[b"GET", b"a", b"b"]),
+            "ERR wrong number of arguments for 'get' command"
+        );
+        assert_eq!(
+            validate_error(&[b"set", b"k"]),
+            "ERR wrong number of arguments for 'set' command"
+        );
+        assert_eq!(
+            validate_error(&[b"xadd", b"s", b"*"]),
+            "ERR wrong number of arguments for 'xadd' command"
+        );
+        assert!(validate(&arguments(&[b"SeT", b"k", b"v"])).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_unknown_commands() {
+        assert_eq!(
+            validate_error(&[b"foo", b"a", b"b"]),
+            "ERR unknown command 'foo', with args beginning with: 'a' 'b' "
+        );
+        assert_eq!(
+            validate_error(&[b"foo"]),
+            "ERR unknown command 'foo', with args beginning with: "
+        );
+        assert_eq!(
+            validate_error(&[b"g\xffet", b"\xfe"]),
+            "ERR unknown command 'g\u{fffd}et', with args beginning with: '\u{fffd}' "
+        );
+        assert_eq!(
+            validate_error(&[b""]),
+            "ERR unknown command '', with args beginning with: "
+        );
+    }
+
+    #[test]
+    fn handlers_reject_garbage_arguments() {
+        let server = test_server();
+        let context = CommandContext {
+            is_replication_connection: false,
+            can_block: false,
+        };
+        let run = |parts: &[&[u8]]| -> String {
+            let arguments = arguments(parts);
+            let result = validate(&arguments)
+                .and_then(|spec| (spec.handler.unwrap())(&arguments, &server, &context));
+            match result {
+                Ok(_) => "OK".to_owned(),
+                Err(error) => error.to_string(),
+            }
+        };
+        assert_eq!(run(&[b"incr", b"\xff"]), "OK"); // a missing key starts from 0
+        assert_eq!(run(&[b"set", b"s", b"\xff"]), "OK");
+        assert_eq!(
+            run(&[b"incr", b"s"]),
+            "ERR value is not an integer or out of range"
+        );
+        assert_eq!(
+            run(&[b"set", b"k", b"v", b"px", b"-1"]),
+            "ERR invalid expire time in 'set' command"
+        );
+        assert_eq!(
+            run(&[b"set", b"k", b"v", b"px", b"\xff"]),
+            "ERR invalid expire time in 'set' command"
+        );
+        assert_eq!(run(&[b"set", b"k", b"v", b"\xff"]), "ERR syntax error");
+        assert_eq!(
+            run(&[b"xadd", b"x", b"\xff", b"f", b"v"]),
+            "ERR Invalid stream ID specified as stream command argument"
+        );
+        assert_eq!(
+            run(&[b"xadd", b"x", b"1-1", b"f", b"v", b"g"]),
+            "ERR wrong number of arguments for 'xadd' command"
+        );
+        assert_eq!(
+            run(&[b"xrange", b"x", b"-", b"a-b"]),
+            "ERR Invalid stream ID specified as stream command argument"
+        );
+        assert_eq!(
+            run(&[b"xadd", b"s", b"*", b"f", b"v"]),
+            "WRONGTYPE Operation against a key holding the wrong kind of value"
+        );
+        assert_eq!(
+            run(&[b"wait", b"x", b"0"]),
+            "ERR value is not an integer or out of range"
+        );
+        assert_eq!(
+            run(&[b"config", b"\xff"]),
+            "ERR unknown subcommand '\u{fffd}'. Try CONFIG HELP."
+        );
+        assert_eq!(
+            run(&[b"xinfo", b"groups", b"s"]),
+            "ERR unknown subcommand 'groups'. Try XINFO HELP."
+        );
+    }
+
+    #[test]
+    fn handlers_never_panic_on_garbage() {
+        let server = test_server();
+        let context = CommandContext {
+            is_replication_connection: false,
+            can_block: false,
+        };
+        // none of these parse as a non negative integer, so WAIT and friends can't end up blocking
+        let garbage: &[&[u8]] = &[
+            b"",
+            b"\xff\xfe",
+            b"-1",
+            b"*",
+            b"$",
+            b"99999999999999999999999",
+            b"nan",
+            b"streams",
+            b"px",
+            b"get",
+            b"1-\xff",
+            b"+",
+        ];
+        // these stop the process or write to disk, which isn't what this is about
+        let skipped = ["shutdown", "save", "bgsave", "bgrewriteaof"];
+        for spec in all() {
+            if spec.handler.is_none() || skipped.contains(&spec.name) {
+                continue;
+            }
+            for count in 1..7 {
+                for offset in 0..garbage.len() {
+                    let mut command = vec![spec.name.as_bytes().to_vec()];
+                    for i in 1..count {
+                        command.push(garbage[(offset + i * 5) % garbage.len()].to_vec());
+                    }
+                    let result = validate(&command);
+                    if !arity_matches(spec, &command) {
+                        assert_eq!(
+                            result.err().unwrap().to_string(),
+                            format!("ERR wrong number of arguments for '{}' command", spec.name)
+                        );
+                        continue;
+                    }
+                    let _ = (spec.handler.unwrap())(&command, &server, &context);
+                }
+            }
+        }
+    }
+}
@@ -5,21 +5,30 @@ use std::{
     time::{Duration, SystemTime},
 };
 
+use super::command_table::{self, CommandSpec};
 use crate::{
+    error::{RedisError, RedisResult},
     handlers::utils::convert_to_redis_bulk_string_array,
     structs::{
+        aof::AppendFsync,
         data_store::DataType,
+        pub_sub::{self, NOTIFY_GENERIC, NOTIFY_STREAM, NOTIFY_STRING},
+        redis_stream, server,
         xread_subscription::{self, XreadSubscription},
     },
-    utils::{arg_parse, resp_parser::RedisType},
+    utils::{
+        arg_parse,
+        glob::glob_match,
+        resp_parser::{self, RedisType},
+    },
     Server,
 };
 
 pub fn incr(
-    arguments: &Vec<String>,
+    arguments: &[Vec<u8>],
     server: &Arc<Server>,
     is_replication_connection: bool,
-) -> RedisType {
+) -> RedisResult<RedisType> {
     let key = &arguments[1];
 
     let mut replication_lock: Option<MutexGuard<()>> = None;
@@ -29,32 +38,54 @@ pub fn incr(
 
     let result = server.data_store.increment(key);
 
-    // this needs to be inside a lock to guarantee replicas receive commands in the right order
-    if !is_replication_connection {
-        let arguments_as_str = arguments.iter().map(|s| s.as_str()).collect();
-        server.queue_send_to_replications(convert_to_redis_bulk_string_array(arguments_as_str));
+    // this needs to be inside a lock to guarantee replicas receive commands in the right order, a
+    // failed INCR changed nothing so there's nothing to replay
+    if !is_replication_connection && result.is_ok() {
+        server.propagate(convert_to_redis_bulk_string_array(
+            arguments.iter().collect(),
+        ));
     }
 
     drop(replication_lock);
 
-    if result.is_err() {
-        return RedisType::SimpleError("ERR value is not an integer or out of range".to_owned());
-    } else {
-        return RedisType::Integer(result.unwrap());
-    }
+    let value = result?;
+    server
+        .pub_sub
+        .notify_keyspace_event(NOTIFY_STRING, "incrby", key);
+    Ok(RedisType::Integer(value))
 }
 
-pub fn xread(arguments: &Vec<String>, server: &Arc<Server>) -> RedisType {
+pub fn xread(
+    arguments: &[Vec<u8>],
+    server: &Arc<Server>,
+    can_block: bool,
+) -> RedisResult<RedisType> {
     let block_time = arg_parse::get_u64("block", arguments);
-    let streams_index = arguments.iter().position(|a| a == "streams").unwrap();
+    let streams_index = arguments
+        .iter()
+        .position(|a| a.eq_ignore_ascii_case(b"streams"))
+        .ok_or(RedisError::Syntax)?;
     let keys_and_ids = &arguments[streams_index + 1..];
+    if keys_and_ids.is_empty() || !keys_and_ids.len().is_multiple_of(2) {
+        return Err(RedisError::Generic(
+            "Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."
+                .to_owned(),
+        ));
+    }
     let keys = Vec::from(&keys_and_ids[..keys_and_ids.len() / 2]);
-    let mut ids = Vec::from(&keys_and_ids[keys.len()..]);
+    let mut ids = Vec::new();
+    for id in &keys_and_ids[keys.len()..] {
+        let id = stream_id_argument(id)?;
+        if id != "$" {
+            redis_stream::validate_id(&id)?;
+        }
+        ids.push(id);
+    }
 
-    server.data_store.xreadids(&keys, &mut ids);
+    server.data_store.xreadids(&keys, &mut ids)?;
 
-    if block_time.is_some() {
-        if block_time.unwrap() == 0 {
+    if let Some(block_time) = block_time.filter(|_| can_block) {
+        if block_time == 0 {
             let mut xread_subscriptions = server.xread_subscriptions.write().unwrap();
             let mut wakeup_keys = HashSet::new();
             for key in &keys {
@@ -64,85 +95,323 @@ pub fn xread(arguments: &Vec<String>, server: &Arc<Server>) -> RedisType {
             xread_subscriptions.push(XreadSubscription::new(wakeup_keys, send));
             drop(xread_subscriptions);
 
-            recv.recv().unwrap(); // block until woken up
+            let _ = recv.recv(); // block until woken up
         } else {
-            thread::sleep(Duration::from_millis(block_time.unwrap()));
+            thread::sleep(Duration::from_millis(block_time));
         }
     }
 
-    let result = server.data_store.xread(&keys, &ids);
+    let result = server.data_store.xread(&keys, &ids)?;
 
     if result.is_empty() {
-        return RedisType::BulkString(None);
+        Ok(RedisType::BulkString(None))
     } else {
-        return RedisType::Array(result);
+        Ok(RedisType::Array(result))
     }
 }
 
-pub fn xrange(arguments: &Vec<String>, server: &Arc<Server>) -> RedisType {
+pub fn xrange(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
     let key = &arguments[1];
-    let start = &arguments[2];
-    let end = &arguments[3];
+    let start = stream_id_argument(&arguments[2])?;
+    let end = stream_id_argument(&arguments[3])?;
+    redis_stream::validate_id(&start)?;
+    redis_stream::validate_id(&end)?;
+
+    Ok(RedisType::Array(
+        server.data_store.xrange(key, &start, &end)?,
+    ))
+}
 
-    return RedisType::Array(server.data_store.xrange(key, start, end));
+/// stream ids are always text, bytes that aren't can't be one
+fn stream_id_argument(argument: &[u8]) -> RedisResult<String> {
+    String::from_utf8(argument.to_vec()).map_err(|_| RedisError::InvalidStreamId)
 }
 
-pub fn xadd(arguments: &Vec<String>, server: &Arc<Server>) -> RedisType {
+pub fn xadd(
+    arguments: &[Vec<u8>],
+    server: &Arc<Server>,
+    is_replication_connection: bool,
+) -> RedisResult<RedisType> {
     let key = &arguments[1];
-    let id = &arguments[2];
+    let id = stream_id_argument(&arguments[2])?;
     let fields = &arguments[3..];
-    let result = server.data_store.xadd(key, id, fields);
+    if !fields.len().is_multiple_of(2) {
+        return Err(RedisError::WrongArity("xadd".to_owned()));
+    }
 
-    if result.is_err() {
-        return RedisType::SimpleError(result.err().unwrap());
-    } else {
-        xread_subscription::wakeup_subscribers(server, key);
-        return RedisType::BulkString(Some(result.unwrap()));
+    let mut replication_lock: Option<MutexGuard<()>> = None;
+    if !is_replication_connection {
+        replication_lock = Some(server.master_repl_mutex.lock().unwrap());
     }
+
+    let result = server.data_store.xadd(key, &id, fields);
+
+    // with the id that was generated, replaying `*` would make up a different one
+    if let (false, Ok(id)) = (is_replication_connection, &result) {
+        let mut propagated: Vec<&[u8]> = arguments.iter().map(|s| s.as_slice()).collect();
+        propagated[2] = id.as_bytes();
+        server.propagate(convert_to_redis_bulk_string_array(propagated));
+    }
+
+    drop(replication_lock);
+
+    let result = result?;
+
+    server
+        .pub_sub
+        .notify_keyspace_event(NOTIFY_STREAM, "xadd", key);
+    xread_subscription::wakeup_subscribers(server, key);
+    Ok(RedisType::BulkString(Some(result.into())))
 }
 
-pub fn value_type(arguments: &Vec<String>, server: &Arc<Server>) -> RedisType {
+pub fn xinfo(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
+    if !arguments[1].eq_ignore_ascii_case(b"stream") {
+        return Err(RedisError::UnknownSubcommand(
+            arg_parse::to_text(&arguments[1]),
+            "XINFO".to_owned(),
+        ));
+    }
+    Ok(RedisType::Map(
+        server.data_store.xinfo_stream(&arguments[2])?,
+    ))
+}
+
+pub fn value_type(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
     let key = &arguments[1];
 
     let data_option = server.data_store.get(key);
-    if data_option.is_some() {
-        return RedisType::SimpleString(
-            match data_option.unwrap() {
+    if let Some(data) = data_option {
+        Ok(RedisType::SimpleString(
+            match data {
                 DataType::String(_) => "string",
+                DataType::List(_) => "list",
+                DataType::Set(_) => "set",
+                DataType::Hash(_) => "hash",
+                DataType::SortedSet(_) => "zset",
                 DataType::Stream(_) => "stream",
             }
             .to_owned(),
-        );
+        ))
     } else {
-        return RedisType::SimpleString("none".to_owned());
+        Ok(RedisType::SimpleString("none".to_owned()))
     }
 }
 
-pub fn keys(server: &Arc<Server>) -> RedisType {
-    return convert_to_redis_bulk_string_array(
-        server
-            .data_store
-            .keys()
+pub fn keys(server: &Arc<Server>) -> RedisResult<RedisType> {
+    Ok(convert_to_redis_bulk_string_array(server.data_store.keys()))
+}
+
+pub fn config(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
+    let subcommand = arg_parse::to_text(&arguments[1]).to_ascii_lowercase();
+    let minimum_arguments = if subcommand == "set" { 4 } else { 3 };
+    if subcommand != "get" && subcommand != "set" {
+        return Err(RedisError::UnknownSubcommand(
+            arg_parse::to_text(&arguments[1]),
+            "CONFIG".to_owned(),
+        ));
+    }
+    if arguments.len() < minimum_arguments {
+        return Err(RedisError::WrongArity(format!("config|{subcommand}")));
+    }
+    if subcommand == "set" {
+        return config_set(arguments, server);
+    }
+    let parameters = [
+        ("dir", server.dir.clone()),
+        ("dbfilename", server.dbfilename.clone()),
+        (
+            "notify-keyspace-events",
+            pub_sub::keyspace_events_to_string(server.pub_sub.keyspace_events()),
+        ),
+        (
+            "requirepass",
+            server
+                .requirepass
+                .read()
+                .unwrap()
+                .clone()
+                .unwrap_or_default(),
+        ),
+        ("masterauth", server.masterauth.clone().unwrap_or_default()),
+        ("aclfile", server.acl.file.clone().unwrap_or_default()),
+        (
+            "save",
+            server::save_points_to_string(&server.save_points.read().unwrap()),
+        ),
+        (
+            "rdbcompression",
+            yes_no(*server.rdb_compression.read().unwrap()),
+        ),
+        ("rdbchecksum", yes_no(*server.rdb_checksum.read().unwrap())),
+        ("appendonly", yes_no(server.aof.is_enabled())),
+        ("appendfilename", server.aof.filename.clone()),
+        ("appenddirname", server.aof.dirname.clone()),
+        (
+            "appendfsync",
+            server.aof.fsync.read().unwrap().name().to_owned(),
+        ),
+        (
+            "aof-use-rdb-preamble",
+            yes_no(*server.aof.use_rdb_preamble.read().unwrap()),
+        ),
+        (
+            "auto-aof-rewrite-percentage",
+            server
+                .aof
+                .auto_rewrite_percentage
+                .read()
+                .unwrap()
+                .to_string(),
+        ),
+        (
+            "auto-aof-rewrite-min-size",
+            server.aof.auto_rewrite_min_size.read().unwrap().to_string(),
+        ),
+        (
+            "repl-diskless-sync",
+            yes_no(*server.repl_diskless_sync.read().unwrap()),
+        ),
+    ];
+    let mut result = Vec::new();
+    for (name, value) in parameters {
+        let matched = arguments[2..]
             .iter()
-            .map(|s| s.as_str())
-            .collect(),
-    );
+            .any(|pattern| glob_match(&pattern.to_ascii_lowercase(), name.as_bytes()));
+        if matched {
+            result.push((
+                RedisType::BulkString(Some(name.into())),
+                RedisType::BulkString(Some(value.into())),
+            ));
+        }
+    }
+    Ok(RedisType::Map(result))
 }
 
-pub fn config(arguments: &Vec<String>, server: &Arc<Server>) -> RedisType {
-    return match arguments[2].to_ascii_lowercase().as_str() {
-        "dir" => convert_to_redis_bulk_string_array(vec!["dir", &server.dir]),
-        "dbfilename" => convert_to_redis_bulk_string_array(vec!["dbfilename", &server.dbfilename]),
-        _ => RedisType::SimpleError("Error, unknown config field".to_owned()),
-    };
+fn config_set(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
+    let parameter = &arg_parse::to_text(&arguments[2]);
+    let value = &arg_parse::to_text(&arguments[3]);
+    match parameter.to_ascii_lowercase().as_str() {
+        "notify-keyspace-events" => {
+            let flags = pub_sub::parse_keyspace_events(value)?;
+            server.pub_sub.set_keyspace_events(flags);
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "requirepass" => {
+            // a password with bytes that aren't utf-8 would get mangled into one that isn't the same
+            if std::str::from_utf8(&arguments[3]).is_err() {
+                return Err(config_invalid_value(parameter));
+            }
+            // connections that already logged in stay logged in, like redis
+            *server.requirepass.write().unwrap() = if value.is_empty() {
+                None
+            } else {
+                Some(value.clone())
+            };
+            server.acl.set_default_password(Some(value));
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "save" => {
+            *server.save_points.write().unwrap() = server::parse_save_points(value)?;
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "rdbcompression" => {
+            *server.rdb_compression.write().unwrap() = config_yes_no(parameter, value)?;
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "rdbchecksum" => {
+            *server.rdb_checksum.write().unwrap() = config_yes_no(parameter, value)?;
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "appendonly" => {
+            let appendonly = config_yes_no(parameter, value)?;
+            if appendonly && !server.aof.is_enabled() {
+                server.start_aof()?;
+            } else if !appendonly {
+                server.aof.close();
+            }
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "appendfsync" => {
+            *server.aof.fsync.write().unwrap() = AppendFsync::parse(value).ok_or_else(|| {
+                RedisError::Generic(format!(
+                    "CONFIG SET failed (possibly related to argument '{parameter}') - argument(s) must be one of the following: always, everysec, no"
+                ))
+            })?;
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "aof-use-rdb-preamble" => {
+            *server.aof.use_rdb_preamble.write().unwrap() = config_yes_no(parameter, value)?;
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "auto-aof-rewrite-percentage" => {
+            *server.aof.auto_rewrite_percentage.write().unwrap() =
+                str::parse::<u64>(value).map_err(|_| config_invalid_argument(parameter, value))?;
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "auto-aof-rewrite-min-size" => {
+            *server.aof.auto_rewrite_min_size.write().unwrap() = arg_parse::parse_memory(value)
+                .ok_or_else(|| config_invalid_argument(parameter, value))?;
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        "repl-diskless-sync" => {
+            *server.repl_diskless_sync.write().unwrap() = config_yes_no(parameter, value)?;
+            Ok(RedisType::SimpleString("OK".to_owned()))
+        }
+        _ => Err(RedisError::Generic(format!(
+            "Unknown option or number of arguments for CONFIG SET - '{}'",
+            parameter.to_ascii_lowercase()
+        ))),
+    }
+}
+
+fn config_yes_no(parameter: &str, value: &str) -> RedisResult<bool> {
+    arg_parse::parse_yes_no(value).ok_or_else(|| {
+        RedisError::Generic(format!(
+            "CONFIG SET failed (possibly related to argument '{parameter}') - argument must be 'yes' or 'no'"
+        ))
+    })
+}
+
+fn config_invalid_argument(parameter: &str, value: &str) -> RedisError {
+    RedisError::Generic(format!(
+        "CONFIG SET failed (possibly related to argument '{parameter}') - argument couldn't be parsed into an integer: '{value}'"
+    ))
+}
+
+fn config_invalid_value(parameter: &str) -> RedisError {
+    RedisError::Generic(format!(
+        "CONFIG SET failed (possibly related to argument '{parameter}') - argument must be valid utf-8"
+    ))
+}
+
+fn yes_no(value: bool) -> String {
+    (if value { "yes" } else { "no" }).to_owned()
+}
+
+fn acknowledged_replication_count(server: &Arc<Server>, expected_offset: u64) -> u64 {
+    let mut replication_count = 0;
+    let connected_replications = server.connected_replications.read().unwrap();
+    for replication in connected_replications.iter() {
+        let master_repl_offset = replication.master_repl_offset.read().unwrap();
+        if *master_repl_offset >= expected_offset {
+            replication_count += 1;
+        }
+    }
+    drop(connected_replications);
+    replication_count
 }
 
-pub fn wait(arguments: &Vec<String>, server: &Arc<Server>) -> RedisType {
-    let required_replication_count = str::parse::<u64>(&arguments[1]).unwrap();
-    let timeout = str::parse::<u64>(&arguments[2]).unwrap();
+pub fn wait(
+    arguments: &[Vec<u8>],
+    server: &Arc<Server>,
+    can_block: bool,
+) -> RedisResult<RedisType> {
+    let required_replication_count =
+        arg_parse::parse::<u64>(&arguments[1]).ok_or(RedisError::NotInteger)?;
+    let timeout = arg_parse::parse::<u64>(&arguments[2]).ok_or(RedisError::NotInteger)?;
     let timeout_time = SystemTime::now()
         .checked_add(Duration::from_millis(timeout))
-        .unwrap();
+        .ok_or(RedisError::Generic("timeout is out of range".to_owned()))?;
 
     let master_repl_offset = server.master_repl_offset.read().unwrap();
     let expected_offset = *master_repl_offset;
@@ -153,27 +422,29 @@ pub fn wait(arguments: &Vec<String>, server: &Arc<Server>) -> RedisType {
         let connected_replications = server.connected_replications.read().unwrap();
         let replication_count = connected_replications.len();
         drop(connected_replications);
-        return RedisType::Integer(replication_count as i64);
+        return Ok(RedisType::Integer(replication_count as i64));
+    }
+
+    // inside a transaction only report what has already been acknowledged
+    if !can_block {
+        return Ok(RedisType::Integer(
+            acknowledged_replication_count(server, expected_offset) as i64,
+        ));
     }
 
+    // keep the getack out of the middle of a transaction's MULTI/EXEC block
+    let transaction_lock = server.data_store.shared_lock();
     server.queue_send_to_replications(convert_to_redis_bulk_string_array(vec![
         "REPLCONF", "GETACK", "*",
     ]));
+    drop(transaction_lock);
 
     let mut max_replication_count: u64 = 0;
     loop {
         if SystemTime::now().ge(&timeout_time) {
             break;
         }
-        let mut replication_count = 0;
-        let connected_replications = server.connected_replications.read().unwrap();
-        for replication in connected_replications.iter() {
-            let master_repl_offset = replication.master_repl_offset.read().unwrap();
-            if *master_repl_offset >= expected_offset {
-                replication_count += 1;
-            }
-        }
-        drop(connected_replications);
+        let replication_count = acknowledged_replication_count(server, expected_offset);
 
         if replication_count > max_replication_count {
             max_replication_count = replication_count;
@@ -186,56 +457,141 @@ pub fn wait(arguments: &Vec<String>, server: &Arc<Server>) -> RedisType {
         thread::sleep(Duration::from_millis(50));
     }
 
-    return RedisType::Integer(max_replication_count as i64);
+    Ok(RedisType::Integer(max_replication_count as i64))
 }
 
-pub fn replconf(arguments: &Vec<String>, server: &Arc<Server>) -> RedisType {
-    if arguments[1].to_ascii_lowercase() == "getack" {
+pub fn replconf(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
+    if arguments.len() > 1 && arguments[1].eq_ignore_ascii_case(b"getack") {
         let master_repl_offset = server.master_repl_offset.read().unwrap();
         let master_repl_offset_string = master_repl_offset.to_string();
         drop(master_repl_offset);
 
-        return convert_to_redis_bulk_string_array(vec![
+        Ok(convert_to_redis_bulk_string_array(vec![
             "REPLCONF",
             "ACK",
             master_repl_offset_string.as_str(),
-        ]);
+        ]))
     } else {
-        return RedisType::SimpleString("OK".to_owned());
+        Ok(RedisType::SimpleString("OK".to_owned()))
     }
 }
 
-pub fn info(server: &Arc<Server>) -> RedisType {
+pub fn info(server: &Arc<Server>) -> RedisResult<RedisType> {
     let role = &server.role;
     let master_replid = &server.replid;
     let master_repl_offset = server.master_repl_offset.read().unwrap();
-    let master_repl_offset_clone = master_repl_offset.clone();
+    let master_repl_offset_clone = *master_repl_offset;
     drop(master_repl_offset);
 
-    return RedisType::BulkString(Some(format!(
-        "role:{role}\n\
+    let save_state = server.save_state.lock().unwrap();
+    let changes_since_last_save = server
+        .data_store
+        .last_version()
+        .saturating_sub(save_state.saved_version);
+    let bgsave_in_progress = save_state.in_progress as u8;
+    let last_save_time = save_state
+        .last_save
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let last_bgsave_status = if save_state.last_save_ok { "ok" } else { "err" };
+    drop(save_state);
+    let aof_enabled = server.aof.is_enabled() as u8;
+    let aof_rewrite_in_progress = server.aof.rewrite_in_progress() as u8;
+    let aof_last_bgrewrite_status = if server.aof.last_rewrite_ok() {
+        "ok"
+    } else {
+        "err"
+    };
+    let (aof_current_size, aof_base_size) = server.aof.sizes();
+    let aof_last_write_status = if server.aof.last_write_ok() {
+        "ok"
+    } else {
+        "err"
+    };
+
+    Ok(RedisType::BulkString(Some(
+        format!(
+            "role:{role}\n\
         master_replid:{master_replid}\n\
-        master_repl_offset:{master_repl_offset_clone}\n",
-    )));
+        master_repl_offset:{master_repl_offset_clone}\n\
+        rdb_changes_since_last_save:{changes_since_last_save}\n\
+        rdb_bgsave_in_progress:{bgsave_in_progress}\n\
+        rdb_last_save_time:{last_save_time}\n\
+        rdb_last_bgsave_status:{last_bgsave_status}\n\
+        aof_enabled:{aof_enabled}\n\
+        aof_rewrite_in_progress:{aof_rewrite_in_progress}\n\
+        aof_last_bgrewrite_status:{aof_last_bgrewrite_status}\n\
+        aof_last_write_status:{aof_last_write_status}\n\
+        aof_current_size:{aof_current_size}\n\
+        aof_base_size:{aof_base_size}\n",
+        )
+        .into(),
+    )))
 }
 
-pub fn set(
-    arguments: &Vec<String>,
-    server: &Arc<Server>,
-    is_replication_connection: bool,
-) -> RedisType {
-    let key = &arguments[1];
-    let value = &arguments[2];
-
+/// when SET's PX or PXAT option says the key expires
+pub fn set_expire_time(arguments: &[Vec<u8>]) -> RedisResult<Option<SystemTime>> {
     let mut expire_time: Option<SystemTime> = None;
-    let lifetime_arg = arg_parse::get_u64("px", &arguments);
-    if lifetime_arg.is_some() {
+    let lifetime_string = arg_parse::get_string("px", arguments);
+    if let Some(lifetime_string) = lifetime_string {
+        let lifetime = arg_parse::parse::<u64>(lifetime_string)
+            .filter(|lifetime| *lifetime > 0)
+            .ok_or(RedisError::Generic(
+                "invalid expire time in 'set' command".to_owned(),
+            ))?;
         expire_time = Some(
             SystemTime::now()
-                .checked_add(Duration::from_millis(lifetime_arg.unwrap()))
-                .unwrap(),
+                .checked_add(Duration::from_millis(lifetime))
+                .ok_or(RedisError::Generic(
+                    "invalid expire time in 'set' command".to_owned(),
+                ))?,
+        );
+    } else if arg_parse::get_string("pxat", arguments).is_some() {
+        let deadline = arg_parse::parse::<u64>(arg_parse::get_string("pxat", arguments).unwrap())
+            .filter(|deadline| *deadline > 0)
+            .ok_or(RedisError::Generic(
+                "invalid expire time in 'set' command".to_owned(),
+            ))?;
+        expire_time = Some(
+            SystemTime::UNIX_EPOCH
+                .checked_add(Duration::from_millis(deadline))
+                .ok_or(RedisError::Generic(
+                    "invalid expire time in 'set' command".to_owned(),
+                ))?,
         );
+    } else if arguments.len() > 3 {
+        return Err(RedisError::Syntax);
     }
+    Ok(expire_time)
+}
+
+/// SET with its PX lifetime replaced by the PXAT deadline it ends at, what goes in an AOF since a
+/// relative expire would start over every time the AOF is loaded
+pub fn set_with_deadline(arguments: &[Vec<u8>], expire_time: SystemTime) -> Vec<Vec<u8>> {
+    let mut arguments = arguments.to_vec();
+    let position = arguments
+        .iter()
+        .position(|argument| argument.eq_ignore_ascii_case(b"px"));
+    if let Some(position) = position.filter(|position| position + 1 < arguments.len()) {
+        let deadline = expire_time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        arguments[position] = b"PXAT".to_vec();
+        arguments[position + 1] = deadline.to_string().into_bytes();
+    }
+    arguments
+}
+
+pub fn set(
+    arguments: &[Vec<u8>],
+    server: &Arc<Server>,
+    is_replication_connection: bool,
+) -> RedisResult<RedisType> {
+    let key = &arguments[1];
+    let value = &arguments[2];
+    let expire_time = set_expire_time(arguments)?;
 
     let mut replication_lock: Option<MutexGuard<()>> = None;
     if !is_replication_connection {
@@ -247,26 +603,308 @@ pub fn set(
         .insert(key, DataType::String(value.to_owned()), expire_time);
 
     // this needs to be inside a lock to guarantee replicas receive commands in the right order
-    if !is_replication_connection {
-        let arguments_as_str = arguments.iter().map(|s| s.as_str()).collect();
-        server.queue_send_to_replications(convert_to_redis_bulk_string_array(arguments_as_str));
+    if !is_replication_connection && arg_parse::get_string("px", arguments).is_some() {
+        let aof_arguments = set_with_deadline(arguments, expire_time.unwrap());
+        server
+            .aof
+            .append(&resp_parser::encode(&convert_to_redis_bulk_string_array(
+                aof_arguments,
+            )));
+        server.queue_send_to_replications(convert_to_redis_bulk_string_array(
+            arguments.iter().collect(),
+        ));
+    } else if !is_replication_connection {
+        server.propagate(convert_to_redis_bulk_string_array(
+            arguments.iter().collect(),
+        ));
     }
 
     drop(replication_lock);
 
-    return RedisType::SimpleString("OK".to_owned());
+    server
+        .pub_sub
+        .notify_keyspace_event(NOTIFY_STRING, "set", key);
+    if expire_time.is_some() {
+        server
+            .pub_sub
+            .notify_keyspace_event(NOTIFY_GENERIC, "expire", key);
+    }
+
+    Ok(RedisType::SimpleString("OK".to_owned()))
 }
 
-pub fn get(arguments: &Vec<String>, server: &Arc<Server>) -> RedisType {
+pub fn get(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
     let key = &arguments[1];
 
-    let data_option = server.data_store.get(key);
-    if data_option.is_none() {
-        return RedisType::BulkString(None);
+    match server.data_store.get(key) {
+        None => Ok(RedisType::BulkString(None)),
+        Some(DataType::String(value)) => Ok(RedisType::BulkString(Some(value))),
+        Some(_) => Err(RedisError::WrongType),
+    }
+}
+
+pub fn flushall(
+    arguments: &[Vec<u8>],
+    server: &Arc<Server>,
+    is_replication_connection: bool,
+) -> RedisResult<RedisType> {
+    let mut replication_lock: Option<MutexGuard<()>> = None;
+    if !is_replication_connection {
+        replication_lock = Some(server.master_repl_mutex.lock().unwrap());
+    }
+
+    server.data_store.flush();
+
+    // this needs to be inside a lock to guarantee replicas receive commands in the right order
+    if !is_replication_connection {
+        server.propagate(convert_to_redis_bulk_string_array(
+            arguments.iter().collect(),
+        ));
+    }
+
+    drop(replication_lock);
+
+    Ok(RedisType::SimpleString("OK".to_owned()))
+}
+
+pub fn save(server: &Arc<Server>) -> RedisResult<RedisType> {
+    server.save()?;
+    Ok(RedisType::SimpleString("OK".to_owned()))
+}
+
+/// BGSAVE [SCHEDULE], SCHEDULE waits for a running save instead of failing
+pub fn bgsave(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
+    let schedule = arguments.len() == 2;
+    if schedule && !arguments[1].eq_ignore_ascii_case(b"schedule") {
+        return Err(RedisError::Syntax);
+    }
+    let mut save_state = server.save_state.lock().unwrap();
+    if schedule && save_state.in_progress {
+        save_state.scheduled = true;
+        return Ok(RedisType::SimpleString(
+            "Background saving scheduled".to_owned(),
+        ));
+    }
+    drop(save_state);
+    server.background_save()?;
+    Ok(RedisType::SimpleString(
+        "Background saving started".to_owned(),
+    ))
+}
+
+pub fn bgrewriteaof(server: &Arc<Server>) -> RedisResult<RedisType> {
+    server.background_rewrite_aof()?;
+    Ok(RedisType::SimpleString(
+        "Background append only file rewriting started".to_owned(),
+    ))
+}
+
+pub fn lastsave(server: &Arc<Server>) -> RedisResult<RedisType> {
+    let save_state = server.save_state.lock().unwrap();
+    let seconds = save_state
+        .last_save
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Ok(RedisType::Integer(seconds as i64))
+}
+
+/// SHUTDOWN [NOSAVE | SAVE], saves first when there are save points or SAVE was given, and stays
+/// up if that save fails
+pub fn shutdown(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
+    let mut save = !server.save_points.read().unwrap().is_empty();
+    for argument in &arguments[1..] {
+        match argument.to_ascii_lowercase().as_slice() {
+            b"nosave" => save = false,
+            b"save" => save = true,
+            _ => return Err(RedisError::Syntax),
+        }
+    }
+    let error = server.shutdown(save);
+    println!("error saving before shutdown: {error}");
+    Err(RedisError::Generic(
+        "Errors trying to SHUTDOWN. Check logs.".to_owned(),
+    ))
+}
+
+pub fn publish(
+    arguments: &[Vec<u8>],
+    server: &Arc<Server>,
+    is_replication_connection: bool,
+) -> RedisResult<RedisType> {
+    let channel = &arguments[1];
+    let message = &arguments[2];
+
+    let mut replication_lock: Option<MutexGuard<()>> = None;
+    if !is_replication_connection {
+        replication_lock = Some(server.master_repl_mutex.lock().unwrap());
+    }
+
+    let receivers = if arguments[0].eq_ignore_ascii_case(b"spublish") {
+        server.pub_sub.spublish(channel, message)
     } else {
-        return match data_option.unwrap() {
-            DataType::String(value) => RedisType::BulkString(Some(value)),
-            _ => RedisType::SimpleError("Error, wrongtype operation".to_owned()),
-        };
+        server.pub_sub.publish(channel, message)
+    };
+
+    // replicas deliver the message to their own subscribers
+    if !is_replication_connection {
+        server.queue_send_to_replications(convert_to_redis_bulk_string_array(
+            arguments.iter().collect(),
+        ));
+    }
+
+    drop(replication_lock);
+
+    Ok(RedisType::Integer(receivers as i64))
+}
+
+pub fn pubsub(arguments: &[Vec<u8>], server: &Arc<Server>) -> RedisResult<RedisType> {
+    Ok(
+        match arg_parse::to_text(&arguments[1])
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "channels" => {
+                convert_to_redis_bulk_string_array(server.pub_sub.channels(arguments.get(2)))
+            }
+            "numsub" => {
+                let mut result = Vec::new();
+                for channel in &arguments[2..] {
+                    result.push(RedisType::BulkString(Some(channel.clone())));
+                    result.push(RedisType::Integer(server.pub_sub.numsub(channel) as i64));
+                }
+                RedisType::Array(result)
+            }
+            "numpat" => RedisType::Integer(server.pub_sub.numpat() as i64),
+            "shardchannels" => {
+                convert_to_redis_bulk_string_array(server.pub_sub.shard_channels(arguments.get(2)))
+            }
+            "shardnumsub" => {
+                let mut result = Vec::new();
+                for channel in &arguments[2..] {
+                    result.push(RedisType::BulkString(Some(channel.clone())));
+                    result.push(RedisType::Integer(
+                        server.pub_sub.shard_numsub(channel) as i64
+                    ));
+                }
+                RedisType::Array(result)
+            }
+            _ => {
+                return Err(RedisError::UnknownSubcommand(
+                    arg_parse::to_text(&arguments[1]),
+                    "PUBSUB".to_owned(),
+                ))
+            }
+        },
+    )
+}
+
+fn command_info(spec: &CommandSpec) -> RedisType {
+    RedisType::Array(vec![
+        RedisType::BulkString(Some(spec.name.into())),
+        RedisType::Integer(spec.arity),
+        RedisType::Array(
+            spec.flags
+                .iter()
+                .map(|flag| RedisType::SimpleString(flag.to_string()))
+                .collect(),
+        ),
+        RedisType::Integer(spec.first_key),
+        RedisType::Integer(spec.last_key),
+        RedisType::Integer(spec.step),
+        RedisType::Array(
+            spec.acl_categories()
+                .iter()
+                .map(|category| RedisType::SimpleString(format!("@{category}")))
+                .collect(),
+        ),
+        RedisType::Array(Vec::new()), // tips
+        RedisType::Array(Vec::new()), // key specs
+        RedisType::Array(Vec::new()), // subcommands
+    ])
+}
+
+fn command_docs(spec: &CommandSpec) -> RedisType {
+    convert_to_redis_bulk_string_array(vec![
+        "summary",
+        spec.summary,
+        "since",
+        spec.since,
+        "group",
+        spec.group,
+    ])
+}
+
+/// the specs for the names given, or every command if there weren't any names
+fn requested_specs(names: &[Vec<u8>]) -> Vec<Option<&'static CommandSpec>> {
+    if names.is_empty() {
+        return command_table::all().iter().map(Some).collect();
+    }
+    names
+        .iter()
+        .map(|name| command_table::lookup(&arg_parse::to_text(name).to_ascii_lowercase()))
+        .collect()
+}
+
+pub fn command(arguments: &[Vec<u8>]) -> RedisResult<RedisType> {
+    if arguments.len() == 1 {
+        return Ok(RedisType::Array(
+            command_table::all().iter().map(command_info).collect(),
+        ));
     }
+
+    Ok(
+        match arg_parse::to_text(&arguments[1])
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "count" => RedisType::Integer(command_table::all().len() as i64),
+            "info" => RedisType::Array(
+                requested_specs(&arguments[2..])
+                    .into_iter()
+                    .map(|spec_option| {
+                        spec_option.map_or(RedisType::BulkString(None), command_info)
+                    })
+                    .collect(),
+            ),
+            "docs" => {
+                let mut result = Vec::new();
+                for spec in requested_specs(&arguments[2..]).into_iter().flatten() {
+                    result.push(RedisType::BulkString(Some(spec.name.into())));
+                    result.push(command_docs(spec));
+                }
+                RedisType::Array(result)
+            }
+            "getkeys" => {
+                if arguments.len() < 3 {
+                    return Err(RedisError::WrongArity("command|getkeys".to_owned()));
+                }
+                let target = Vec::from(&arguments[2..]);
+                let spec_option =
+                    command_table::lookup(&arg_parse::to_text(&target[0]).to_ascii_lowercase());
+                if spec_option.is_none() {
+                    return Err(RedisError::Generic("Invalid command specified".to_owned()));
+                }
+                let spec = spec_option.unwrap();
+                if !command_table::arity_matches(spec, &target) {
+                    return Err(RedisError::Generic(
+                        "Invalid number of arguments specified for command".to_owned(),
+                    ));
+                }
+                let keys = command_table::get_keys(spec, &target);
+                if keys.is_empty() {
+                    return Err(RedisError::Generic(
+                        "The command has no key arguments".to_owned(),
+                    ));
+                }
+                convert_to_redis_bulk_string_array(keys)
+            }
+            _ => {
+                return Err(RedisError::UnknownSubcommand(
+                    arg_parse::to_text(&arguments[1]),
+                    "COMMAND".to_owned(),
+                ))
+            }
+        },
+    )
 }
@@ -0,0 +1,15 @@
+use std::{sync::Arc, thread, time::Duration};
+
+use crate::Server;
+
+pub fn expiry_loop(server: Arc<Server>) {
+    loop {
+        // expiring is a write, replicas would see it even though CLIENT PAUSE is holding writes back
+        if !server.is_paused(true) {
+            let transaction_lock = server.data_store.shared_lock();
+            server.data_store.remove_expired_keys();
+            drop(transaction_lock);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
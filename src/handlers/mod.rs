@@ -1,5 +1,10 @@
+mod acl_command;
+mod client_command;
 pub mod client_handler;
+pub mod command_table;
 mod commands;
+pub mod expiry_handler;
+pub mod persistence_handler;
 pub mod replication_handler;
 pub mod server_handler;
-mod utils;
+pub mod utils;
@@ -0,0 +1,305 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Seek},
+    path::Path,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use super::server_handler;
+use crate::{
+    error::{RedisError, RedisResult},
+    structs::aof::AppendFsync,
+    utils::{aof, arg_parse, rdb},
+    Server,
+};
+
+/// starts a background save whenever a save point has been reached, and an AOF rewrite whenever
+/// the AOF has grown enough
+pub fn save_loop(server: Arc<Server>) {
+    loop {
+        if server.should_background_save() {
+            // the snapshot has to see transactions whole
+            let transaction_lock = server.data_store.shared_lock();
+            let _ = server.background_save(); // it only fails if one is already running
+            drop(transaction_lock);
+        }
+        if server.aof.should_rewrite() {
+            let transaction_lock = server.data_store.shared_lock();
+            let result = server.background_rewrite_aof();
+            if result.is_err() {
+                println!("couldn't start an AOF rewrite: {}", result.err().unwrap());
+            }
+            drop(transaction_lock);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// appendfsync everysec, the most a crash can lose is the last second of writes
+pub fn aof_fsync_loop(server: Arc<Server>) {
+    loop {
+        if *server.aof.fsync.read().unwrap() == AppendFsync::EverySec {
+            server.aof.fsync();
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// fills the data store before any client is served, from the AOF when appendonly is on since it
+/// has every write, from the RDB file otherwise
+pub fn load(server: &Arc<Server>) -> RedisResult<()> {
+    if !server.aof.is_enabled() {
+        load_rdb_file(server)?;
+    } else {
+        let mut manifest = aof::read_manifest(&server.aof.manifest_path())?;
+        if manifest.is_none() && Path::new(&server.aof.single_file_path(&server.dir)).exists() {
+            manifest = Some(server.aof.upgrade_single_file(&server.dir)?);
+        }
+        if let Some(manifest) = manifest {
+            let files = manifest.files();
+            for (i, file) in files.iter().enumerate() {
+                load_aof_file(
+                    server,
+                    &server.aof.file_path(&file.name),
+                    i == files.len() - 1,
+                )?;
+            }
+            server.aof.open(manifest)?;
+        } else {
+            // appendonly was just turned on, the AOF starts out as a copy of the RDB file
+            load_rdb_file(server)?;
+            server.start_aof()?;
+        }
+    }
+    server.save_state.lock().unwrap().saved_version = server.data_store.last_version(); // what was loaded is already on disk
+    Ok(())
+}
+
+/// a missing file just means there is nothing to load
+fn load_rdb_file(server: &Arc<Server>) -> RedisResult<()> {
+    let file_result = File::open(server.rdb_path());
+    if file_result.is_err() {
+        return Ok(());
+    }
+    let checksum = *server.rdb_checksum.read().unwrap();
+    rdb::load_rdb(
+        &mut BufReader::new(file_result.unwrap()),
+        &server.data_store,
+        checksum,
+    )
+}
+
+/// replays one of the AOF's files, an RDB preamble and then commands. A command cut short at the
+/// end of the last file is what a crash mid-write leaves behind, it gets truncated away, anything
+/// else wrong refuses to load
+fn load_aof_file(server: &Arc<Server>, path: &String, last: bool) -> RedisResult<()> {
+    let file_result = File::open(path);
+    if file_result.is_err() {
+        return Err(RedisError::Aof(format!(
+            "couldn't open {path}: {}",
+            file_result.err().unwrap()
+        )));
+    }
+    let file = file_result.unwrap();
+    let length = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    if reader.fill_buf()?.starts_with(b"REDIS") {
+        let checksum = *server.rdb_checksum.read().unwrap();
+        rdb::load_rdb(&mut reader, &server.data_store, checksum)?;
+    }
+
+    let mut multi_queue: Option<Vec<Vec<Vec<u8>>>> = None;
+    let mut multi_offset = 0; // where the open transaction started
+    let mut valid_length = length;
+    loop {
+        let offset = reader.stream_position()?;
+        if offset == length {
+            break;
+        }
+        if reader.fill_buf()?[0] != b'*' {
+            return Err(RedisError::Aof(format!(
+                "expected '*' at offset {offset} of {path}"
+            )));
+        }
+        let arguments_result = server_handler::parse_arguments(&mut reader);
+        if let Err(RedisError::Io(error)) = &arguments_result {
+            if error.kind() == ErrorKind::UnexpectedEof {
+                valid_length = offset;
+                break;
+            }
+        }
+        if arguments_result.is_err() {
+            return Err(RedisError::Aof(format!(
+                "{} at offset {offset} of {path}",
+                arguments_result.err().unwrap()
+            )));
+        }
+        let (arguments, _) = arguments_result.unwrap();
+        if arguments.is_empty() {
+            return Err(RedisError::Aof(format!(
+                "empty command at offset {offset} of {path}"
+            )));
+        }
+
+        match arg_parse::to_text(&arguments[0])
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "multi" => {
+                multi_queue = Some(Vec::new());
+                multi_offset = offset;
+            }
+            "exec" if multi_queue.is_some() => {
+                for arguments in multi_queue.take().unwrap().iter() {
+                    server_handler::apply_replicated_command(arguments, server);
+                }
+            }
+            _ if multi_queue.is_some() => multi_queue.as_mut().unwrap().push(arguments),
+            _ => server_handler::apply_replicated_command(&arguments, server),
+        }
+    }
+
+    if multi_queue.is_some() {
+        println!("reverting an incomplete MULTI/EXEC transaction at the end of {path}");
+        valid_length = multi_offset;
+    } else if valid_length < length {
+        println!("!!! warning: short read while loading {path} !!!");
+    }
+    if valid_length < length && !last {
+        // only the file being appended to when the server stopped can end part way through
+        return Err(RedisError::Aof(format!(
+            "{path} is truncated but isn't the last file of the AOF"
+        )));
+    }
+    if valid_length < length {
+        println!("truncating {path} from {length} to {valid_length} bytes");
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(valid_length)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handlers::server_handler::tests::{assert_reply, connect, start_server, test_config},
+        structs::{data_store::DataType, server::ServerConfig},
+    };
+    use std::{fs, io::Write};
+
+    /// appendonly in a fresh directory of its own
+    fn aof_config(name: &str) -> ServerConfig {
+        let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        ServerConfig {
+            dir: Some(dir.to_string_lossy().into_owned()),
+            appendonly: Some("yes".to_owned()),
+            ..test_config(name)
+        }
+    }
+
+    /// what a restart with the same options loads
+    fn restart(config: &ServerConfig) -> Arc<Server> {
+        let server = Arc::new(Server::new(config.clone()).unwrap());
+        load(&server).unwrap();
+        server
+    }
+
+    fn get_string(server: &Server, key: &[u8]) -> Option<Vec<u8>> {
+        match server.data_store.get(key) {
+            Some(DataType::String(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn write_some_commands(port: u16) {
+        let mut client = connect(port);
+        assert_reply(&mut client, &["SET", "a", "1"], b"+OK\r\n");
+        assert_reply(&mut client, &["MULTI"], b"+OK\r\n");
+        assert_reply(&mut client, &["INCR", "n"], b"+QUEUED\r\n");
+        assert_reply(&mut client, &["INCR", "n"], b"+QUEUED\r\n");
+        assert_reply(&mut client, &["EXEC"], b"*2\r\n:1\r\n:2\r\n");
+    }
+
+    #[test]
+    fn aof_is_replayed_on_restart() {
+        let config = aof_config("aof-replay");
+        let (_server, port) = start_server(config.clone());
+        write_some_commands(port);
+
+        let restarted = restart(&config);
+        assert_eq!(get_string(&restarted, b"a"), Some(b"1".to_vec()));
+        assert_eq!(get_string(&restarted, b"n"), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn a_truncated_aof_tail_is_cut_off_on_restart() {
+        let config = aof_config("aof-truncated");
+        let (server, port) = start_server(config.clone());
+        write_some_commands(port);
+
+        // a crash part way through a command, after a transaction that never got its EXEC
+        let manifest = aof::read_manifest(&server.aof.manifest_path())
+            .unwrap()
+            .unwrap();
+        let path = server.aof.file_path(&manifest.files().last().unwrap().name);
+        let length = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(
+            b"*1\r\n$5\r\nMULTI\r\n*2\r\n$4\r\nINCR\r\n$1\r\nn\r\n*3\r\n$3\r\nSET\r\n$1\r\nb",
+        )
+        .unwrap();
+        drop(file);
+
+        let restarted = restart(&config);
+        assert_eq!(get_string(&restarted, b"a"), Some(b"1".to_vec()));
+        assert_eq!(get_string(&restarted, b"n"), Some(b"2".to_vec()));
+        assert_eq!(get_string(&restarted, b"b"), None);
+        assert_eq!(fs::metadata(&path).unwrap().len(), length);
+    }
+
+    #[test]
+    fn aof_rewrite_rotates_the_manifest() {
+        let config = aof_config("aof-rewrite");
+        let (server, port) = start_server(config.clone());
+        write_some_commands(port);
+        let manifest_path = server.aof.manifest_path();
+        let before = aof::read_manifest(&manifest_path).unwrap().unwrap();
+        assert_eq!(before.base.as_ref().unwrap().seq, 1);
+        assert_eq!(before.incrementals.len(), 1);
+
+        let mut client = connect(port);
+        assert_reply(
+            &mut client,
+            &["BGREWRITEAOF"],
+            b"+Background append only file rewriting started\r\n",
+        );
+        while server.aof.rewrite_in_progress() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_reply(&mut client, &["SET", "c", "3"], b"+OK\r\n");
+
+        // a new base and incremental file, and the ones they replaced are gone
+        let after = aof::read_manifest(&manifest_path).unwrap().unwrap();
+        assert_eq!(after.base.as_ref().unwrap().seq, 2);
+        let incremental_seqs: Vec<u64> = after.incrementals.iter().map(|file| file.seq).collect();
+        assert_eq!(incremental_seqs, vec![2]);
+        for file in before.files() {
+            assert!(!Path::new(&server.aof.file_path(&file.name)).exists());
+        }
+        for file in after.files() {
+            assert!(Path::new(&server.aof.file_path(&file.name)).exists());
+        }
+
+        let restarted = restart(&config);
+        assert_eq!(get_string(&restarted, b"a"), Some(b"1".to_vec()));
+        assert_eq!(get_string(&restarted, b"n"), Some(b"2".to_vec()));
+        assert_eq!(get_string(&restarted, b"c"), Some(b"3".to_vec()));
+    }
+}
@@ -1,3 +1,4 @@
+use bytes::Bytes;
 use std::{
     io::Write,
     net::TcpStream,
@@ -7,15 +8,27 @@ use std::{
 };
 
 use crate::{
-    macros::option_type_guard,
-    utils::resp_parser::{self, RedisType},
+    macros::{option_get_or_return_none, option_type_guard},
+    utils::{
+        arg_parse,
+        resp_parser::{self, RedisType},
+    },
     Server,
 };
 
 pub struct Replication {
-    pub(crate) stream: TcpStream, // this should be set to non blocking mode before constructing
-    pub(crate) send_buffer: RwLock<Vec<String>>,
+    pub(crate) stream: TcpStream, // non blocking once the snapshot has been sent
+    pub(crate) send_buffer: RwLock<Vec<Bytes>>,
     pub(crate) master_repl_offset: RwLock<u64>,
+    pub(crate) syncing: Arc<RwLock<bool>>, // the snapshot is still being sent, writes wait until it's done
+}
+
+/// the offset out of a `REPLCONF ACK <offset>` response, anything else from a replica is ignored
+fn acknowledged_offset(response: &RedisType) -> Option<u64> {
+    option_type_guard!(response_array, response, RedisType::Array);
+    option_get_or_return_none!(offset_part, response_array?.get(2));
+    option_type_guard!(offset_string, offset_part, RedisType::BulkString);
+    arg_parse::parse::<u64>(offset_string?.as_ref()?)
 }
 
 pub fn replication_loop(server: Arc<Server>) {
@@ -24,18 +37,16 @@ pub fn replication_loop(server: Arc<Server>) {
         let mut drop_indicies: Vec<usize> = Vec::new();
         for i in 0..replication_vec.len() {
             let replication = &mut replication_vec[i];
+            if *replication.syncing.read().unwrap() {
+                continue;
+            }
 
             // non blocking read for getack response for offset
-            let response_and_count_option = resp_parser::decode(&mut replication.stream);
-            if response_and_count_option.is_some() {
-                let (response, _) = response_and_count_option.unwrap();
-                option_type_guard!(response_array, response, RedisType::Array);
-                let response_offset_part = &response_array.unwrap()[2];
-                option_type_guard!(offset_string, response_offset_part, RedisType::BulkString);
-
-                let offset = str::parse::<u64>(offset_string.unwrap().as_ref().unwrap()).unwrap();
-                let mut master_repl_offset = replication.master_repl_offset.write().unwrap();
-                *master_repl_offset = offset;
+            if let Ok((response, _)) = resp_parser::decode(&mut replication.stream) {
+                if let Some(offset) = acknowledged_offset(&response) {
+                    let mut master_repl_offset = replication.master_repl_offset.write().unwrap();
+                    *master_repl_offset = offset;
+                }
             }
 
             // check for data to send
@@ -44,10 +55,10 @@ pub fn replication_loop(server: Arc<Server>) {
                 continue;
             }
             let command = send_buffer.remove(0);
-            let write_result = replication.stream.write(&command.as_bytes());
+            let write_result = replication.stream.write(&command);
 
             if write_result.is_err() {
-                drop_indicies.push(i as usize);
+                drop_indicies.push(i);
             }
         }
 
@@ -1,87 +1,197 @@
 use std::{
-    fs::File,
-    io::{Read, Write},
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::{BufReader, BufWriter, Read, Write},
     net::TcpStream,
     sync::{Arc, RwLock},
+    thread,
+    time::SystemTime,
 };
 
-use super::{commands, replication_handler::Replication, utils::send};
+use super::{
+    acl_command, client_command,
+    command_table::{self, CommandContext, CommandSpec},
+    commands,
+    replication_handler::Replication,
+    utils::{convert_to_redis_bulk_string_array, send},
+};
 use crate::{
-    macros::option_type_guard,
-    utils::resp_parser::{self, RedisType},
+    error::{RedisError, RedisResult},
+    structs::{
+        client::{Client, ReplyMode, TrackingState},
+        data_store::Snapshot,
+        server::REDIS_VERSION,
+    },
+    utils::{
+        arg_parse, byte_stream, inline_parser, rdb,
+        resp_parser::{self, RedisType},
+    },
     Server,
 };
 
-fn parse_arguments(stream: &mut impl Read) -> Option<(Vec<String>, u64)> {
-    let input_option = resp_parser::decode(stream);
-    if input_option.is_none() {
-        return None; // socket closed or bad parse
-    }
-    let (input, bytes_read) = input_option.unwrap();
-    option_type_guard!(arguments_option, input, RedisType::Array);
-    // clients should only be sending arrays of bulk strings
-    return Some((
-        arguments_option
-            .unwrap()
-            .iter()
-            .map(|v| {
-                option_type_guard!(string, v, RedisType::BulkString);
-                return string.unwrap().as_ref().unwrap().to_owned();
-            })
-            .collect(),
-        bytes_read,
-    ));
-}
-
-fn psync(mut stream: TcpStream, server: &Arc<Server>) {
-    let master_replid = &server.master_replid;
-    let master_repl_offset = &server.master_repl_offset.read().unwrap();
-    send(
-        &mut stream,
-        resp_parser::encode_simple_string(&format!(
-            "FULLRESYNC {master_replid} {master_repl_offset}"
-        )),
-    );
-    let filepath = server.dir.clone() + "/" + &server.dbfilename;
-    let mut empty_rdb_stream = File::open(filepath).unwrap();
-    let _ = stream.write(resp_parser::encode_rdb(&mut empty_rdb_stream).as_slice());
+pub fn parse_arguments(stream: &mut impl Read) -> RedisResult<(Vec<Vec<u8>>, u64)> {
+    // anything that doesn't start like a RESP array is an inline command
+    let first_byte = byte_stream::read_byte(stream)?;
+    if first_byte != b'*' {
+        return inline_parser::read_inline(stream, first_byte);
+    }
+    resp_parser::decode_command(&mut [first_byte].chain(stream))
+}
 
-    stream.set_nonblocking(true).unwrap();
+/// FULLRESYNC with a snapshot of the keyspace as it is right now, nothing is read from disk. The
+/// replica is registered at the same point, writes after it wait in its send buffer until the
+/// snapshot has been sent
+fn psync(mut stream: TcpStream, server: &Arc<Server>, eof_capable: bool) -> RedisResult<()> {
+    let transaction_lock = server.data_store.shared_lock(); // the snapshot has to see transactions whole
+    let replication_lock = server.master_repl_mutex.lock().unwrap();
+    let snapshot = server.data_store.snapshot();
+    let master_repl_offset = *server.master_repl_offset.read().unwrap();
+    let syncing = Arc::new(RwLock::new(true));
     let mut stream_vec = server.connected_replications.write().unwrap();
     stream_vec.push(Replication {
-        stream,
+        stream: stream.try_clone()?,
         send_buffer: RwLock::new(Vec::new()),
         master_repl_offset: RwLock::new(0),
+        syncing: Arc::clone(&syncing),
     });
     drop(stream_vec);
+    drop(replication_lock);
+    drop(transaction_lock);
+
+    let master_replid = &server.master_replid;
+    let mut result = send(
+        &mut stream,
+        &resp_parser::encode(&RedisType::SimpleString(format!(
+            "FULLRESYNC {master_replid} {master_repl_offset}"
+        ))),
+    );
+    if result.is_ok() {
+        // the EOF marker form is only for replicas that said they understand it
+        let diskless = eof_capable && *server.repl_diskless_sync.read().unwrap();
+        result = send_snapshot(&mut stream, &snapshot, diskless, server);
+    }
+
+    // a failed transfer leaves a dead socket, the replication loop drops it on the next write
+    stream.set_nonblocking(true)?;
+    *syncing.write().unwrap() = false;
+    result
+}
+
+/// `$<length>\r\n` and the RDB file, or with repl-diskless-sync written straight to the socket
+/// between two `$EOF:<marker>` markers since its length isn't known up front
+fn send_snapshot(
+    stream: &mut TcpStream,
+    snapshot: &Snapshot,
+    diskless: bool,
+    server: &Arc<Server>,
+) -> RedisResult<()> {
+    let compression = *server.rdb_compression.read().unwrap();
+    let checksum = *server.rdb_checksum.read().unwrap();
+    if diskless {
+        let marker = eof_marker();
+        let mut writer = BufWriter::new(stream);
+        writer.write_all(format!("$EOF:{marker}\r\n").as_bytes())?;
+        rdb::write_rdb(&mut writer, &snapshot.entries, compression, checksum)?;
+        writer.write_all(marker.as_bytes())?;
+        writer.flush()?;
+        return Ok(());
+    }
+    let mut rdb = Vec::new();
+    rdb::write_rdb(&mut rdb, &snapshot.entries, compression, checksum)?;
+    stream.write_all(&resp_parser::encode_rdb(&mut rdb.as_slice())?)?;
+    Ok(())
+}
+
+/// 40 random hex characters, what can't show up by chance at the end of the RDB file
+fn eof_marker() -> String {
+    let mut marker = String::new();
+    while marker.len() < resp_parser::EOF_MARKER_LENGTH {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        marker.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    marker.truncate(resp_parser::EOF_MARKER_LENGTH);
+    marker
+}
+
+/// applies a write the master propagated (or one from the AOF while it loads), replicas never
+/// reply to these
+pub fn apply_replicated_command(arguments: &[Vec<u8>], server: &Arc<Server>) {
+    let spec_option =
+        command_table::lookup(&arg_parse::to_text(&arguments[0]).to_ascii_lowercase());
+    if spec_option.is_none() {
+        return;
+    }
+    let spec = spec_option.unwrap();
+    if !spec.is_propagated() || !command_table::arity_matches(spec, arguments) {
+        return;
+    }
+    if let Some(handler) = spec.handler {
+        let context = CommandContext {
+            is_replication_connection: true,
+            can_block: false,
+        };
+        // the deadline is fixed here so the key and the replica's own AOF agree on it
+        let mut arguments = arguments.to_vec();
+        if spec.name == "set" {
+            if let Ok(Some(expire_time)) = commands::set_expire_time(&arguments) {
+                arguments = commands::set_with_deadline(&arguments, expire_time);
+            }
+        }
+        let result = handler(&arguments, server, &context);
+        if result.is_ok() && spec.has_flag("write") {
+            // a replica keeps its own AOF, the master's writes are the only ones it gets
+            let command = convert_to_redis_bulk_string_array(arguments);
+            server.aof.append(&resp_parser::encode(&command));
+        }
+    }
 }
 
-pub fn replication_stream_handler(mut stream: TcpStream, server: Arc<Server>) {
+pub fn replication_stream_handler(stream: TcpStream, server: Arc<Server>) {
+    let mut reader = BufReader::new(stream);
+    let mut multi_queue: Option<Vec<Vec<Vec<u8>>>> = None;
     loop {
-        let arguments_option = parse_arguments(&mut stream);
-        if arguments_option.is_none() {
+        let arguments_result = parse_arguments(&mut reader);
+        if arguments_result.is_err() {
             return; // socket closed or bad parse
         }
-        let (arguments, bytes_read) = arguments_option.unwrap();
+        let (arguments, bytes_read) = arguments_result.unwrap();
+        if arguments.is_empty() {
+            continue;
+        }
 
-        let response_option: Option<RedisType> = match arguments[0].to_ascii_lowercase().as_str() {
-            "replconf" => Some(commands::replconf(&arguments, &server)), // replconf is the only one that should respond on a replication connection
-            "incr" => {
-                commands::incr(&arguments, &server, true);
-                None
+        match arg_parse::to_text(&arguments[0])
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            // replconf is the only one that should respond on a replication connection
+            "replconf" => {
+                let response = commands::replconf(&arguments, &server)
+                    .unwrap_or_else(|error| RedisType::SimpleError(error.to_string()));
+                if send(reader.get_mut(), &resp_parser::encode(&response)).is_err() {
+                    return;
+                }
+            }
+            "multi" => multi_queue = Some(Vec::new()),
+            "exec" if multi_queue.is_some() => {
+                // apply the whole transaction at once, same as the master did
+                let transaction_lock = server.data_store.exclusive_lock();
+                for arguments in multi_queue.take().unwrap().iter() {
+                    apply_replicated_command(arguments, &server);
+                }
+                drop(transaction_lock);
             }
-            "set" => {
-                commands::set(&arguments, &server, true);
-                None
+            _ if multi_queue.is_some() => multi_queue.as_mut().unwrap().push(arguments),
+            _ => {
+                let transaction_lock = server.data_store.shared_lock();
+                apply_replicated_command(&arguments, &server);
+                drop(transaction_lock);
             }
-            _ => None,
-        };
-
-        if response_option.is_some() {
-            send(
-                &mut stream,
-                resp_parser::encode(response_option.as_ref().unwrap()),
-            );
         }
 
         let mut master_repl_offset = server.master_repl_offset.write().unwrap();
@@ -90,89 +200,963 @@ pub fn replication_stream_handler(mut stream: TcpStream, server: Arc<Server>) {
     }
 }
 
-pub fn stream_handler(mut stream: TcpStream, server: Arc<Server>) {
+/// a push frame on RESP3 connections, like the messages that follow it
+fn subscription_reply(kind: &str, channel: Option<&Vec<u8>>, count: usize) -> RedisType {
+    RedisType::Push(vec![
+        RedisType::BulkString(Some(kind.into())),
+        RedisType::BulkString(channel.cloned()),
+        RedisType::Integer(count as i64),
+    ])
+}
+
+/// SUBSCRIBE, PSUBSCRIBE, SSUBSCRIBE and their unsubscribe counterparts, which reply once per channel
+fn subscription_command(arguments: &[Vec<u8>], client: &Arc<Client>, server: &Arc<Server>) {
+    let command = arg_parse::to_text(&arguments[0]).to_ascii_lowercase();
+    let mut channels = Vec::from(&arguments[1..]);
+    if channels.is_empty() {
+        channels = match command.as_str() {
+            "unsubscribe" => client.channels.read().unwrap().iter().cloned().collect(),
+            "punsubscribe" => client.patterns.read().unwrap().iter().cloned().collect(),
+            "sunsubscribe" => client
+                .shard_channels
+                .read()
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect(),
+            _ => {
+                client.reply_error(&format!(
+                    "ERR wrong number of arguments for '{command}' command"
+                ));
+                return;
+            }
+        };
+        if channels.is_empty() {
+            // unsubscribing from nothing still gets a single reply
+            client.reply(subscription_reply(
+                &command,
+                None,
+                subscription_count(&command, client),
+            ));
+            return;
+        }
+    }
+
+    for channel in &channels {
+        match command.as_str() {
+            "subscribe" => server.pub_sub.subscribe(client, channel),
+            "psubscribe" => server.pub_sub.psubscribe(client, channel),
+            "ssubscribe" => server.pub_sub.ssubscribe(client, channel),
+            "unsubscribe" => server.pub_sub.unsubscribe(client, channel),
+            "punsubscribe" => server.pub_sub.punsubscribe(client, channel),
+            _ => server.pub_sub.sunsubscribe(client, channel),
+        };
+        client.reply(subscription_reply(
+            &command,
+            Some(channel),
+            subscription_count(&command, client),
+        ));
+    }
+}
+
+/// shard channel replies count shard subscriptions only, the others count channels and patterns
+fn subscription_count(command: &str, client: &Arc<Client>) -> usize {
+    if command == "ssubscribe" || command == "sunsubscribe" {
+        return client.shard_subscription_count();
+    }
+    client.subscription_count()
+}
+
+/// while subscribed a RESP2 connection can only manage its subscriptions
+fn allowed_while_subscribed(command: &str) -> bool {
+    matches!(
+        command,
+        "subscribe"
+            | "psubscribe"
+            | "ssubscribe"
+            | "unsubscribe"
+            | "punsubscribe"
+            | "sunsubscribe"
+            | "ping"
+            | "quit"
+            | "reset"
+    )
+}
+
+/// HELLO [protover [AUTH username password] [SETNAME clientname]], switches the connection's
+/// protocol version and replies with details about the server
+fn hello(
+    arguments: &[Vec<u8>],
+    client: &Arc<Client>,
+    server: &Arc<Server>,
+) -> RedisResult<RedisType> {
+    let mut protover = client.protover();
+    if arguments.len() > 1 {
+        let requested = arg_parse::parse::<i64>(&arguments[1]).ok_or_else(|| {
+            RedisError::Generic("Protocol version is not an integer or out of range".to_owned())
+        })?;
+        if requested != 2 && requested != 3 {
+            return Err(RedisError::NoProto);
+        }
+        protover = requested as u8;
+    }
+
+    let mut name: Option<&Vec<u8>> = None;
+    let mut credentials: Option<(&Vec<u8>, &Vec<u8>)> = None;
+    let mut i = 2;
+    while i < arguments.len() {
+        match arguments[i].to_ascii_lowercase().as_slice() {
+            b"auth" if i + 2 < arguments.len() => {
+                credentials = Some((&arguments[i + 1], &arguments[i + 2]));
+                i += 3;
+            }
+            b"setname" if i + 1 < arguments.len() => {
+                name = Some(&arguments[i + 1]);
+                i += 2;
+            }
+            _ => {
+                return Err(RedisError::Generic(format!(
+                    "Syntax error in HELLO option '{}'",
+                    arg_parse::to_text(&arguments[i])
+                )))
+            }
+        }
+    }
+
+    if let Some((username, password)) = credentials {
+        log_in(&arg_parse::to_text(username), password, client, server)?;
+    } else if !client.is_authenticated() {
+        return Err(RedisError::NoAuth("HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time".to_owned()));
+    }
+
+    // nothing else changes unless every option was valid
+    if let Some(name) = name {
+        client.set_name(name)?;
+    }
+    *client.protover.write().unwrap() = protover;
+
+    let role = if server.role == "master" {
+        "master"
+    } else {
+        "replica"
+    };
+    Ok(RedisType::Map(vec![
+        (
+            RedisType::BulkString(Some("server".into())),
+            RedisType::BulkString(Some("redis".into())),
+        ),
+        (
+            RedisType::BulkString(Some("version".into())),
+            RedisType::BulkString(Some(REDIS_VERSION.into())),
+        ),
+        (
+            RedisType::BulkString(Some("proto".into())),
+            RedisType::Integer(protover as i64),
+        ),
+        (
+            RedisType::BulkString(Some("id".into())),
+            RedisType::Integer(client.id as i64),
+        ),
+        (
+            RedisType::BulkString(Some("mode".into())),
+            RedisType::BulkString(Some("standalone".into())),
+        ),
+        (
+            RedisType::BulkString(Some("role".into())),
+            RedisType::BulkString(Some(role.into())),
+        ),
+        (
+            RedisType::BulkString(Some("modules".into())),
+            RedisType::Array(Vec::new()),
+        ),
+    ]))
+}
+
+/// AUTH [username] password
+fn auth(arguments: &[Vec<u8>], client: &Arc<Client>, server: &Arc<Server>) -> RedisResult<()> {
+    if arguments.len() > 3 {
+        return Err(RedisError::Syntax);
+    }
+    if arguments.len() == 2 && !server.requires_auth() {
+        return Err(RedisError::Generic("AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".to_owned()));
+    }
+    let username = if arguments.len() == 3 {
+        arg_parse::to_text(&arguments[1])
+    } else {
+        "default".to_owned()
+    };
+    log_in(&username, arguments.last().unwrap(), client, server)
+}
+
+/// switches the connection to `username`, failed attempts show up in ACL LOG
+fn log_in(
+    username: &str,
+    password: &[u8],
+    client: &Arc<Client>,
+    server: &Arc<Server>,
+) -> RedisResult<()> {
+    let result = server.acl.authenticate(username, password);
+    if result.is_err() {
+        let client_info = client_command::describe(client, server);
+        server
+            .acl
+            .log_denial("auth", "toplevel", "AUTH", username, client_info);
+        return result;
+    }
+    *client.user.write().unwrap() = username.to_owned();
+    *client.authenticated.write().unwrap() = true;
+    Ok(())
+}
+
+/// the NOPERM error for a command the connection's user isn't allowed to run, logged for ACL LOG
+fn check_permissions(
+    spec: &CommandSpec,
+    arguments: &[Vec<u8>],
+    context: &str,
+    client: &Arc<Client>,
+    server: &Arc<Server>,
+) -> RedisResult<()> {
+    let username = client.user.read().unwrap().clone();
+    let denial_option = server.acl.check(&username, spec, arguments);
+    if denial_option.is_none() {
+        return Ok(());
+    }
+    let denial = denial_option.unwrap();
+    let client_info = client_command::describe(client, server);
+    server.acl.log_denial(
+        denial.reason,
+        context,
+        &denial.object,
+        &username,
+        client_info,
+    );
+    Err(RedisError::NoPerm(denial.message))
+}
+
+pub fn stream_handler(stream: TcpStream, server: Arc<Server>) {
+    let client_result = Client::new(server.allocate_client_id(), &stream);
+    if client_result.is_err() {
+        return;
+    }
+    let writer_stream_result = stream.try_clone();
+    if writer_stream_result.is_err() {
+        return;
+    }
+    let client = Arc::new(client_result.unwrap());
+    let writer_client = Arc::clone(&client);
+    let writer_stream = writer_stream_result.unwrap();
+    thread::spawn(move || writer_client.write_output(writer_stream));
+    *client.authenticated.write().unwrap() = !server.requires_auth();
+    server.add_client(&client);
+    connection_loop(&mut BufReader::new(stream), &client, &server);
+    server.remove_client(&client);
+    client.close_output();
+}
+
+fn connection_loop(reader: &mut BufReader<TcpStream>, client: &Arc<Client>, server: &Arc<Server>) {
     let mut multi_in_process = false;
-    let mut multi_queue: Vec<Vec<String>> = Vec::new();
+    let mut multi_dirty = false; // a command failed to queue, so EXEC has to abort
+    let mut multi_queue: Vec<Vec<Vec<u8>>> = Vec::new();
+    let mut watched_keys: Vec<(Vec<u8>, u64)> = Vec::new(); // key and its version at WATCH time
+    let mut replica_capabilities: Vec<String> = Vec::new(); // REPLCONF capa, for PSYNC
     loop {
-        let arguments_option = parse_arguments(&mut stream);
-        if arguments_option.is_none() {
-            return; // socket closed or bad parse
+        let (arguments, _) = match parse_arguments(reader) {
+            Ok(arguments_and_count) => arguments_and_count,
+            Err(error) => {
+                // like redis, reply to garbage with the error and then close the connection
+                if !error.is_disconnect() {
+                    client.reply_error(&error.to_string());
+                }
+                return;
+            }
+        };
+        if arguments.is_empty() {
+            continue;
+        }
+        let command = arg_parse::to_text(&arguments[0]).to_ascii_lowercase();
+        // a whole pipeline usually arrives in one read, its replies go out together after the last command
+        client.set_batching(next_command_buffered(reader));
+        if command == "client" && arguments.len() > 1 {
+            client.start_command(&format!(
+                "client|{}",
+                arg_parse::to_text(&arguments[1]).to_ascii_lowercase()
+            ));
+        } else {
+            client.start_command(&command);
+        }
+
+        if client.is_subscribed() && client.protover() == 2 {
+            if !allowed_while_subscribed(&command) {
+                client.reply_error(&format!(
+                    "ERR Can't execute '{command}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context"
+                ));
+                continue;
+            }
+            if command == "ping" {
+                let message = arguments.get(1).cloned().unwrap_or_default();
+                client.reply(convert_to_redis_bulk_string_array(vec![
+                    b"pong".as_slice(),
+                    &message,
+                ]));
+                continue;
+            }
+        }
+
+        let spec = match command_table::validate(&arguments) {
+            Ok(spec) => spec,
+            Err(error) => {
+                if multi_in_process {
+                    multi_dirty = true;
+                }
+                client.reply_error(&error.to_string());
+                continue;
+            }
+        };
+        if !client.is_authenticated() && !spec.has_flag("no_auth") {
+            if multi_in_process {
+                multi_dirty = true;
+            }
+            client.reply_error(
+                &RedisError::NoAuth("Authentication required.".to_owned()).to_string(),
+            );
+            continue;
+        }
+        let context = if multi_in_process {
+            "multi"
+        } else {
+            "toplevel"
+        };
+        if let Err(error) = check_permissions(spec, &arguments, context, client, server) {
+            if multi_in_process {
+                multi_dirty = true;
+            }
+            client.reply_error(&error.to_string());
+            continue;
+        }
+
+        // queued commands show up in MONITOR when EXEC runs them
+        let queueing = multi_in_process
+            && !matches!(
+                command.as_str(),
+                "exec" | "discard" | "multi" | "watch" | "quit" | "reset"
+            );
+        if !queueing {
+            let write = spec.is_propagated()
+                || (command == "exec" && multi_queue.iter().any(|arguments| is_write(arguments)));
+            if server.is_paused(write) {
+                client.set_batching(false);
+                server.wait_while_paused(write);
+            }
+            feed_monitors(&arguments, client, server);
         }
-        let (arguments, _) = arguments_option.unwrap();
 
         // special commands
-        match arguments[0].to_ascii_lowercase().as_str() {
+        match command.as_str() {
             "discard" => {
                 if multi_in_process {
                     multi_in_process = false;
+                    multi_dirty = false;
                     multi_queue.clear();
-                    send(&mut stream, resp_parser::encode_simple_string("OK"));
+                    watched_keys.clear();
+                    *client.multi.write().unwrap() = None;
+                    client.reply_status("OK");
                 } else {
-                    send(
-                        &mut stream,
-                        resp_parser::encode_simple_error("ERR DISCARD without MULTI"),
-                    );
+                    client.reply_error("ERR DISCARD without MULTI");
                 }
                 continue;
             }
             "exec" => {
                 if multi_in_process {
-                    let responses = multi_queue
-                        .iter()
-                        .map(|arguments| handle_command(&arguments, &server))
-                        .collect();
-                    send(&mut stream, resp_parser::encode_array(&responses));
+                    if multi_dirty {
+                        client.reply_error(
+                            "EXECABORT Transaction discarded because of previous errors.",
+                        );
+                    } else {
+                        match exec(&multi_queue, &watched_keys, client, server) {
+                            Some(responses) => {
+                                client.reply(RedisType::Array(responses));
+                                client.tracking.write().unwrap().caching = None;
+                            }
+                            None => client.send(resp_parser::NULL_ARRAY),
+                        }
+                    }
                     multi_in_process = false;
+                    multi_dirty = false;
                     multi_queue.clear();
+                    watched_keys.clear();
+                    *client.multi.write().unwrap() = None;
                 } else {
-                    send(
-                        &mut stream,
-                        resp_parser::encode_simple_error("ERR EXEC without MULTI"),
-                    )
+                    client.reply_error("ERR EXEC without MULTI")
+                }
+                continue;
+            }
+            "watch" if multi_in_process => {
+                client.reply_error("ERR WATCH inside MULTI is not allowed");
+                continue;
+            }
+            "watch" => {
+                for key in &arguments[1..] {
+                    watched_keys.push((key.clone(), server.data_store.version(key)));
                 }
+                client.reply_status("OK");
+                continue;
+            }
+            "unwatch" if !multi_in_process => {
+                watched_keys.clear();
+                client.reply_status("OK");
+                continue;
+            }
+            "multi" if multi_in_process => {
+                client.reply_error("ERR MULTI calls can not be nested");
                 continue;
             }
             "multi" => {
                 multi_in_process = true;
-                send(&mut stream, resp_parser::encode_simple_string("OK"));
+                *client.multi.write().unwrap() = Some(0);
+                client.reply_status("OK");
+                continue;
+            }
+            "subscribe" | "psubscribe" | "ssubscribe" | "unsubscribe" | "punsubscribe"
+            | "sunsubscribe"
+                if !multi_in_process =>
+            {
+                subscription_command(&arguments, client, server);
+                continue;
+            }
+            "auth" if !multi_in_process => {
+                match auth(&arguments, client, server) {
+                    Ok(()) => client.reply_status("OK"),
+                    Err(error) => client.reply_error(&error.to_string()),
+                }
+                continue;
+            }
+            "hello" if !multi_in_process => {
+                let response = hello(&arguments, client, server)
+                    .unwrap_or_else(|error| RedisType::SimpleError(error.to_string()));
+                client.reply(response);
+                continue;
+            }
+            "client" if !multi_in_process => {
+                let response = client_command::client(&arguments, client, server)
+                    .unwrap_or_else(|error| RedisType::SimpleError(error.to_string()));
+                client.reply(response);
+                continue;
+            }
+            "acl" if !multi_in_process => {
+                let response = acl_command::acl(&arguments, client, server)
+                    .unwrap_or_else(|error| RedisType::SimpleError(error.to_string()));
+                client.reply(response);
                 continue;
             }
-            "psync" => {
-                psync(stream, &server);
+            "monitor" if !multi_in_process => {
+                server.add_monitor(client);
+                client.reply_status("OK");
+                continue;
+            }
+            "quit" => {
+                client.reply_status("OK");
+                return;
+            }
+            "reset" => {
+                multi_in_process = false;
+                multi_dirty = false;
+                multi_queue.clear();
+                watched_keys.clear();
+                *client.multi.write().unwrap() = None;
+                server.pub_sub.remove_client(client);
+                server.remove_monitor(client);
+                server.tracking.remove_client(client);
+                *client.tracking.write().unwrap() = TrackingState::default();
+                *client.protover.write().unwrap() = 2;
+                *client.name.write().unwrap() = None;
+                *client.reply_mode.write().unwrap() = ReplyMode::On;
+                *client.no_evict.write().unwrap() = false;
+                // back to how a new connection starts, which logs out if the default user has a password
+                *client.user.write().unwrap() = "default".to_owned();
+                *client.authenticated.write().unwrap() = !server.requires_auth();
+                client.reply_status("RESET");
+                continue;
+            }
+            "replconf" => {
+                // a replica announces these before PSYNC, the reply is the usual one
+                for pair in arguments[1..].chunks_exact(2) {
+                    if pair[0].eq_ignore_ascii_case(b"capa") {
+                        replica_capabilities
+                            .push(arg_parse::to_text(&pair[1]).to_ascii_lowercase());
+                    }
+                }
+            }
+            "psync" if !multi_in_process => {
+                server.pub_sub.remove_client(client);
+                // the sync is written straight to the socket, after any replies and with nothing
+                // else from the writer thread mixed in
+                client.close_output();
+                client.wait_written();
+                let eof_capable = replica_capabilities.iter().any(|capa| capa == "eof");
+                let psync_result = reader
+                    .get_ref()
+                    .try_clone()
+                    .map_err(RedisError::from)
+                    .and_then(|stream| psync(stream, server, eof_capable));
+                if let Err(error) = psync_result {
+                    let _ = send(
+                        reader.get_mut(),
+                        &resp_parser::encode(&RedisType::SimpleError(error.to_string())),
+                    );
+                }
                 return; // This connection is now a replication connection that will be handled elsewhere
             }
             _ => {}
         }
 
         if multi_in_process {
+            if !can_queue(spec) {
+                multi_dirty = true;
+                client.reply_error("ERR Command not allowed inside a transaction");
+                continue;
+            }
             multi_queue.push(arguments);
-            send(&mut stream, resp_parser::encode_simple_string("QUEUED"));
+            *client.multi.write().unwrap() = Some(multi_queue.len());
+            client.reply_status("QUEUED");
             continue;
         }
 
-        send(
-            &mut stream,
-            resp_parser::encode(&handle_command(&arguments, &server)),
-        );
+        // blocking commands can't hold the lock or they would stall every transaction while they wait
+        let mut transaction_lock = None;
+        if !spec.may_block(&arguments) {
+            transaction_lock = Some(server.data_store.shared_lock());
+        } else {
+            client.set_batching(false); // don't keep earlier replies waiting while this one blocks
+        }
+        let context = CommandContext {
+            is_replication_connection: false,
+            can_block: true,
+        };
+        track_reads(spec, &arguments, client, server);
+        let response = handle_command(spec, &arguments, server, &context);
+        drop(transaction_lock);
+        client.tracking.write().unwrap().caching = None; // CLIENT CACHING only covers the next command
+        client.reply(response);
+    }
+}
+
+/// client side caching remembers the keys a read only command is about to look at. Doing it before
+/// the read means a write racing with it still sends an invalidation.
+fn track_reads(
+    spec: &CommandSpec,
+    arguments: &[Vec<u8>],
+    client: &Arc<Client>,
+    server: &Arc<Server>,
+) {
+    if spec.has_flag("readonly") {
+        server
+            .tracking
+            .track_read(client, command_table::get_keys(spec, arguments));
+    }
+}
+
+/// passwords never show up in MONITOR
+fn feed_monitors(arguments: &[Vec<u8>], client: &Arc<Client>, server: &Arc<Server>) {
+    let command = &arguments[0];
+    let has_password = command.eq_ignore_ascii_case(b"auth")
+        || (command.eq_ignore_ascii_case(b"hello")
+            && arguments
+                .iter()
+                .any(|argument| argument.eq_ignore_ascii_case(b"auth")));
+    if !has_password {
+        server.feed_monitors(client, arguments);
     }
 }
 
-fn handle_command(arguments: &Vec<String>, server: &Arc<Server>) -> RedisType {
-    return match arguments[0].to_ascii_lowercase().as_str() {
-        "incr" => commands::incr(arguments, server, false),
-        "xread" => commands::xread(arguments, server),
-        "xrange" => commands::xrange(arguments, server),
-        "xadd" => commands::xadd(arguments, server),
-        "type" => commands::value_type(arguments, server), // can't be 'type' because rust
-        "keys" => commands::keys(server),
-        "config" => commands::config(arguments, server),
-        "wait" => commands::wait(arguments, server),
-        "replconf" => commands::replconf(arguments, server),
-        "info" => commands::info(server),
-        "set" => commands::set(arguments, server, false),
-        "get" => commands::get(arguments, server),
-        "echo" => RedisType::BulkString(Some(arguments[1].to_owned())),
-        "ping" => RedisType::SimpleString("PONG".to_owned()),
-        _ => RedisType::SimpleError("Error, unsupported command".to_owned()),
+/// a queued command that CLIENT PAUSE WRITE holds EXEC back for
+fn is_write(arguments: &[Vec<u8>]) -> bool {
+    command_table::lookup(&arg_parse::to_text(&arguments[0]).to_ascii_lowercase())
+        .is_some_and(|spec| spec.is_propagated())
+}
+
+/// whether the next command is already read in full. Only part of one means reading the rest can
+/// block, and the client could be waiting on the replies so far before it sends it
+fn next_command_buffered(reader: &BufReader<TcpStream>) -> bool {
+    let mut buffered = reader.buffer();
+    !buffered.is_empty() && parse_arguments(&mut buffered).is_ok()
+}
+
+/// commands that need the connection itself can't be run later by EXEC
+fn can_queue(spec: &CommandSpec) -> bool {
+    spec.handler.is_some() && !spec.has_flag("no_multi")
+}
+
+/// runs a queued transaction while holding the store lock exclusively so no other client's commands
+/// interleave with it, and wraps its writes in MULTI/EXEC for the replicas. `None` when a watched
+/// key changed, checked under the same lock so nothing can change it before the commands run
+fn exec(
+    multi_queue: &[Vec<Vec<u8>>],
+    watched_keys: &[(Vec<u8>, u64)],
+    client: &Arc<Client>,
+    server: &Arc<Server>,
+) -> Option<Vec<RedisType>> {
+    let transaction_lock = server.data_store.exclusive_lock();
+    let watched_key_modified = watched_keys
+        .iter()
+        .any(|(key, version)| server.data_store.version(key) != *version);
+    if watched_key_modified {
+        return None;
+    }
+    let specs: Vec<&CommandSpec> = multi_queue
+        .iter()
+        .map(|arguments| {
+            command_table::lookup(&arg_parse::to_text(&arguments[0]).to_ascii_lowercase()).unwrap()
+        })
+        .collect(); // everything was validated when it was queued
+    let propagate = specs.iter().any(|spec| spec.is_propagated());
+    if propagate {
+        server.propagate(convert_to_redis_bulk_string_array(vec!["MULTI"]));
+    }
+
+    let context = CommandContext {
+        is_replication_connection: false,
+        can_block: false,
     };
+    let responses = specs
+        .iter()
+        .zip(multi_queue.iter())
+        .map(|(spec, arguments)| {
+            // permissions could have changed since the command was queued
+            if let Err(error) = check_permissions(spec, arguments, "multi", client, server) {
+                return RedisType::SimpleError(error.to_string());
+            }
+            feed_monitors(arguments, client, server);
+            track_reads(spec, arguments, client, server);
+            handle_command(spec, arguments, server, &context)
+        })
+        .collect();
+
+    if propagate {
+        server.propagate(convert_to_redis_bulk_string_array(vec!["EXEC"]));
+    }
+    drop(transaction_lock);
+    Some(responses)
+}
+
+fn handle_command(
+    spec: &CommandSpec,
+    arguments: &[Vec<u8>],
+    server: &Arc<Server>,
+    context: &CommandContext,
+) -> RedisType {
+    let result = match spec.handler {
+        Some(handler) => handler(arguments, server, context),
+        None => Err(RedisError::Generic(
+            "Command not allowed inside a transaction".to_owned(),
+        )),
+    };
+    result.unwrap_or_else(|error| RedisType::SimpleError(error.to_string()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        handlers::{persistence_handler, utils::convert_to_redis_bulk_strings},
+        structs::server::ServerConfig,
+    };
+    use std::{net::TcpListener, time::Duration};
+
+    /// files of its own in the temp dir, and no save points
+    pub(crate) fn test_config(name: &str) -> ServerConfig {
+        ServerConfig {
+            dir: Some(std::env::temp_dir().to_string_lossy().into_owned()),
+            dbfilename: Some(format!("{name}-{}.rdb", std::process::id())),
+            save: Some(String::new()),
+            ..Default::default()
+        }
+    }
+
+    /// a server that has loaded its files and accepts connections on a free port
+    pub(crate) fn start_server(config: ServerConfig) -> (Arc<Server>, u16) {
+        let server = Arc::new(Server::new(config).unwrap());
+        persistence_handler::load(&server).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accept_server = Arc::clone(&server);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let server = Arc::clone(&accept_server);
+                thread::spawn(move || stream_handler(stream.unwrap(), server));
+            }
+        });
+        (server, port)
+    }
+
+    pub(crate) fn connect(port: u16) -> TcpStream {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream
+    }
+
+    pub(crate) fn send_command(stream: &mut TcpStream, command: &[&str]) {
+        let request = RedisType::Array(convert_to_redis_bulk_strings(command.to_vec()));
+        stream.write_all(&resp_parser::encode(&request)).unwrap();
+    }
+
+    /// reads exactly `expected`, so anything after it is left for the next check
+    pub(crate) fn assert_received(stream: &mut TcpStream, expected: &[u8]) {
+        let mut received = vec![0; expected.len()];
+        stream.read_exact(&mut received).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&received),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    pub(crate) fn assert_reply(stream: &mut TcpStream, command: &[&str], expected: &[u8]) {
+        send_command(stream, command);
+        assert_received(stream, expected);
+    }
+
+    fn parse_error(input: &[u8]) -> String {
+        parse_arguments(&mut &input[..]).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parse_arguments_keeps_bytes() {
+        let mut input: &[u8] = b"*2\r\n$3\r\nGET\r\n$2\r\n\xff\x00\r\n";
+        let (arguments, bytes_read) = parse_arguments(&mut input).unwrap();
+        assert_eq!(arguments, vec![b"GET".to_vec(), b"\xff\x00".to_vec()]);
+        assert_eq!(bytes_read, 21);
+
+        let mut inline: &[u8] = b"set \"a\\xffb\" 'c d'\r\n";
+        let (arguments, _) = parse_arguments(&mut inline).unwrap();
+        assert_eq!(
+            arguments,
+            vec![b"set".to_vec(), b"a\xffb".to_vec(), b"c d".to_vec()]
+        );
+    }
+
+    #[test]
+    fn parse_arguments_rejects_anything_but_bulk_strings() {
+        assert_eq!(
+            parse_error(b"*1\r\n:1\r\n"),
+            "ERR Protocol error: expected '$'"
+        );
+        assert_eq!(
+            parse_error(b"*2\r\n$3\r\nGET\r\n$-1\r\n"),
+            "ERR Protocol error: expected '$'"
+        );
+        assert_eq!(
+            parse_error(b"*1\r\n*1\r\n$1\r\na\r\n"),
+            "ERR Protocol error: expected '$'"
+        );
+        assert_eq!(
+            parse_error(b"*x\r\n"),
+            "ERR Protocol error: invalid integer 'x'"
+        );
+        assert_eq!(
+            parse_error(b"*1\r\n$999999999999\r\n"),
+            "ERR Protocol error: invalid bulk length"
+        );
+        assert_eq!(
+            parse_error(b"get \"key\r\n"),
+            "ERR Protocol error: unbalanced quotes in request"
+        );
+        assert_eq!(
+            parse_error(b"get 'a'b\r\n"),
+            "ERR Protocol error: unbalanced quotes in request"
+        );
+        assert!(parse_arguments(&mut &b"*1\r\n$3\r\nGE"[..]).is_err());
+    }
+
+    #[test]
+    fn parse_arguments_rejects_nested_arrays_without_recursing() {
+        // deep enough to overflow the stack if each level were a recursive call
+        let nested = b"*1\r\n".repeat(200_000);
+        assert_eq!(parse_error(&nested), "ERR Protocol error: expected '$'");
+    }
+
+    #[test]
+    fn parse_arguments_never_panics_on_garbage() {
+        // bytes that mean something to one of the parsers, so the garbage gets past the first check
+        let alphabet = b"*$\r\n0123-\"' \\xab";
+        let mut state: u32 = 7;
+        for length in 0..3000 {
+            let bytes: Vec<u8> = (0..length % 48)
+                .map(|_| {
+                    state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                    alphabet[(state >> 16) as usize % alphabet.len()]
+                })
+                .collect();
+            let _ = parse_arguments(&mut bytes.as_slice());
+        }
+    }
+
+    #[test]
+    fn exec_returns_nil_after_a_watched_key_changes() {
+        let (_server, port) = start_server(test_config("watch-changed"));
+        let mut watcher = connect(port);
+        let mut writer = connect(port);
+        assert_reply(&mut watcher, &["WATCH", "k"], b"+OK\r\n");
+        assert_reply(&mut writer, &["SET", "k", "1"], b"+OK\r\n");
+
+        assert_reply(&mut watcher, &["MULTI"], b"+OK\r\n");
+        assert_reply(&mut watcher, &["SET", "k", "2"], b"+QUEUED\r\n");
+        assert_reply(&mut watcher, &["EXEC"], b"*-1\r\n");
+        assert_reply(&mut watcher, &["GET", "k"], b"$1\r\n1\r\n");
+    }
+
+    #[test]
+    fn exec_runs_when_watched_keys_are_unchanged() {
+        let (_server, port) = start_server(test_config("watch-unchanged"));
+        let mut client = connect(port);
+        assert_reply(&mut client, &["SET", "k", "1"], b"+OK\r\n");
+        assert_reply(&mut client, &["WATCH", "k"], b"+OK\r\n");
+        assert_reply(&mut client, &["MULTI"], b"+OK\r\n");
+        assert_reply(&mut client, &["INCR", "k"], b"+QUEUED\r\n");
+        assert_reply(&mut client, &["EXEC"], b"*1\r\n:2\r\n");
+
+        // EXEC unwatches, so a later change doesn't affect the next transaction
+        assert_reply(&mut client, &["SET", "k", "5"], b"+OK\r\n");
+        assert_reply(&mut client, &["MULTI"], b"+OK\r\n");
+        assert_reply(&mut client, &["GET", "k"], b"+QUEUED\r\n");
+        assert_reply(&mut client, &["EXEC"], b"*1\r\n$1\r\n5\r\n");
+    }
+
+    #[test]
+    fn exec_runs_atomically() {
+        let (_server, port) = start_server(test_config("exec-atomic"));
+        let mut transaction = connect(port);
+        assert_reply(&mut transaction, &["MULTI"], b"+OK\r\n");
+        for _ in 0..500 {
+            send_command(&mut transaction, &["INCR", "n"]);
+        }
+        assert_received(&mut transaction, &b"+QUEUED\r\n".repeat(500));
+
+        // another client reading the counter while EXEC runs sees it before or after, never half done
+        let reader = thread::spawn(move || {
+            let mut stream = connect(port);
+            let mut seen = Vec::new();
+            while seen.last() != Some(&500) && seen.len() < 100_000 {
+                send_command(&mut stream, &["GET", "n"]);
+                let value = match resp_parser::decode(&mut stream).unwrap().0 {
+                    RedisType::BulkString(Some(value)) => arg_parse::parse::<u64>(&value).unwrap(),
+                    _ => 0,
+                };
+                seen.push(value);
+            }
+            seen
+        });
+        send_command(&mut transaction, &["EXEC"]);
+        let replies: Vec<u8> = (1..=500)
+            .flat_map(|i| format!(":{i}\r\n").into_bytes())
+            .collect();
+        assert_received(
+            &mut transaction,
+            &[b"*500\r\n".as_slice(), &replies].concat(),
+        );
+
+        let seen = reader.join().unwrap();
+        assert!(seen.iter().all(|value| *value == 0 || *value == 500));
+        assert_eq!(seen.last(), Some(&500));
+    }
+
+    #[test]
+    fn exec_aborts_after_a_command_fails_to_queue() {
+        let (_server, port) = start_server(test_config("execabort"));
+        let mut client = connect(port);
+        assert_reply(&mut client, &["MULTI"], b"+OK\r\n");
+        assert_reply(&mut client, &["SET", "k", "1"], b"+QUEUED\r\n");
+        assert_reply(
+            &mut client,
+            &["SET", "k"],
+            b"-ERR wrong number of arguments for 'set' command\r\n",
+        );
+        assert_reply(
+            &mut client,
+            &["NOSUCHCOMMAND"],
+            b"-ERR unknown command 'NOSUCHCOMMAND', with args beginning with: \r\n",
+        );
+        assert_reply(
+            &mut client,
+            &["EXEC"],
+            b"-EXECABORT Transaction discarded because of previous errors.\r\n",
+        );
+
+        // nothing that was queued ran, and the connection is out of MULTI
+        assert_reply(&mut client, &["GET", "k"], b"$-1\r\n");
+        assert_reply(&mut client, &["EXEC"], b"-ERR EXEC without MULTI\r\n");
+    }
+
+    #[test]
+    fn hello_3_switches_to_maps_and_pushes() {
+        let (_server, port) = start_server(test_config("hello"));
+        let mut resp3 = connect(port);
+        let mut resp2 = connect(port);
+        let mut publisher = connect(port);
+        assert_reply(
+            &mut resp3,
+            &["HELLO", "3"],
+            b"%7\r\n$6\r\nserver\r\n$5\r\nredis\r\n$7\r\nversion\r\n$5\r\n7.2.0\r\n\
+              $5\r\nproto\r\n:3\r\n$2\r\nid\r\n:1\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n\
+              $4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+        );
+
+        assert_reply(
+            &mut resp3,
+            &["SUBSCRIBE", "ch"],
+            b">3\r\n$9\r\nsubscribe\r\n$2\r\nch\r\n:1\r\n",
+        );
+        assert_reply(
+            &mut resp2,
+            &["SUBSCRIBE", "ch"],
+            b"*3\r\n$9\r\nsubscribe\r\n$2\r\nch\r\n:1\r\n",
+        );
+        assert_reply(&mut publisher, &["PUBLISH", "ch", "hi"], b":2\r\n");
+        assert_received(
+            &mut resp3,
+            b">3\r\n$7\r\nmessage\r\n$2\r\nch\r\n$2\r\nhi\r\n",
+        );
+        assert_received(
+            &mut resp2,
+            b"*3\r\n$7\r\nmessage\r\n$2\r\nch\r\n$2\r\nhi\r\n",
+        );
+
+        // RESP3 nulls, and HELLO 2 going back to the RESP2 shapes
+        send_command(&mut publisher, &["HELLO", "3"]);
+        let hello = resp_parser::decode(&mut publisher).unwrap().0;
+        assert!(matches!(hello, RedisType::Map(fields) if fields.len() == 7));
+        assert_reply(&mut publisher, &["GET", "missing"], b"_\r\n");
+        assert_reply(&mut publisher, &["HELLO", "2"], b"*14\r\n");
+    }
+
+    #[test]
+    fn requirepass_refuses_commands_until_auth() {
+        let (_server, port) = start_server(ServerConfig {
+            requirepass: Some("secret".to_owned()),
+            ..test_config("auth")
+        });
+        let mut client = connect(port);
+        assert_reply(
+            &mut client,
+            &["GET", "k"],
+            b"-NOAUTH Authentication required.\r\n",
+        );
+        assert_reply(
+            &mut client,
+            &["AUTH", "wrong"],
+            b"-WRONGPASS invalid username-password pair or user is disabled.\r\n",
+        );
+        assert_reply(
+            &mut client,
+            &["SET", "k", "1"],
+            b"-NOAUTH Authentication required.\r\n",
+        );
+
+        assert_reply(&mut client, &["AUTH", "secret"], b"+OK\r\n");
+        assert_reply(&mut client, &["GET", "k"], b"$-1\r\n");
+        // the default user can be named too
+        let mut named = connect(port);
+        assert_reply(&mut named, &["AUTH", "default", "secret"], b"+OK\r\n");
+        assert_reply(&mut named, &["PING"], b"+PONG\r\n");
+    }
 }
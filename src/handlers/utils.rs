@@ -1,16 +1,22 @@
 use std::io::Write;
 
-use crate::utils::resp_parser::RedisType;
+use crate::{error::RedisResult, utils::resp_parser::RedisType};
 
-pub fn send(stream: &mut impl Write, message: String) {
-    stream.write(message.as_bytes()).unwrap();
+pub fn send(stream: &mut impl Write, message: &[u8]) -> RedisResult<()> {
+    stream.write_all(message)?;
+    Ok(())
 }
 
-/// converts a Vec\<String\> to RedisType::Array\<RedisType::BulkString\>
-pub fn convert_to_redis_bulk_string_array(strings: Vec<&str>) -> RedisType {
-    let mut bulk_string_command: Vec<RedisType> = Vec::new();
+/// converts a Vec of strings or byte strings to RedisType::Array\<RedisType::BulkString\>
+pub fn convert_to_redis_bulk_string_array<T: AsRef<[u8]>>(strings: Vec<T>) -> RedisType {
+    RedisType::Array(convert_to_redis_bulk_strings(strings))
+}
+
+/// converts a Vec of strings or byte strings to Vec\<RedisType::BulkString\>
+pub fn convert_to_redis_bulk_strings<T: AsRef<[u8]>>(strings: Vec<T>) -> Vec<RedisType> {
+    let mut bulk_strings: Vec<RedisType> = Vec::new();
     for part in strings {
-        bulk_string_command.push(RedisType::BulkString(Some(part.to_owned())));
+        bulk_strings.push(RedisType::BulkString(Some(part.as_ref().to_vec())));
     }
-    return RedisType::Array(bulk_string_command);
+    bulk_strings
 }
@@ -1,3 +1,4 @@
+mod error;
 #[macro_use]
 mod handlers;
 mod macros;
@@ -11,25 +12,40 @@ use std::{
     thread,
 };
 
-use handlers::{client_handler, replication_handler, server_handler};
-use structs::server::Server;
+use handlers::{
+    client_handler, expiry_handler, persistence_handler, replication_handler, server_handler,
+};
+use structs::{
+    pub_sub,
+    server::{Server, ServerConfig},
+};
 use utils::arg_parse;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let port = arg_parse::get_u64("--port", &args).unwrap_or(6379);
     let replica_args_option = arg_parse::get_string("--replicaof", &args);
-    let dir = arg_parse::get_string("--dir", &args);
-    let dbfilename = arg_parse::get_string("--dbfilename", &args);
+    let masterauth = arg_parse::get_string("--masterauth", &args);
+    let keyspace_events_result = pub_sub::parse_keyspace_events(
+        arg_parse::get_string("--notify-keyspace-events", &args).map_or("", |s| s.as_str()),
+    );
+    if keyspace_events_result.is_err() {
+        println!("{}", keyspace_events_result.err().unwrap());
+        return;
+    }
 
     let mut master_replid: Option<String> = None;
     let mut master_repl_offset: Option<u64> = None;
     let mut host_stream: Option<TcpStream> = None;
-    if replica_args_option.is_some() {
-        let replica_args = replica_args_option.as_ref().unwrap();
+    let mut master_rdb: Option<Vec<u8>> = None;
+    if let Some(replica_args) = replica_args_option {
         let result = client_handler::replicate_server(
-            &replica_args.split(' ').map(|s| s.to_owned()).collect(),
+            &replica_args
+                .split(' ')
+                .map(|s| s.to_owned())
+                .collect::<Vec<String>>(),
             port,
+            masterauth,
         );
         if result.is_err() {
             println!("{}", result.err().unwrap());
@@ -39,15 +55,71 @@ fn main() {
         master_replid = Some(master_info.0);
         master_repl_offset = Some(master_info.1);
         host_stream = Some(master_info.2);
+        master_rdb = Some(master_info.3);
     }
 
-    let server = Arc::new(Server::new(
-        replica_args_option,
+    let server_result = Server::new(ServerConfig {
+        replicaof: replica_args_option.cloned(),
         master_replid,
         master_repl_offset,
-        dir,
-        dbfilename,
-    ));
+        dir: arg_parse::get_string("--dir", &args).cloned(),
+        dbfilename: arg_parse::get_string("--dbfilename", &args).cloned(),
+        keyspace_events: keyspace_events_result.unwrap(),
+        requirepass: arg_parse::get_string("--requirepass", &args).cloned(),
+        masterauth: masterauth.cloned(),
+        aclfile: arg_parse::get_string("--aclfile", &args).cloned(),
+        save: arg_parse::get_string("--save", &args).cloned(),
+        rdbcompression: arg_parse::get_string("--rdbcompression", &args).cloned(),
+        rdbchecksum: arg_parse::get_string("--rdbchecksum", &args).cloned(),
+        appendonly: arg_parse::get_string("--appendonly", &args).cloned(),
+        appendfilename: arg_parse::get_string("--appendfilename", &args).cloned(),
+        appenddirname: arg_parse::get_string("--appenddirname", &args).cloned(),
+        appendfsync: arg_parse::get_string("--appendfsync", &args).cloned(),
+        aof_use_rdb_preamble: arg_parse::get_string("--aof-use-rdb-preamble", &args).cloned(),
+        auto_aof_rewrite_percentage: arg_parse::get_string("--auto-aof-rewrite-percentage", &args)
+            .cloned(),
+        auto_aof_rewrite_min_size: arg_parse::get_string("--auto-aof-rewrite-min-size", &args)
+            .cloned(),
+        repl_diskless_sync: arg_parse::get_string("--repl-diskless-sync", &args).cloned(),
+    });
+    if server_result.is_err() {
+        // a bad ACL file or option, refusing to start beats running with the wrong configuration
+        println!("{}", server_result.err().unwrap());
+        std::process::exit(1);
+    }
+    let server = Arc::new(server_result.unwrap());
+
+    // a corrupt RDB file or AOF, refusing to start beats running with part of the data
+    let load_result = persistence_handler::load(&server);
+    if load_result.is_err() {
+        println!("{}", load_result.err().unwrap());
+        std::process::exit(1);
+    }
+    if let Some(master_rdb) = master_rdb {
+        let load_result = client_handler::load_master_rdb(&server, &master_rdb);
+        if load_result.is_err() {
+            println!(
+                "couldn't load the master's snapshot: {}",
+                load_result.err().unwrap()
+            );
+            std::process::exit(1);
+        }
+    }
+
+    {
+        let server = Arc::clone(&server);
+        thread::spawn(move || expiry_handler::expiry_loop(server));
+    }
+
+    {
+        let server = Arc::clone(&server);
+        thread::spawn(move || persistence_handler::save_loop(server));
+    }
+
+    {
+        let server = Arc::clone(&server);
+        thread::spawn(move || persistence_handler::aof_fsync_loop(server));
+    }
 
     if host_stream.is_some() {
         let server = Arc::clone(&server);
@@ -61,7 +133,15 @@ fn main() {
         });
     }
 
-    let listener = TcpListener::bind(format!("127.0.0.1:{port}")).unwrap();
+    let listener_result = TcpListener::bind(format!("127.0.0.1:{port}"));
+    if listener_result.is_err() {
+        println!(
+            "couldn't listen on port {port}: {}",
+            listener_result.err().unwrap()
+        );
+        return;
+    }
+    let listener = listener_result.unwrap();
 
     for stream_result in listener.incoming() {
         match stream_result {
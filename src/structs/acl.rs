@@ -0,0 +1,795 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    sync::{Mutex, RwLock},
+    time::SystemTime,
+};
+
+use crate::{
+    error::{RedisError, RedisResult},
+    handlers::command_table::{self, CommandSpec, ACL_CATEGORIES, SUBCOMMAND_ACL_CATEGORIES},
+    utils::{arg_parse, glob::glob_match, sha256::sha256_hex},
+};
+
+// same as redis' default acllog-max-len
+const LOG_MAX_LENGTH: usize = 128;
+
+/// A `~pattern` rule, `%R~` and `%W~` only grant one of reading or writing.
+#[derive(Clone)]
+struct KeyPattern {
+    pattern: String,
+    read: bool,
+    write: bool,
+}
+
+impl KeyPattern {
+    fn describe(&self) -> String {
+        match (self.read, self.write) {
+            (true, true) => format!("~{}", self.pattern),
+            (true, false) => format!("%R~{}", self.pattern),
+            _ => format!("%W~{}", self.pattern),
+        }
+    }
+}
+
+/// An ACL user. Command permissions are kept resolved so checking a command is a lookup, and
+/// `command_rules` remembers the rules that got them there for ACL LIST.
+#[derive(Clone)]
+pub struct User {
+    pub name: String,
+    enabled: bool,
+    nopass: bool,
+    passwords: Vec<String>, // sha256 hex digests, the plain text is never kept
+    commands: HashSet<&'static str>,
+    subcommands: HashMap<String, bool>, // `command|subcommand` rules that override their command
+    command_rules: Vec<String>,
+    keys: Vec<KeyPattern>,
+    channels: Vec<String>, // `*` means every channel, psubscribe patterns included
+}
+
+impl User {
+    /// a new user can't do anything until rules are added, like redis' `reset`
+    fn new(name: &str) -> User {
+        User {
+            name: name.to_owned(),
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            commands: HashSet::new(),
+            subcommands: HashMap::new(),
+            command_rules: vec!["-@all".to_owned()],
+            keys: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    fn default_user() -> User {
+        let mut user = User::new("default");
+        for rule in ["on", "nopass", "allkeys", "allchannels", "+@all"] {
+            user.apply_rule(rule).unwrap();
+        }
+        user
+    }
+
+    /// applies a single ACL SETUSER rule, the error is the reason redis gives for rejecting it
+    fn apply_rule(&mut self, rule: &str) -> Result<(), &'static str> {
+        let lowercase = rule.to_ascii_lowercase();
+        match lowercase.as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec![key_pattern("*", true, true)],
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.channels = vec!["*".to_owned()],
+            "resetchannels" => self.channels.clear(),
+            "allcommands" | "+@all" => self.set_all_commands(true),
+            "nocommands" | "-@all" => self.set_all_commands(false),
+            "reset" => {
+                for reset_rule in ["resetpass", "resetkeys", "resetchannels", "off", "-@all"] {
+                    self.apply_rule(reset_rule)?;
+                }
+            }
+            "sanitize-payload" | "skip-sanitize-payload" => {} // there's no RESTORE to sanitize for
+            _ if rule.starts_with('>') => {
+                let hash = sha256_hex(&rule.as_bytes()[1..]);
+                if !self.passwords.contains(&hash) {
+                    self.passwords.push(hash);
+                }
+                self.nopass = false;
+            }
+            _ if rule.starts_with('#') => {
+                let hash = &rule[1..];
+                if hash.len() != 64
+                    || !hash
+                        .bytes()
+                        .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+                {
+                    return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters");
+                }
+                if !self.passwords.iter().any(|password| password == hash) {
+                    self.passwords.push(hash.to_owned());
+                }
+                self.nopass = false;
+            }
+            _ if rule.starts_with('<') || rule.starts_with('!') => {
+                let hash = if let Some(password) = rule.strip_prefix('<') {
+                    sha256_hex(password.as_bytes())
+                } else {
+                    rule[1..].to_owned()
+                };
+                let position = self.passwords.iter().position(|password| *password == hash);
+                if position.is_none() {
+                    return Err(
+                        "The password you are trying to remove from the user does not exist",
+                    );
+                }
+                self.passwords.remove(position.unwrap());
+            }
+            _ if rule.starts_with('~') => self.keys.push(key_pattern(&rule[1..], true, true)),
+            _ if rule.starts_with('%') => {
+                let separator = rule.find('~').ok_or("Syntax error")?;
+                let permissions = lowercase[1..separator].to_owned();
+                if permissions.is_empty()
+                    || permissions.bytes().any(|byte| byte != b'r' && byte != b'w')
+                {
+                    return Err("Syntax error");
+                }
+                self.keys.push(key_pattern(
+                    &rule[separator + 1..],
+                    permissions.contains('r'),
+                    permissions.contains('w'),
+                ));
+            }
+            _ if rule.starts_with('&') => {
+                if !self.channels.iter().any(|channel| channel == &rule[1..]) {
+                    self.channels.push(rule[1..].to_owned());
+                }
+            }
+            _ if rule.starts_with("+@") || rule.starts_with("-@") => {
+                let category = &lowercase[2..];
+                if !ACL_CATEGORIES.contains(&category) {
+                    return Err("Unknown command or category name in ACL");
+                }
+                self.set_category(category, rule.starts_with('+'));
+                self.command_rules.push(lowercase.clone());
+            }
+            _ if rule.starts_with('+') || rule.starts_with('-') => {
+                let allow = rule.starts_with('+');
+                let (command, subcommand) = match lowercase[1..].split_once('|') {
+                    Some((command, subcommand)) => (command, Some(subcommand)),
+                    None => (&lowercase[1..], None),
+                };
+                let spec_option = command_table::lookup(command);
+                if spec_option.is_none() || subcommand == Some("") {
+                    return Err("Unknown command or category name in ACL");
+                }
+                if subcommand.is_some() {
+                    if !command_table::CONTAINER_COMMANDS.contains(&command) {
+                        return Err("Unknown command or category name in ACL");
+                    }
+                    self.subcommands.insert(lowercase[1..].to_owned(), allow);
+                } else {
+                    self.set_command(spec_option.unwrap().name, allow);
+                }
+                self.command_rules.push(lowercase.clone());
+            }
+            _ => return Err("Syntax error"),
+        }
+        Ok(())
+    }
+
+    fn set_all_commands(&mut self, allow: bool) {
+        self.commands.clear();
+        self.subcommands.clear();
+        if allow {
+            self.commands
+                .extend(command_table::all().iter().map(|spec| spec.name));
+        }
+        self.command_rules = vec![if allow { "+@all" } else { "-@all" }.to_owned()];
+    }
+
+    fn set_command(&mut self, name: &'static str, allow: bool) {
+        if allow {
+            self.commands.insert(name);
+        } else {
+            self.commands.remove(name);
+        }
+        let prefix = format!("{name}|");
+        self.subcommands
+            .retain(|subcommand, _| !subcommand.starts_with(&prefix));
+    }
+
+    /// allowing or denying a command's category leaves its subcommands in other categories alone
+    fn set_category(&mut self, category: &str, allow: bool) {
+        for spec in command_table::all() {
+            if !spec.acl_categories().contains(&category) {
+                continue;
+            }
+            let prefix = format!("{}|", spec.name);
+            let unaffected: Vec<(String, bool)> = SUBCOMMAND_ACL_CATEGORIES
+                .iter()
+                .filter(|(subcommand, categories)| {
+                    subcommand.starts_with(&prefix) && !categories.contains(&category)
+                })
+                .map(|(subcommand, _)| {
+                    (subcommand.to_string(), self.subcommand_allowed(subcommand))
+                })
+                .collect();
+            self.set_command(spec.name, allow);
+            for (subcommand, allowed) in unaffected {
+                if allowed != allow {
+                    self.subcommands.insert(subcommand, allowed);
+                }
+            }
+        }
+        for (subcommand, categories) in SUBCOMMAND_ACL_CATEGORIES {
+            if categories.contains(&category) {
+                self.subcommands.insert(subcommand.to_string(), allow);
+            }
+        }
+    }
+
+    /// `name` is the full `command|subcommand` name
+    fn subcommand_allowed(&self, name: &str) -> bool {
+        if let Some(allowed) = self.subcommands.get(name) {
+            return *allowed;
+        }
+        let command = name.split('|').next().unwrap_or_default();
+        self.commands.contains(command)
+    }
+
+    fn check_password(&self, password: &[u8]) -> bool {
+        if self.nopass {
+            return true;
+        }
+        let hash = sha256_hex(password);
+        let mut valid = false;
+        for stored in &self.passwords {
+            valid |= constant_time_eq(stored, &hash); // no early exit, every hash gets compared
+        }
+        valid
+    }
+
+    /// why a command isn't allowed, or None if it is
+    fn check(&self, spec: &CommandSpec, arguments: &[Vec<u8>]) -> Option<Denial> {
+        // AUTH, HELLO and the like are what gets a connection to a user with permissions
+        let name = command_table::full_name(spec, arguments);
+        if !spec.has_flag("no_auth") && !self.subcommand_allowed(&name) {
+            return Some(Denial {
+                reason: "command",
+                object: name.clone(),
+                message: format!(
+                    "User {} has no permissions to run the '{name}' command",
+                    self.name
+                ),
+            });
+        }
+
+        let read = spec.has_flag("readonly");
+        let write = spec.has_flag("write");
+        for key in command_table::get_keys(spec, arguments) {
+            let allowed = self.keys.iter().any(|pattern| {
+                (!read || pattern.read)
+                    && (!write || pattern.write)
+                    && glob_match(pattern.pattern.as_bytes(), key)
+            });
+            if !allowed {
+                return Some(Denial {
+                    reason: "key",
+                    object: arg_parse::to_text(key),
+                    message: "No permissions to access a key".to_owned(),
+                });
+            }
+        }
+
+        for (channel, is_pattern) in channel_arguments(spec, arguments) {
+            // a pattern subscription has to be allowed as that exact pattern
+            let allowed = self.channels.iter().any(|allowed| {
+                allowed == "*"
+                    || (is_pattern && allowed.as_bytes() == channel.as_slice())
+                    || (!is_pattern && glob_match(allowed.as_bytes(), channel))
+            });
+            if !allowed {
+                return Some(Denial {
+                    reason: "channel",
+                    object: arg_parse::to_text(channel),
+                    message: "No permissions to access a channel".to_owned(),
+                });
+            }
+        }
+        None
+    }
+
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    pub fn passwords(&self) -> &Vec<String> {
+        &self.passwords
+    }
+
+    pub fn command_rules(&self) -> String {
+        self.command_rules.join(" ")
+    }
+
+    pub fn key_rules(&self) -> String {
+        let patterns: Vec<String> = self.keys.iter().map(|pattern| pattern.describe()).collect();
+        patterns.join(" ")
+    }
+
+    pub fn channel_rules(&self) -> String {
+        let patterns: Vec<String> = self
+            .channels
+            .iter()
+            .map(|channel| format!("&{channel}"))
+            .collect();
+        patterns.join(" ")
+    }
+
+    /// the user as the rules that would recreate it, the format of ACL LIST and the ACL file
+    pub fn describe(&self) -> String {
+        let mut parts = vec![format!("user {}", self.name)];
+        parts.push(if self.enabled { "on" } else { "off" }.to_owned());
+        if self.nopass {
+            parts.push("nopass".to_owned());
+        }
+        for password in &self.passwords {
+            parts.push(format!("#{password}"));
+        }
+        if !self.keys.is_empty() {
+            parts.push(self.key_rules());
+        }
+        if self.channels.is_empty() {
+            parts.push("resetchannels".to_owned());
+        } else {
+            parts.push(self.channel_rules());
+        }
+        parts.push(self.command_rules());
+        parts.join(" ")
+    }
+}
+
+fn key_pattern(pattern: &str, read: bool, write: bool) -> KeyPattern {
+    KeyPattern {
+        pattern: pattern.to_owned(),
+        read,
+        write,
+    }
+}
+
+/// the channels a pub/sub command touches, and whether each is a pattern
+fn channel_arguments<'a>(spec: &CommandSpec, arguments: &'a [Vec<u8>]) -> Vec<(&'a Vec<u8>, bool)> {
+    match spec.name {
+        "publish" | "spublish" => vec![(&arguments[1], false)],
+        "subscribe" | "ssubscribe" => arguments[1..]
+            .iter()
+            .map(|channel| (channel, false))
+            .collect(),
+        "psubscribe" => arguments[1..]
+            .iter()
+            .map(|pattern| (pattern, true))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// compares every byte so how long a wrong password takes to reject doesn't leak how much of it was right
+fn constant_time_eq(expected: &str, actual: &str) -> bool {
+    if expected.len() != actual.len() {
+        return false;
+    }
+    expected
+        .bytes()
+        .zip(actual.bytes())
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+/// Why ACL refused a command. `reason` and `object` are what ACL LOG shows.
+pub struct Denial {
+    pub reason: &'static str,
+    pub object: String,
+    pub message: String,
+}
+
+#[derive(Clone)]
+pub struct LogEntry {
+    pub count: u64,
+    pub reason: String,
+    pub context: String,
+    pub object: String,
+    pub username: String,
+    pub client_info: String,
+    pub entry_id: u64,
+    pub created: SystemTime,
+    pub updated: SystemTime,
+}
+
+struct Log {
+    entries: Vec<LogEntry>, // newest first
+    next_entry_id: u64,
+}
+
+/// Every user, plus the log of what they were refused.
+pub struct Acl {
+    users: RwLock<HashMap<String, User>>,
+    log: Mutex<Log>,
+    pub file: Option<String>,
+}
+
+impl Acl {
+    /// starts with just the default user, then loads the ACL file if there is one
+    pub fn new(file: Option<&String>, requirepass: Option<&String>) -> RedisResult<Acl> {
+        // loading the file would replace the default user and quietly drop the password
+        if file.is_some() && requirepass.is_some_and(|password| !password.is_empty()) {
+            return Err(RedisError::Generic(
+                "requirepass can't be used with an ACL file, set the default user's password in the file instead".to_owned(),
+            ));
+        }
+        let acl = Acl {
+            users: RwLock::new(HashMap::from([(
+                "default".to_owned(),
+                User::default_user(),
+            )])),
+            log: Mutex::new(Log {
+                entries: Vec::new(),
+                next_entry_id: 0,
+            }),
+            file: file.cloned(),
+        };
+        acl.set_default_password(requirepass);
+        if acl.file.is_some() {
+            acl.load()?;
+        }
+        Ok(acl)
+    }
+
+    /// requirepass is a password for the default user, an empty one makes it `nopass` again
+    pub fn set_default_password(&self, requirepass: Option<&String>) {
+        let mut users = self.users.write().unwrap();
+        let user = users.get_mut("default").unwrap();
+        if requirepass.is_some_and(|password| !password.is_empty()) {
+            user.apply_rule("resetpass").unwrap();
+            user.apply_rule(&format!(">{}", requirepass.unwrap()))
+                .unwrap();
+        } else {
+            user.apply_rule("nopass").unwrap();
+        }
+    }
+
+    /// new connections are logged in as the default user unless it needs a password
+    pub fn requires_auth(&self) -> bool {
+        let users = self.users.read().unwrap();
+        let user = users.get("default").unwrap();
+        !(user.enabled && user.nopass)
+    }
+
+    pub fn authenticate(&self, username: &str, password: &[u8]) -> RedisResult<()> {
+        let users = self.users.read().unwrap();
+        let valid = users
+            .get(username)
+            .is_some_and(|user| user.enabled && user.check_password(password));
+        if !valid {
+            return Err(RedisError::WrongPass);
+        }
+        Ok(())
+    }
+
+    pub fn check(
+        &self,
+        username: &str,
+        spec: &CommandSpec,
+        arguments: &[Vec<u8>],
+    ) -> Option<Denial> {
+        let users = self.users.read().unwrap();
+        let user_option = users.get(username);
+        if user_option.is_none() {
+            // the user was deleted, its connections are on their way out
+            return Some(Denial {
+                reason: "command",
+                object: spec.name.to_owned(),
+                message: format!(
+                    "User {username} has no permissions to run the '{}' command",
+                    spec.name
+                ),
+            });
+        }
+        user_option.unwrap().check(spec, arguments)
+    }
+
+    /// applies every rule or none of them
+    pub fn set_user(&self, name: &str, rules: &[Vec<u8>]) -> RedisResult<()> {
+        let mut users = self.users.write().unwrap();
+        let mut user = users.get(name).cloned().unwrap_or_else(|| User::new(name));
+        for rule in rules {
+            // not lossy, two different passwords mustn't end up as the same one
+            let rule_text = std::str::from_utf8(rule).map_err(|_| "Syntax error");
+            rule_text
+                .and_then(|rule| user.apply_rule(rule))
+                .map_err(|reason| {
+                    RedisError::Generic(format!(
+                        "Error in ACL SETUSER modifier '{}': {reason}",
+                        arg_parse::to_text(rule)
+                    ))
+                })?;
+        }
+        users.insert(name.to_owned(), user);
+        Ok(())
+    }
+
+    /// the names of the users that existed and were removed
+    pub fn delete_users(&self, names: &[Vec<u8>]) -> RedisResult<Vec<String>> {
+        let names: Vec<String> = names.iter().map(|name| arg_parse::to_text(name)).collect();
+        if names.iter().any(|name| name == "default") {
+            return Err(RedisError::Generic(
+                "The 'default' user cannot be removed".to_owned(),
+            ));
+        }
+        let mut users = self.users.write().unwrap();
+        Ok(names
+            .into_iter()
+            .filter(|name| users.remove(name).is_some())
+            .collect())
+    }
+
+    pub fn get_user(&self, name: &str) -> Option<User> {
+        return self.users.read().unwrap().get(name).cloned();
+    }
+
+    /// every user, sorted by name
+    pub fn users(&self) -> Vec<User> {
+        let users = self.users.read().unwrap();
+        let mut result: Vec<User> = users.values().cloned().collect();
+        result.sort_by(|a, b| a.name.cmp(&b.name));
+        result
+    }
+
+    /// similar denials close together are counted as one entry, like redis
+    pub fn log_denial(
+        &self,
+        reason: &str,
+        context: &str,
+        object: &str,
+        username: &str,
+        client_info: String,
+    ) {
+        let now = SystemTime::now();
+        let mut log = self.log.lock().unwrap();
+        let existing = log.entries.iter().position(|entry| {
+            entry.reason == reason
+                && entry.context == context
+                && entry.object == object
+                && entry.username == username
+                && now
+                    .duration_since(entry.updated)
+                    .is_ok_and(|age| age.as_secs() < 60)
+        });
+        if let Some(existing) = existing {
+            let mut entry = log.entries.remove(existing);
+            entry.count += 1;
+            entry.updated = now;
+            entry.client_info = client_info;
+            log.entries.insert(0, entry);
+            return;
+        }
+
+        let entry_id = log.next_entry_id;
+        log.next_entry_id += 1;
+        log.entries.insert(
+            0,
+            LogEntry {
+                count: 1,
+                reason: reason.to_owned(),
+                context: context.to_owned(),
+                object: object.to_owned(),
+                username: username.to_owned(),
+                client_info,
+                entry_id,
+                created: now,
+                updated: now,
+            },
+        );
+        log.entries.truncate(LOG_MAX_LENGTH);
+    }
+
+    /// the newest `count` entries
+    pub fn log_entries(&self, count: usize) -> Vec<LogEntry> {
+        let log = self.log.lock().unwrap();
+        log.entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn reset_log(&self) {
+        self.log.lock().unwrap().entries.clear();
+    }
+
+    /// replaces every user with the ones in the ACL file, nothing changes if any line is bad
+    pub fn load(&self) -> RedisResult<()> {
+        let path = self.file.as_ref().ok_or_else(no_acl_file)?;
+        let contents = fs::read_to_string(path).map_err(|error| {
+            RedisError::Generic(format!(
+                "Error loading ACLs, opening file '{path}': {error}"
+            ))
+        })?;
+
+        let mut users: HashMap<String, User> = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line_error =
+                |reason: &str| RedisError::Generic(format!("{path}:{}: {reason}. ", i + 1));
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.is_empty() {
+                continue;
+            }
+            if parts[0] != "user" || parts.len() < 2 {
+                return Err(line_error("should start with user keyword"));
+            }
+            if users.contains_key(parts[1]) {
+                return Err(line_error(&format!("Duplicate user '{}' found", parts[1])));
+            }
+            let mut user = User::new(parts[1]);
+            for rule in &parts[2..] {
+                user.apply_rule(rule).map_err(&line_error)?;
+            }
+            users.insert(parts[1].to_owned(), user);
+        }
+        users
+            .entry("default".to_owned())
+            .or_insert_with(User::default_user);
+
+        *self.users.write().unwrap() = users;
+        Ok(())
+    }
+
+    /// writes every user to the ACL file, through a temporary file so a crash can't leave half of it
+    pub fn save(&self) -> RedisResult<()> {
+        let path = self.file.as_ref().ok_or_else(no_acl_file)?;
+        let mut contents = String::new();
+        for user in self.users() {
+            contents += &user.describe();
+            contents.push('\n');
+        }
+        let temporary_path = format!("{path}.tmp-{}", std::process::id());
+        fs::write(&temporary_path, contents)?;
+        fs::rename(&temporary_path, path)?;
+        Ok(())
+    }
+}
+
+fn no_acl_file() -> RedisError {
+    RedisError::Generic("This Redis instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command and then issue a CONFIG REWRITE (assuming you have a Redis configuration file set) in order to store users in the Redis configuration.".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<Vec<u8>> {
+        rules.iter().map(|rule| rule.as_bytes().to_vec()).collect()
+    }
+
+    fn arguments(arguments: &[&str]) -> Vec<Vec<u8>> {
+        rules(arguments)
+    }
+
+    /// the reason ACL LOG would record, or None if the command is allowed
+    fn denial_reason(acl: &Acl, username: &str, command: &[&str]) -> Option<&'static str> {
+        let arguments = arguments(command);
+        let spec = command_table::lookup(command[0]).unwrap();
+        acl.check(username, spec, &arguments)
+            .map(|denial| denial.reason)
+    }
+
+    #[test]
+    fn requirepass_and_an_acl_file_refuse_to_start() {
+        let path = std::env::temp_dir().join(format!("acl-{}.acl", std::process::id()));
+        fs::write(&path, "user alice on >pw ~* +@all\n").unwrap();
+        let file = path.to_string_lossy().to_string();
+
+        let result = Acl::new(Some(&file), Some(&"secret".to_owned()));
+        assert!(result.is_err());
+        // without requirepass the file is loaded as usual
+        let acl = Acl::new(Some(&file), None).unwrap();
+        assert!(acl.get_user("alice").is_some());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn shard_channels_are_checked_against_channel_rules_only() {
+        let acl = Acl::new(None, None).unwrap();
+        acl.set_user(
+            "u",
+            &rules(&[
+                "on",
+                ">pw",
+                "~cache:*",
+                "+@read",
+                "+spublish",
+                "+ssubscribe",
+                "&chan",
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(denial_reason(&acl, "u", &["spublish", "chan", "m"]), None);
+        assert_eq!(denial_reason(&acl, "u", &["ssubscribe", "chan"]), None);
+        assert_eq!(
+            denial_reason(&acl, "u", &["spublish", "other", "m"]),
+            Some("channel")
+        );
+        assert_eq!(
+            denial_reason(&acl, "u", &["ssubscribe", "chan", "cache:1"]),
+            Some("channel")
+        );
+    }
+
+    #[test]
+    fn key_rules_limit_which_keys_and_how() {
+        let acl = Acl::new(None, None).unwrap();
+        acl.set_user(
+            "u",
+            &rules(&["on", ">pw", "~cache:*", "%R~ro:*", "%W~wo:*", "+@all"]),
+        )
+        .unwrap();
+
+        assert_eq!(denial_reason(&acl, "u", &["get", "cache:1"]), None);
+        assert_eq!(denial_reason(&acl, "u", &["set", "cache:1", "v"]), None);
+        assert_eq!(denial_reason(&acl, "u", &["get", "other"]), Some("key"));
+        assert_eq!(denial_reason(&acl, "u", &["get", "ro:1"]), None);
+        assert_eq!(denial_reason(&acl, "u", &["set", "ro:1", "v"]), Some("key"));
+        assert_eq!(denial_reason(&acl, "u", &["set", "wo:1", "v"]), None);
+        assert_eq!(denial_reason(&acl, "u", &["get", "wo:1"]), Some("key"));
+
+        acl.set_user("u", &rules(&["resetkeys", "allkeys", "-set"]))
+            .unwrap();
+        assert_eq!(denial_reason(&acl, "u", &["get", "other"]), None);
+        assert_eq!(
+            denial_reason(&acl, "u", &["set", "other", "v"]),
+            Some("command")
+        );
+    }
+
+    #[test]
+    fn channel_rules_match_channels_and_exact_patterns() {
+        let acl = Acl::new(None, None).unwrap();
+        acl.set_user("u", &rules(&["on", ">pw", "+@all", "&news.*"]))
+            .unwrap();
+
+        assert_eq!(
+            denial_reason(&acl, "u", &["publish", "news.sport", "m"]),
+            None
+        );
+        assert_eq!(denial_reason(&acl, "u", &["subscribe", "news.sport"]), None);
+        assert_eq!(
+            denial_reason(&acl, "u", &["publish", "chat", "m"]),
+            Some("channel")
+        );
+        assert_eq!(
+            denial_reason(&acl, "u", &["subscribe", "news.sport", "chat"]),
+            Some("channel")
+        );
+        // a pattern subscription has to be granted as that same pattern
+        assert_eq!(denial_reason(&acl, "u", &["psubscribe", "news.*"]), None);
+        assert_eq!(
+            denial_reason(&acl, "u", &["psubscribe", "news.s*"]),
+            Some("channel")
+        );
+
+        acl.set_user("u", &rules(&["resetchannels"])).unwrap();
+        assert_eq!(
+            denial_reason(&acl, "u", &["publish", "news.sport", "m"]),
+            Some("channel")
+        );
+        acl.set_user("u", &rules(&["allchannels"])).unwrap();
+        assert_eq!(denial_reason(&acl, "u", &["psubscribe", "*"]), None);
+    }
+}
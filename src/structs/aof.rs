@@ -0,0 +1,390 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    sync::{Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    error::{RedisError, RedisResult},
+    structs::server::ServerConfig,
+    utils::{
+        aof::{self, AofFile, AofFileType, Manifest},
+        arg_parse,
+    },
+};
+
+/// how long a failed automatic rewrite waits before trying again
+const REWRITE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// appendfsync, how much of the AOF a crash of the whole machine can lose
+#[derive(Clone, Copy, PartialEq)]
+pub enum AppendFsync {
+    Always,   // every write is on disk before its reply goes out
+    EverySec, // at most a second of writes
+    No,       // whenever the OS gets to it
+}
+
+impl AppendFsync {
+    pub fn parse(value: &str) -> Option<AppendFsync> {
+        match value.to_ascii_lowercase().as_str() {
+            "always" => Some(AppendFsync::Always),
+            "everysec" => Some(AppendFsync::EverySec),
+            "no" => Some(AppendFsync::No),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::EverySec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+/// a rewrite that has switched to a new incremental file and still has to write its base
+pub struct AofRewrite {
+    pub base_path: String,
+    base: AofFile,
+    first_kept_seq: u64, // incremental files from this one on have writes the base doesn't
+}
+
+struct AofState {
+    enabled: bool, // appendonly, the file is only open once it has been loaded or created
+    file: Option<File>, // the last incremental file, writes go straight to it
+    unsynced: bool, // written to since the last fsync
+    last_write_ok: bool,
+    manifest: Manifest,
+    size: u64,      // every file in the manifest together
+    base_size: u64, // the size right after the last rewrite or load, growth is measured from it
+    rewrite_in_progress: bool,
+    last_rewrite_ok: bool,
+    last_rewrite_attempt: SystemTime,
+}
+
+/// the append only file, every write command in the same RESP form replicas receive
+pub struct Aof {
+    state: Mutex<AofState>,
+    pub fsync: RwLock<AppendFsync>,
+    pub filename: String,
+    pub dirname: String,
+    directory: String, // dirname inside the server's dir
+    pub use_rdb_preamble: RwLock<bool>,
+    pub auto_rewrite_percentage: RwLock<u64>, // 0 turns automatic rewrites off
+    pub auto_rewrite_min_size: RwLock<u64>,
+}
+
+impl Aof {
+    /// appendonly and the other AOF options, checked before anything is opened
+    pub fn new(dir: &str, config: &ServerConfig) -> RedisResult<Aof> {
+        let fsync = config
+            .appendfsync
+            .as_ref()
+            .map_or(Some(AppendFsync::EverySec), |value| {
+                AppendFsync::parse(value)
+            })
+            .ok_or(RedisError::Generic(
+                "argument 'appendfsync' must be one of 'always', 'everysec' or 'no'".to_owned(),
+            ))?;
+        let auto_rewrite_percentage = config
+            .auto_aof_rewrite_percentage
+            .as_ref()
+            .map_or(Some(100), |value| str::parse::<u64>(value).ok());
+        let auto_rewrite_min_size = config
+            .auto_aof_rewrite_min_size
+            .as_ref()
+            .map_or(Some(64 * 1024 * 1024), |value| {
+                arg_parse::parse_memory(value)
+            });
+        if auto_rewrite_percentage.is_none() || auto_rewrite_min_size.is_none() {
+            return Err(RedisError::Generic(
+                "invalid auto-aof-rewrite-percentage or auto-aof-rewrite-min-size".to_owned(),
+            ));
+        }
+        let enabled = arg_parse::config_yes_no("appendonly", config.appendonly.as_ref(), false)?;
+        let use_rdb_preamble = arg_parse::config_yes_no(
+            "aof-use-rdb-preamble",
+            config.aof_use_rdb_preamble.as_ref(),
+            true,
+        )?;
+        let dirname = config
+            .appenddirname
+            .clone()
+            .unwrap_or("appendonlydir".to_owned());
+        let filename = config
+            .appendfilename
+            .clone()
+            .unwrap_or("appendonly.aof".to_owned());
+
+        Ok(Aof {
+            state: Mutex::new(AofState {
+                enabled,
+                file: None,
+                unsynced: false,
+                last_write_ok: true,
+                manifest: Manifest::new(),
+                size: 0,
+                base_size: 0,
+                rewrite_in_progress: false,
+                last_rewrite_ok: true,
+                last_rewrite_attempt: SystemTime::UNIX_EPOCH,
+            }),
+            fsync: RwLock::new(fsync),
+            filename,
+            directory: dir.to_owned() + "/" + &dirname,
+            dirname,
+            use_rdb_preamble: RwLock::new(use_rdb_preamble),
+            auto_rewrite_percentage: RwLock::new(auto_rewrite_percentage.unwrap()),
+            auto_rewrite_min_size: RwLock::new(auto_rewrite_min_size.unwrap()),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        return self.state.lock().unwrap().enabled;
+    }
+
+    pub fn last_write_ok(&self) -> bool {
+        return self.state.lock().unwrap().last_write_ok;
+    }
+
+    pub fn rewrite_in_progress(&self) -> bool {
+        return self.state.lock().unwrap().rewrite_in_progress;
+    }
+
+    pub fn last_rewrite_ok(&self) -> bool {
+        return self.state.lock().unwrap().last_rewrite_ok;
+    }
+
+    /// (current size, size after the last rewrite)
+    pub fn sizes(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.size, state.base_size)
+    }
+
+    pub fn file_path(&self, name: &str) -> String {
+        self.directory.clone() + "/" + name
+    }
+
+    pub fn manifest_path(&self) -> String {
+        self.file_path(&format!("{}.manifest", self.filename))
+    }
+
+    /// the AOF from before there were manifests, a single file directly in the server's dir
+    pub fn single_file_path(&self, dir: &str) -> String {
+        dir.to_owned() + "/" + &self.filename
+    }
+
+    /// moves a single file AOF into the directory as the base of a new manifest, it keeps its name
+    pub fn upgrade_single_file(&self, dir: &str) -> RedisResult<Manifest> {
+        fs::create_dir_all(&self.directory)?;
+        fs::rename(self.single_file_path(dir), self.file_path(&self.filename))?;
+        let mut manifest = Manifest::new();
+        manifest.base = Some(AofFile {
+            name: self.filename.clone(),
+            seq: 1,
+            file_type: AofFileType::Base,
+        });
+        aof::write_manifest(&self.manifest_path(), &manifest)?;
+        Ok(manifest)
+    }
+
+    /// starts appending to the manifest's last incremental file, a new one if it has none
+    pub fn open(&self, manifest: Manifest) -> RedisResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.manifest = manifest;
+        if state.manifest.incrementals.is_empty() {
+            self.open_incremental(&mut state)?;
+        } else {
+            let last = state.manifest.incrementals.last().unwrap();
+            let path = self.file_path(&last.name);
+            state.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        }
+        state.enabled = true;
+        state.unsynced = false;
+        state.size = self.manifest_size(&state.manifest);
+        state.base_size = state.size;
+        Ok(())
+    }
+
+    /// stops appending, what was written so far is synced first
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(file) = state.file.as_ref() {
+            let _ = file.sync_data();
+        }
+        state.enabled = false;
+        state.file = None;
+        state.unsynced = false;
+    }
+
+    /// appendonly turned on at runtime, the rewrite that follows creates the files
+    pub fn enable(&self) {
+        self.state.lock().unwrap().enabled = true;
+    }
+
+    /// appends an encoded command, callers hold `master_repl_mutex` so the order matches replicas
+    pub fn append(&self, command_bytes: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if state.file.is_none() {
+            return;
+        }
+        let file = state.file.as_mut().unwrap();
+        let always = *self.fsync.read().unwrap() == AppendFsync::Always;
+        let mut result = file.write_all(command_bytes);
+        if result.is_ok() && always {
+            result = file.sync_data();
+        }
+        state.last_write_ok = result.is_ok();
+        state.unsynced = !always;
+        state.size += command_bytes.len() as u64;
+        if result.is_err() {
+            println!("error writing to the AOF: {}", result.err().unwrap());
+        }
+    }
+
+    /// the everysec fsync, done on a duplicate handle so writers aren't held up behind the disk
+    pub fn fsync(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.file.is_none() || !state.unsynced {
+            return;
+        }
+        let file_result = state.file.as_ref().unwrap().try_clone();
+        state.unsynced = false;
+        drop(state);
+
+        let result = file_result.and_then(|file| file.sync_data());
+        if result.is_err() {
+            println!("error syncing the AOF: {}", result.err().unwrap());
+            self.state.lock().unwrap().unsynced = true; // try again next time
+        }
+    }
+
+    /// whether the AOF has grown enough past its last rewrite for another one
+    pub fn should_rewrite(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        if state.file.is_none() || state.rewrite_in_progress {
+            return false;
+        }
+        if !state.last_rewrite_ok
+            && SystemTime::now()
+                .duration_since(state.last_rewrite_attempt)
+                .unwrap_or_default()
+                < REWRITE_RETRY_DELAY
+        {
+            return false;
+        }
+        let percentage = *self.auto_rewrite_percentage.read().unwrap();
+        if percentage == 0 || state.size < *self.auto_rewrite_min_size.read().unwrap() {
+            return false;
+        }
+        let base_size = state.base_size.max(1);
+        let growth = state.size.saturating_sub(base_size) * 100 / base_size;
+        growth >= percentage
+    }
+
+    /// the start of a rewrite, writes from here on go to a new incremental file so the base only
+    /// needs what came before. Callers hold `master_repl_mutex` and take the snapshot with it
+    pub fn start_rewrite(&self, rdb_preamble: bool) -> RedisResult<AofRewrite> {
+        let mut state = self.state.lock().unwrap();
+        if state.rewrite_in_progress {
+            return Err(RedisError::Generic(
+                "Background append only file rewriting already in progress".to_owned(),
+            ));
+        }
+        fs::create_dir_all(&self.directory)?;
+        if state.file.is_none() {
+            // nothing is being appended, the rewrite replaces whatever AOF is on disk
+            state.manifest = aof::read_manifest(&self.manifest_path())?.unwrap_or(Manifest::new());
+        }
+        let first_kept_seq = state.manifest.next_incremental_seq();
+        if state.enabled {
+            self.open_incremental(&mut state)?;
+        }
+
+        let seq = state.manifest.next_base_seq();
+        let extension = if rdb_preamble { "rdb" } else { "aof" };
+        let base = AofFile {
+            name: format!("{}.{seq}.base.{extension}", self.filename),
+            seq,
+            file_type: AofFileType::Base,
+        };
+        state.rewrite_in_progress = true;
+        state.last_rewrite_attempt = SystemTime::now();
+        Ok(AofRewrite {
+            base_path: self.file_path(&base.name),
+            base,
+            first_kept_seq,
+        })
+    }
+
+    /// swaps the new base in once it is on disk, the files it replaces are deleted
+    pub fn finish_rewrite(&self, rewrite: AofRewrite, ok: bool) -> RedisResult<()> {
+        let mut state = self.state.lock().unwrap();
+        state.rewrite_in_progress = false;
+        state.last_rewrite_ok = ok;
+        if !ok {
+            return Ok(()); // the manifest still lists the old base and every incremental file
+        }
+
+        let mut manifest = state.manifest.clone();
+        let replaced: Vec<AofFile> = manifest
+            .files()
+            .into_iter()
+            .filter(|file| file.file_type == AofFileType::Base || file.seq < rewrite.first_kept_seq)
+            .cloned()
+            .collect();
+        manifest.base = Some(rewrite.base);
+        manifest
+            .incrementals
+            .retain(|file| file.seq >= rewrite.first_kept_seq);
+        let result = aof::write_manifest(&self.manifest_path(), &manifest);
+        if result.is_err() {
+            state.last_rewrite_ok = false;
+            return result;
+        }
+        state.manifest = manifest;
+        for file in replaced {
+            let _ = fs::remove_file(self.file_path(&file.name));
+        }
+        state.size = self.manifest_size(&state.manifest);
+        state.base_size = state.size;
+        Ok(())
+    }
+
+    /// starts the next incremental file and records it in the manifest before anything goes in it
+    fn open_incremental(&self, state: &mut AofState) -> RedisResult<()> {
+        let seq = state.manifest.next_incremental_seq();
+        let incremental = AofFile {
+            name: format!("{}.{seq}.incr.aof", self.filename),
+            seq,
+            file_type: AofFileType::Incremental,
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.file_path(&incremental.name))?;
+        let mut manifest = state.manifest.clone();
+        manifest.incrementals.push(incremental);
+        aof::write_manifest(&self.manifest_path(), &manifest)?;
+
+        if let Some(file) = state.file.as_ref() {
+            let _ = file.sync_data();
+        }
+        state.manifest = manifest;
+        state.file = Some(file);
+        state.unsynced = false;
+        Ok(())
+    }
+
+    fn manifest_size(&self, manifest: &Manifest) -> u64 {
+        manifest
+            .files()
+            .iter()
+            .map(|file| {
+                fs::metadata(self.file_path(&file.name)).map_or(0, |metadata| metadata.len())
+            })
+            .sum()
+    }
+}
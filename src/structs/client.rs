@@ -0,0 +1,362 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Condvar, Mutex, RwLock},
+    time::SystemTime,
+};
+
+use bytes::BytesMut;
+
+use crate::{
+    error::{RedisError, RedisResult},
+    utils::resp_parser::{self, PushMessage, RedisType},
+};
+
+// past this much buffered output a pipeline's replies get written out early
+const OUTPUT_FLUSH_SIZE: usize = 64 * 1024;
+
+// a client that lets this much pushed output pile up gets disconnected, redis' default
+// client-output-buffer-limit for pub/sub clients
+const PUSH_OUTPUT_LIMIT: usize = 32 * 1024 * 1024;
+
+/// The buffered side of a client's socket. Replies and pushes are encoded into the buffer and the
+/// connection's writer thread writes them out, so nothing that sends to a client ever blocks on its
+/// socket. While the connection is working through a pipeline replies pile up and go out together
+/// once the pipeline runs dry.
+struct Output {
+    stream: TcpStream, // only for shutting it down, the writer thread has its own handle
+    buffer: BytesMut,
+    batching: bool,
+    ready: bool,      // the buffer should be written out
+    in_flight: usize, // taken by the writer thread and not written yet
+    closed: bool,     // nothing more gets written, the writer exits once the buffer is empty
+}
+
+impl Output {
+    fn flush(&mut self) {
+        self.ready = true;
+    }
+
+    fn written(&mut self) {
+        if self.closed {
+            self.buffer.clear(); // the writer is gone or going, nobody will read this
+        } else if !self.batching || self.buffer.len() >= OUTPUT_FLUSH_SIZE {
+            self.flush();
+        }
+    }
+}
+
+/// every connected client by id
+pub type ClientRegistry = RwLock<HashMap<u64, Arc<Client>>>;
+
+/// What CLIENT TRACKING turned on for a connection.
+#[derive(Default)]
+pub struct TrackingState {
+    pub enabled: bool,
+    pub bcast: bool,
+    pub optin: bool,
+    pub optout: bool,
+    pub redirect: Option<u64>, // the client invalidations are sent to instead of this one
+    pub broken_redirect: bool, // the redirect client went away
+    pub prefixes: Vec<Vec<u8>>, // BCAST prefixes, none means every key
+    pub caching: Option<bool>, // CLIENT CACHING for the next command in OPTIN/OPTOUT mode
+}
+
+/// CLIENT REPLY, `SkipNext` turns into `Skip` when the next command starts
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReplyMode {
+    On,
+    Off,
+    SkipNext,
+    Skip,
+}
+
+/// A connected client. Replies from the connection's own thread and messages pushed to it from
+/// other threads (pub/sub) both go through the same writer so they never interleave.
+pub struct Client {
+    pub id: u64,
+    pub addr: String,  // the peer's ip:port
+    pub laddr: String, // our side of the connection
+    pub created: SystemTime,
+    output: Mutex<Output>,
+    output_changed: Condvar, // wakes the writer thread, and anything waiting for it to catch up
+    pub(crate) channels: RwLock<HashSet<Vec<u8>>>,
+    pub(crate) patterns: RwLock<HashSet<Vec<u8>>>,
+    pub(crate) shard_channels: RwLock<HashSet<Vec<u8>>>,
+    pub(crate) protover: RwLock<u8>, // RESP version picked with HELLO, connections start on 2
+    pub(crate) name: RwLock<Option<String>>,
+    pub(crate) tracking: RwLock<TrackingState>,
+    pub(crate) user: RwLock<String>,
+    pub(crate) authenticated: RwLock<bool>,
+    pub(crate) last_interaction: RwLock<SystemTime>,
+    pub(crate) last_command: RwLock<String>, // for CLIENT LIST, subcommands show up as `client|list`
+    pub(crate) multi: RwLock<Option<usize>>, // how many commands are queued while in a transaction
+    pub(crate) no_evict: RwLock<bool>,
+    pub(crate) reply_mode: RwLock<ReplyMode>,
+}
+
+impl Client {
+    pub fn new(id: u64, stream: &TcpStream) -> std::io::Result<Client> {
+        Ok(Client {
+            id,
+            addr: stream.peer_addr()?.to_string(),
+            laddr: stream.local_addr()?.to_string(),
+            created: SystemTime::now(),
+            output: Mutex::new(Output {
+                stream: stream.try_clone()?,
+                buffer: BytesMut::with_capacity(4096),
+                batching: false,
+                ready: false,
+                in_flight: 0,
+                closed: false,
+            }),
+            output_changed: Condvar::new(),
+            channels: RwLock::new(HashSet::new()),
+            patterns: RwLock::new(HashSet::new()),
+            shard_channels: RwLock::new(HashSet::new()),
+            protover: RwLock::new(2),
+            name: RwLock::new(None),
+            tracking: RwLock::new(TrackingState::default()),
+            user: RwLock::new("default".to_owned()),
+            authenticated: RwLock::new(false),
+            last_interaction: RwLock::new(SystemTime::now()),
+            last_command: RwLock::new("NULL".to_owned()),
+            multi: RwLock::new(None),
+            no_evict: RwLock::new(false),
+            reply_mode: RwLock::new(ReplyMode::On),
+        })
+    }
+
+    /// called before each command runs, for CLIENT LIST and CLIENT REPLY SKIP
+    pub fn start_command(&self, command: &str) {
+        *self.last_interaction.write().unwrap() = SystemTime::now();
+        *self.last_command.write().unwrap() = command.to_owned();
+        let mut reply_mode = self.reply_mode.write().unwrap();
+        *reply_mode = match *reply_mode {
+            ReplyMode::SkipNext => ReplyMode::Skip,
+            ReplyMode::Skip => ReplyMode::On,
+            mode => mode,
+        };
+    }
+
+    /// closes the connection, its own thread notices and cleans up. A client killing itself still
+    /// gets the reply, so only the read side is shut then.
+    pub fn kill(&self, close_after_reply: bool) {
+        let output = self.output.lock().unwrap();
+        let how = if close_after_reply {
+            Shutdown::Read
+        } else {
+            Shutdown::Both
+        };
+        let _ = output.stream.shutdown(how);
+    }
+
+    /// the connection's writer thread, everything sent to the client is written from here. Returns
+    /// once the connection is closed and what was queued before that has been written.
+    pub fn write_output(&self, mut stream: TcpStream) {
+        let mut output = self.output.lock().unwrap();
+        loop {
+            if output.ready && !output.buffer.is_empty() {
+                let chunk = output.buffer.split();
+                output.ready = false;
+                output.in_flight = chunk.len();
+                drop(output); // never hold the lock while the socket could block
+                let result = stream.write_all(&chunk);
+                output = self.output.lock().unwrap();
+                output.in_flight = 0;
+                self.output_changed.notify_all();
+                if result.is_err() {
+                    // a dead socket gets cleaned up by the connection's thread
+                    output.closed = true;
+                    output.buffer.clear();
+                    return;
+                }
+                continue;
+            }
+            if output.closed {
+                return;
+            }
+            output = self.output_changed.wait(output).unwrap();
+        }
+    }
+
+    /// the connection is done, the writer thread exits after writing what's already queued
+    pub fn close_output(&self) {
+        let mut output = self.output.lock().unwrap();
+        output.closed = true;
+        output.flush();
+        self.output_changed.notify_all();
+    }
+
+    /// blocks until everything sent so far is written, for when the socket is about to be used directly
+    pub fn wait_written(&self) {
+        let mut output = self.output.lock().unwrap();
+        output.flush();
+        self.output_changed.notify_all();
+        while !output.buffer.is_empty() || output.in_flight > 0 {
+            output = self.output_changed.wait(output).unwrap();
+        }
+    }
+
+    /// for replies that are already encoded
+    pub fn send(&self, message: &[u8]) {
+        let mut output = self.output.lock().unwrap();
+        output.buffer.extend_from_slice(message);
+        self.written(&mut output);
+    }
+
+    /// holds replies back while there are more pipelined commands to run, turning it off flushes them
+    pub fn set_batching(&self, batching: bool) {
+        let mut output = self.output.lock().unwrap();
+        output.batching = batching;
+        if !batching {
+            output.flush();
+            self.output_changed.notify_all();
+        }
+    }
+
+    /// sends a reply in whichever protocol version this client speaks
+    pub fn reply(&self, data: RedisType) {
+        if !self.replies_enabled() {
+            return;
+        }
+        let data = resp_parser::for_protocol(data, self.protover());
+        let mut output = self.output.lock().unwrap();
+        resp_parser::encode_into(&data, &mut output.buffer);
+        self.written(&mut output);
+    }
+
+    /// sends an out of band message, as a push frame if the client speaks RESP3. These come from
+    /// other connections, so a client that doesn't read them fast enough is disconnected instead
+    /// of letting them pile up.
+    pub fn push(&self, message: &PushMessage) {
+        let message = if self.protover() >= 3 {
+            &message.resp3
+        } else {
+            &message.resp2
+        };
+        let mut output = self.output.lock().unwrap();
+        output.buffer.extend_from_slice(message);
+        if output.buffer.len() + output.in_flight > PUSH_OUTPUT_LIMIT {
+            output.closed = true;
+            let _ = output.stream.shutdown(Shutdown::Both);
+        }
+        self.written(&mut output);
+    }
+
+    pub fn reply_status(&self, status: &str) {
+        if !self.replies_enabled() {
+            return;
+        }
+        let mut output = self.output.lock().unwrap();
+        resp_parser::put_simple_string(&mut output.buffer, status);
+        self.written(&mut output);
+    }
+
+    pub fn reply_error(&self, error: &str) {
+        if !self.replies_enabled() {
+            return;
+        }
+        let mut output = self.output.lock().unwrap();
+        resp_parser::put_simple_error(&mut output.buffer, error);
+        self.written(&mut output);
+    }
+
+    fn written(&self, output: &mut Output) {
+        output.written();
+        self.output_changed.notify_all(); // the writer thread picks it up if it's ready
+    }
+
+    /// CLIENT REPLY OFF and SKIP drop replies, pushes still get through
+    fn replies_enabled(&self) -> bool {
+        return *self.reply_mode.read().unwrap() == ReplyMode::On;
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        return *self.authenticated.read().unwrap();
+    }
+
+    pub fn protover(&self) -> u8 {
+        return *self.protover.read().unwrap();
+    }
+
+    /// an empty name clears it, like redis
+    pub fn set_name(&self, name: &[u8]) -> RedisResult<()> {
+        if name.iter().any(|byte| *byte <= b' ' || *byte > b'~') {
+            return Err(RedisError::Generic(
+                "Client names cannot contain spaces, newlines or special characters.".to_owned(),
+            ));
+        }
+        let mut client_name = self.name.write().unwrap();
+        *client_name = if name.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(name).into_owned()) // only printable ascii got this far
+        };
+        Ok(())
+    }
+
+    pub fn subscription_count(&self) -> usize {
+        let channels = self.channels.read().unwrap();
+        let patterns = self.patterns.read().unwrap();
+        channels.len() + patterns.len()
+    }
+
+    pub fn shard_subscription_count(&self) -> usize {
+        return self.shard_channels.read().unwrap().len();
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.subscription_count() > 0 || self.shard_subscription_count() > 0
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{io::Read, net::TcpListener, thread};
+
+    /// a client with its writer thread running, and the other end of its socket
+    pub(crate) fn connected_client(id: u64) -> (Arc<Client>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let client = Arc::new(Client::new(id, &stream).unwrap());
+        let writer_client = Arc::clone(&client);
+        thread::spawn(move || writer_client.write_output(stream));
+        (client, peer)
+    }
+
+    #[test]
+    fn replies_and_pushes_are_written_in_order() {
+        let (client, mut peer) = connected_client(1);
+        client.set_batching(true);
+        client.reply_status("OK");
+        client.push(&PushMessage::new(vec![RedisType::Integer(1)]));
+        client.set_batching(false);
+        client.reply_error("ERR x");
+        client.wait_written();
+
+        let expected = b"+OK\r\n*1\r\n:1\r\n-ERR x\r\n";
+        let mut received = vec![0; expected.len()];
+        peer.read_exact(&mut received).unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn a_client_that_stops_reading_pushes_is_disconnected() {
+        let (client, mut peer) = connected_client(1);
+        let message = PushMessage::new(vec![RedisType::BulkString(Some(vec![b'x'; 1 << 20]))]);
+        // none of these wait on the socket, even though nothing is reading it
+        for _ in 0..(PUSH_OUTPUT_LIMIT >> 20) + 8 {
+            client.push(&message);
+        }
+
+        let mut received = Vec::new();
+        peer.set_read_timeout(Some(std::time::Duration::from_secs(10)))
+            .unwrap();
+        peer.read_to_end(&mut received).unwrap(); // ends because the connection was closed
+        assert!(received.len() < PUSH_OUTPUT_LIMIT);
+    }
+}
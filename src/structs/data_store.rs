@@ -1,41 +1,87 @@
-use std::{collections::HashMap, sync::RwLock, time::SystemTime};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::SystemTime,
+};
 
-use crate::{structs::redis_stream::RedisStream, utils::resp_parser::RedisType};
+use crate::{
+    error::{RedisError, RedisResult},
+    structs::{
+        pub_sub::{PubSub, NOTIFY_EXPIRED},
+        redis_stream::{RedisStream, StreamEntry},
+        tracking::Tracking,
+    },
+    utils::{arg_parse, resp_parser::RedisType},
+};
 
+/// lists, sets, hashes and sorted sets only come from RDB files for now, there are no commands for them
 #[derive(Debug, Clone)]
 pub enum DataType {
-    String(String),
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    SortedSet(HashMap<Vec<u8>, f64>), // member to score
     Stream(RedisStream),
 }
 
 struct DataMaps {
-    data: HashMap<String, DataType>,
-    expire_times: HashMap<String, SystemTime>,
+    data: HashMap<Vec<u8>, DataType>,
+    expire_times: HashMap<Vec<u8>, SystemTime>,
+    versions: HashMap<Vec<u8>, u64>, // bumped on every modification so WATCH can detect changes
+    last_version: u64,
+    flush_version: u64, // version of the last flush, which counts as a modification of every key
+}
+
+impl DataMaps {
+    fn touch(&mut self, key: &[u8]) {
+        self.last_version += 1;
+        self.versions.insert(key.to_owned(), self.last_version);
+    }
+
+    fn version(&self, key: &[u8]) -> u64 {
+        let version = *self.versions.get(key).unwrap_or(&0);
+        version.max(self.flush_version)
+    }
+}
+
+/// every key, its value and expire time at one point, `version` is the store's version at that point
+/// a key, its value and when it expires, how snapshots and RDB files hold the keyspace
+pub type Entry = (Vec<u8>, DataType, Option<SystemTime>);
+
+pub struct Snapshot {
+    pub entries: Vec<Entry>,
+    pub version: u64,
 }
 
 pub struct DataStore {
     maps: RwLock<DataMaps>,
+    transaction_lock: RwLock<()>, // commands hold it shared, EXEC holds it exclusively to run atomically
+    pub_sub: Arc<PubSub>,
+    tracking: Arc<Tracking>, // told about every modification so cached copies get invalidated
 }
 
 impl DataStore {
-    pub fn new() -> DataStore {
-        return DataStore {
+    pub fn new(pub_sub: Arc<PubSub>, tracking: Arc<Tracking>) -> DataStore {
+        DataStore {
             maps: RwLock::new(DataMaps {
                 data: HashMap::new(),
                 expire_times: HashMap::new(),
+                versions: HashMap::new(),
+                last_version: 0,
+                flush_version: 0,
             }),
-        };
+            transaction_lock: RwLock::new(()),
+            pub_sub,
+            tracking,
+        }
     }
 
-    pub fn get(&self, key: &String) -> Option<DataType> {
-        let expired: bool;
-
+    pub fn get(&self, key: &[u8]) -> Option<DataType> {
         let maps = self.maps.read().unwrap();
-        if maps.expire_times.contains_key(key)
-            && SystemTime::now().gt(maps.expire_times.get(key).unwrap())
-        {
-            expired = true;
-        } else {
+        let expired = maps.expire_times.contains_key(key)
+            && SystemTime::now().gt(maps.expire_times.get(key).unwrap());
+        if !expired {
             return maps.data.get(key).cloned();
         }
         drop(maps);
@@ -49,37 +95,124 @@ impl DataStore {
             {
                 maps.expire_times.remove(key);
                 maps.data.remove(key);
+                maps.touch(key);
+                drop(maps);
+                self.tracking.invalidate(key);
+                self.pub_sub
+                    .notify_keyspace_event(NOTIFY_EXPIRED, "expired", key);
             }
-            drop(maps);
         }
-        return None;
+        None
+    }
+
+    /// active expiry, removes every key whose expire time has passed instead of waiting for it to be read
+    pub fn remove_expired_keys(&self) {
+        let now = SystemTime::now();
+        let maps = self.maps.read().unwrap();
+        let any_expired = maps.expire_times.values().any(|time| now.gt(time));
+        drop(maps);
+        if !any_expired {
+            return;
+        }
+
+        let mut maps = self.maps.write().unwrap();
+        let expired_keys: Vec<Vec<u8>> = maps
+            .expire_times
+            .iter()
+            .filter(|(_, time)| now.gt(*time))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired_keys {
+            maps.expire_times.remove(key);
+            maps.data.remove(key);
+            maps.touch(key);
+        }
+        drop(maps);
+
+        for key in &expired_keys {
+            self.tracking.invalidate(key);
+            self.pub_sub
+                .notify_keyspace_event(NOTIFY_EXPIRED, "expired", key);
+        }
     }
 
-    pub fn insert(&self, key: &String, value: DataType, expire_time: Option<SystemTime>) {
+    pub fn insert(&self, key: &[u8], value: DataType, expire_time: Option<SystemTime>) {
         let mut maps = self.maps.write().unwrap();
         maps.data.insert(key.to_owned(), value);
-        if expire_time.is_some() {
-            maps.expire_times
-                .insert(key.to_owned(), expire_time.unwrap());
+        maps.touch(key);
+        if let Some(expire_time) = expire_time {
+            maps.expire_times.insert(key.to_owned(), expire_time);
         } else {
             maps.expire_times.remove(key);
         }
         drop(maps);
+        self.tracking.invalidate(key);
     }
 
-    pub fn keys(&self) -> Vec<String> {
+    pub fn keys(&self) -> Vec<Vec<u8>> {
         let maps = self.maps.read().unwrap();
-        let mut key_refs: Vec<&String> = maps.data.keys().collect();
+        let mut key_refs: Vec<&Vec<u8>> = maps.data.keys().collect();
         let now = SystemTime::now();
         key_refs.retain(|key| {
             !maps.expire_times.contains_key(*key) || !now.gt(maps.expire_times.get(*key).unwrap())
         });
-        let keys: Vec<String> = key_refs
+        let keys: Vec<Vec<u8>> = key_refs
             .into_iter()
             .map(|key_ref| key_ref.to_owned())
             .collect();
         drop(maps);
-        return keys;
+        keys
+    }
+
+    /// a copy of every live key with its expire time, taken in one go so it is consistent. Writers only
+    /// wait for the copy, not for whatever is done with it.
+    pub fn snapshot(&self) -> Snapshot {
+        let maps = self.maps.read().unwrap();
+        let now = SystemTime::now();
+        let mut entries = Vec::with_capacity(maps.data.len());
+        for (key, value) in maps.data.iter() {
+            let expire_time = maps.expire_times.get(key).copied();
+            if expire_time.is_some_and(|time| now.gt(&time)) {
+                continue;
+            }
+            entries.push((key.clone(), value.clone(), expire_time));
+        }
+        Snapshot {
+            entries,
+            version: maps.last_version,
+        }
+    }
+
+    /// goes up by one for every modification, save points count changes with it
+    pub fn last_version(&self) -> u64 {
+        let maps = self.maps.read().unwrap();
+        maps.last_version
+    }
+
+    pub fn shared_lock(&self) -> RwLockReadGuard<'_, ()> {
+        self.transaction_lock.read().unwrap()
+    }
+
+    pub fn exclusive_lock(&self) -> RwLockWriteGuard<'_, ()> {
+        self.transaction_lock.write().unwrap()
+    }
+
+    /// the key's current version, expiring it first so a key that was already dead isn't treated as modified later
+    pub fn version(&self, key: &[u8]) -> u64 {
+        self.get(key);
+        let maps = self.maps.read().unwrap();
+        maps.version(key)
+    }
+
+    pub fn flush(&self) {
+        let mut maps = self.maps.write().unwrap();
+        maps.data.clear();
+        maps.expire_times.clear();
+        maps.versions.clear();
+        maps.last_version += 1;
+        maps.flush_version = maps.last_version;
+        drop(maps);
+        self.tracking.invalidate_all();
     }
 
     pub fn reserve(&self, size: usize) {
@@ -88,46 +221,101 @@ impl DataStore {
         maps.expire_times.reserve(size);
     }
 
-    pub fn xadd(&self, key: &String, id: &String, fields: &[String]) -> Result<String, String> {
+    pub fn xadd(&self, key: &[u8], id: &str, fields: &[Vec<u8>]) -> RedisResult<String> {
         let mut maps = self.maps.write().unwrap();
         if !maps.data.contains_key(key) {
             maps.data
-                .insert(key.clone(), DataType::Stream(RedisStream::new()));
+                .insert(key.to_owned(), DataType::Stream(RedisStream::new()));
             maps.expire_times.remove(key);
         }
 
-        let mut data = maps.data.get_mut(key).unwrap();
-        let value = match &mut data {
-            DataType::Stream(value) => Some(value),
-            _ => None,
-        }
-        .unwrap();
+        let value = match maps.data.get_mut(key) {
+            Some(DataType::Stream(value)) => value,
+            _ => return Err(RedisError::WrongType),
+        };
 
         let mut entry = Vec::new();
-        for i in (0..((fields.len() / 2) * 2) - 1).step_by(2) {
-            entry.push((fields[i].clone(), fields[i + 1].clone()));
+        for pair in fields.chunks_exact(2) {
+            entry.push((pair[0].clone(), pair[1].clone()));
         }
 
-        let result = value.insert(id.clone(), entry);
+        let result = value.insert(id.to_owned(), entry);
+        if result.is_ok() {
+            maps.touch(key);
+        }
         drop(maps);
-        return result;
+        if result.is_ok() {
+            self.tracking.invalidate(key);
+        }
+        result
     }
 
-    pub fn xrange(&self, key: &String, start: &String, end: &String) -> Vec<RedisType> {
-        let mut result: Vec<RedisType> = Vec::new();
+    pub fn xrange(&self, key: &[u8], start: &String, end: &String) -> RedisResult<Vec<RedisType>> {
         let maps = self.maps.read().unwrap();
-        if maps.data.contains_key(key) {
-            let value = match &maps.data.get(key).unwrap() {
-                DataType::Stream(value) => Some(value),
-                _ => None,
+        match maps.data.get(key) {
+            Some(DataType::Stream(value)) => {
+                Ok(convert_entries_to_vec(value.query_inclusive(start, end)))
             }
-            .unwrap();
-            result = convert_entries_to_vec(value.query_inclusive(start, end));
+            Some(_) => Err(RedisError::WrongType),
+            None => Ok(Vec::new()),
         }
-        return result;
     }
 
-    pub fn xreadids(&self, keys: &Vec<String>, ids: &mut Vec<String>) {
+    /// the XINFO STREAM reply, as map pairs
+    pub fn xinfo_stream(&self, key: &[u8]) -> RedisResult<Vec<(RedisType, RedisType)>> {
+        let maps = self.maps.read().unwrap();
+        let value = match maps.data.get(key) {
+            Some(DataType::Stream(value)) => value,
+            Some(_) => return Err(RedisError::WrongType),
+            None => return Err(RedisError::Generic("no such key".to_owned())),
+        };
+        let entries = value.query_inclusive(&"-".to_owned(), &"+".to_owned());
+        let first_id = entries
+            .first()
+            .map_or("0-0".to_owned(), |(id, _)| (*id).clone());
+        let first_entry =
+            convert_entries_to_vec(entries.first().into_iter().copied().collect()).pop();
+        let last_entry =
+            convert_entries_to_vec(entries.last().into_iter().copied().collect()).pop();
+
+        let field = |name: &str| RedisType::BulkString(Some(name.into()));
+        Ok(vec![
+            (field("length"), RedisType::Integer(value.len() as i64)),
+            (
+                field("last-generated-id"),
+                RedisType::BulkString(Some(value.last_id().into())),
+            ),
+            (
+                field("max-deleted-entry-id"),
+                field(&format!(
+                    "{}-{}",
+                    value.max_deleted_entry_id.0, value.max_deleted_entry_id.1
+                )),
+            ),
+            (
+                field("entries-added"),
+                RedisType::Integer(value.entries_added as i64),
+            ),
+            (
+                field("recorded-first-entry-id"),
+                RedisType::BulkString(Some(first_id.into())),
+            ),
+            (
+                field("groups"),
+                RedisType::Integer(value.groups.len() as i64),
+            ),
+            (
+                field("first-entry"),
+                first_entry.unwrap_or(RedisType::BulkString(None)),
+            ),
+            (
+                field("last-entry"),
+                last_entry.unwrap_or(RedisType::BulkString(None)),
+            ),
+        ])
+    }
+
+    pub fn xreadids(&self, keys: &[Vec<u8>], ids: &mut [String]) -> RedisResult<()> {
         if ids.iter().find(|id| *id == "$").is_some() {
             // maybe make this into it's own function 'xids' or something
             let maps = self.maps.read().unwrap();
@@ -135,67 +323,66 @@ impl DataStore {
                 if ids[i] != "$" {
                     continue;
                 }
-                if maps.data.contains_key(key) {
-                    let value = match &maps.data.get(key).unwrap() {
-                        DataType::Stream(value) => Some(value),
-                        _ => None,
-                    }
-                    .unwrap();
-                    ids[i] = value.last_id();
-                }
+                ids[i] = match maps.data.get(key) {
+                    Some(DataType::Stream(value)) => value.last_id(),
+                    Some(_) => return Err(RedisError::WrongType),
+                    None => "0-0".to_owned(),
+                };
             }
             drop(maps);
         }
+        Ok(())
     }
 
-    pub fn xread(&self, keys: &Vec<String>, ids: &Vec<String>) -> Vec<RedisType> {
+    pub fn xread(&self, keys: &[Vec<u8>], ids: &[String]) -> RedisResult<Vec<RedisType>> {
         let mut result: Vec<RedisType> = Vec::new();
         let maps = self.maps.read().unwrap();
         for (i, key) in keys.iter().enumerate() {
-            if maps.data.contains_key(key) {
-                let mut stream_result = Vec::new();
-                let value = match &maps.data.get(key).unwrap() {
-                    DataType::Stream(value) => Some(value),
-                    _ => None,
-                }
-                .unwrap();
-                let entries =
-                    convert_entries_to_vec(value.query_exclusive(&ids[i], &"+".to_owned()));
-                if !entries.is_empty() {
-                    stream_result.push(RedisType::BulkString(Some(key.clone())));
-                    stream_result.push(RedisType::Array(entries));
-                    result.push(RedisType::Array(stream_result));
-                }
+            let value = match maps.data.get(key) {
+                Some(DataType::Stream(value)) => value,
+                Some(_) => return Err(RedisError::WrongType),
+                None => continue,
+            };
+            let entries = convert_entries_to_vec(value.query_exclusive(&ids[i], &"+".to_owned()));
+            if !entries.is_empty() {
+                result.push(RedisType::Array(vec![
+                    RedisType::BulkString(Some(key.clone())),
+                    RedisType::Array(entries),
+                ]));
             }
         }
         drop(maps);
-        return result;
+        Ok(result)
     }
 
-    pub fn increment(&self, key: &String) -> Result<i64, ()> {
+    pub fn increment(&self, key: &[u8]) -> RedisResult<i64> {
         let string_value = match self.get(key) {
-            Some(DataType::String(value)) => Ok(value),
-            None => Ok("0".to_owned()),
-            _ => Err(()),
-        }?;
-
-        let integer_value_result = str::parse::<i64>(&string_value);
-        if integer_value_result.is_err() {
-            return Err(());
-        }
+            Some(DataType::String(value)) => value,
+            None => b"0".to_vec(),
+            _ => return Err(RedisError::WrongType),
+        };
 
-        let integer_value = integer_value_result.unwrap() + 1;
+        let integer_value = arg_parse::parse::<i64>(&string_value)
+            .ok_or(RedisError::NotInteger)?
+            .checked_add(1)
+            .ok_or(RedisError::Generic(
+                "increment or decrement would overflow".to_owned(),
+            ))?;
 
-        self.insert(key, DataType::String(format!("{}", integer_value)), None);
-        return Ok(integer_value);
+        self.insert(
+            key,
+            DataType::String(integer_value.to_string().into_bytes()),
+            None,
+        );
+        Ok(integer_value)
     }
 }
 
-fn convert_entries_to_vec(entries: Vec<(&String, &Vec<(String, String)>)>) -> Vec<RedisType> {
+fn convert_entries_to_vec(entries: Vec<(&String, &StreamEntry)>) -> Vec<RedisType> {
     let mut result = Vec::new();
     for (id, entry) in entries {
         let mut entry_vec: Vec<RedisType> = Vec::new();
-        entry_vec.push(RedisType::BulkString(Some(id.clone())));
+        entry_vec.push(RedisType::BulkString(Some(id.clone().into())));
 
         let mut fields_vec: Vec<RedisType> = Vec::new();
         for (key, value) in entry {
@@ -206,5 +393,58 @@ fn convert_entries_to_vec(entries: Vec<(&String, &Vec<(String, String)>)>) -> Ve
 
         result.push(RedisType::Array(entry_vec));
     }
-    return result;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handlers::utils::convert_to_redis_bulk_strings,
+        structs::{client::tests::connected_client, pub_sub::NOTIFY_KEYEVENT},
+        utils::resp_parser::PushMessage,
+    };
+    use std::{
+        io::Read,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    #[test]
+    fn expiry_does_not_wait_for_keyspace_subscribers_to_read() {
+        let pub_sub = Arc::new(PubSub::new(NOTIFY_KEYEVENT | NOTIFY_EXPIRED));
+        let tracking = Arc::new(Tracking::new(Arc::new(RwLock::new(HashMap::new()))));
+        let data_store = DataStore::new(Arc::clone(&pub_sub), tracking);
+        let (subscriber, mut peer) = connected_client(1);
+        pub_sub.subscribe(&subscriber, b"__keyevent@0__:expired");
+
+        // far more than the socket buffers hold, so writing them inline would block until the peer reads
+        let keys: Vec<Vec<u8>> = (0..8).map(|i| vec![b'a' + i; 1 << 20]).collect();
+        for key in &keys {
+            data_store.insert(key, DataType::String(Vec::new()), Some(UNIX_EPOCH));
+        }
+        data_store.remove_expired_keys();
+        assert!(data_store.get(&keys[0]).is_none());
+
+        // expiry walks a hash map, so the messages come in no particular order
+        let mut expected: Vec<_> = keys
+            .iter()
+            .map(|key| {
+                PushMessage::new(convert_to_redis_bulk_strings(vec![
+                    b"message".as_slice(),
+                    b"__keyevent@0__:expired",
+                    key,
+                ]))
+                .resp2
+            })
+            .collect();
+        peer.set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        while !expected.is_empty() {
+            let mut received = vec![0; expected[0].len()]; // every message is the same length
+            peer.read_exact(&mut received).unwrap();
+            let position = expected.iter().position(|message| *message == received);
+            assert!(position.is_some());
+            expected.remove(position.unwrap());
+        }
+    }
 }
@@ -1,4 +1,9 @@
+pub mod acl;
+pub mod aof;
+pub mod client;
 pub mod data_store;
+pub mod pub_sub;
 pub mod redis_stream;
 pub mod server;
+pub mod tracking;
 pub mod xread_subscription;
@@ -0,0 +1,365 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    error::{RedisError, RedisResult},
+    handlers::utils::convert_to_redis_bulk_strings,
+    structs::client::Client,
+    utils::{glob::glob_match, hash_slot::key_hash_slot, resp_parser::PushMessage},
+};
+
+// keyspace event classes, same bits and characters redis uses for notify-keyspace-events
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0; // K
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1; // E
+pub const NOTIFY_GENERIC: u32 = 1 << 2; // g
+pub const NOTIFY_STRING: u32 = 1 << 3; // $
+pub const NOTIFY_LIST: u32 = 1 << 4; // l
+pub const NOTIFY_SET: u32 = 1 << 5; // s
+pub const NOTIFY_HASH: u32 = 1 << 6; // h
+pub const NOTIFY_ZSET: u32 = 1 << 7; // z
+pub const NOTIFY_EXPIRED: u32 = 1 << 8; // x
+pub const NOTIFY_EVICTED: u32 = 1 << 9; // e
+pub const NOTIFY_STREAM: u32 = 1 << 10; // t
+pub const NOTIFY_KEY_MISS: u32 = 1 << 11; // m
+pub const NOTIFY_MODULE: u32 = 1 << 13; // d
+pub const NOTIFY_NEW: u32 = 1 << 14; // n
+pub const NOTIFY_ALL: u32 = NOTIFY_GENERIC
+    | NOTIFY_STRING
+    | NOTIFY_LIST
+    | NOTIFY_SET
+    | NOTIFY_HASH
+    | NOTIFY_ZSET
+    | NOTIFY_EXPIRED
+    | NOTIFY_EVICTED
+    | NOTIFY_STREAM
+    | NOTIFY_MODULE; // A
+
+const CLASS_CHARACTERS: [(char, u32); 10] = [
+    ('g', NOTIFY_GENERIC),
+    ('$', NOTIFY_STRING),
+    ('l', NOTIFY_LIST),
+    ('s', NOTIFY_SET),
+    ('h', NOTIFY_HASH),
+    ('z', NOTIFY_ZSET),
+    ('x', NOTIFY_EXPIRED),
+    ('e', NOTIFY_EVICTED),
+    ('t', NOTIFY_STREAM),
+    ('d', NOTIFY_MODULE),
+];
+
+pub fn parse_keyspace_events(classes: &str) -> RedisResult<u32> {
+    let mut flags = 0;
+    for class in classes.chars() {
+        flags |= match class {
+            'A' => NOTIFY_ALL,
+            'K' => NOTIFY_KEYSPACE,
+            'E' => NOTIFY_KEYEVENT,
+            'm' => NOTIFY_KEY_MISS,
+            'n' => NOTIFY_NEW,
+            _ => match CLASS_CHARACTERS.iter().find(|(c, _)| *c == class) {
+                Some((_, flag)) => *flag,
+                None => {
+                    return Err(RedisError::Generic("CONFIG SET failed (possibly related to argument 'notify-keyspace-events') - Invalid event class character. Use 'Ag$lshzxeKEtmdn'.".to_owned()))
+                }
+            },
+        };
+    }
+    Ok(flags)
+}
+
+pub fn keyspace_events_to_string(flags: u32) -> String {
+    let mut result = String::new();
+    if flags & NOTIFY_ALL == NOTIFY_ALL {
+        result.push('A');
+    } else {
+        for (class, flag) in CLASS_CHARACTERS {
+            if flags & flag != 0 {
+                result.push(class);
+            }
+        }
+    }
+    for (class, flag) in [
+        ('K', NOTIFY_KEYSPACE),
+        ('E', NOTIFY_KEYEVENT),
+        ('m', NOTIFY_KEY_MISS),
+        ('n', NOTIFY_NEW),
+    ] {
+        if flags & flag != 0 {
+            result.push(class);
+        }
+    }
+    result
+}
+
+type ChannelSubscribers = HashMap<Vec<u8>, Vec<Arc<Client>>>;
+
+pub struct PubSub {
+    channels: RwLock<ChannelSubscribers>,
+    patterns: RwLock<Vec<(Vec<u8>, Arc<Client>)>>,
+    shard_channels: RwLock<HashMap<u16, ChannelSubscribers>>, // keyed by hash slot
+
+    keyspace_events: RwLock<u32>,
+}
+
+impl PubSub {
+    pub fn new(keyspace_events: u32) -> PubSub {
+        PubSub {
+            channels: RwLock::new(HashMap::new()),
+            patterns: RwLock::new(Vec::new()),
+            shard_channels: RwLock::new(HashMap::new()),
+            keyspace_events: RwLock::new(keyspace_events),
+        }
+    }
+
+    /// returns false if the client was already subscribed to the channel
+    pub fn subscribe(&self, client: &Arc<Client>, channel: &[u8]) -> bool {
+        let mut client_channels = client.channels.write().unwrap();
+        if !client_channels.insert(channel.to_owned()) {
+            return false;
+        }
+        let mut channels = self.channels.write().unwrap();
+        channels
+            .entry(channel.to_owned())
+            .or_default()
+            .push(Arc::clone(client));
+        true
+    }
+
+    /// returns false if the client was already subscribed to the pattern
+    pub fn psubscribe(&self, client: &Arc<Client>, pattern: &[u8]) -> bool {
+        let mut client_patterns = client.patterns.write().unwrap();
+        if !client_patterns.insert(pattern.to_owned()) {
+            return false;
+        }
+        let mut patterns = self.patterns.write().unwrap();
+        patterns.push((pattern.to_owned(), Arc::clone(client)));
+        true
+    }
+
+    /// returns false if the client wasn't subscribed to the channel
+    pub fn unsubscribe(&self, client: &Arc<Client>, channel: &[u8]) -> bool {
+        let mut client_channels = client.channels.write().unwrap();
+        if !client_channels.remove(channel) {
+            return false;
+        }
+        let mut channels = self.channels.write().unwrap();
+        let subscribers = channels.get_mut(channel).unwrap();
+        subscribers.retain(|subscriber| subscriber.id != client.id);
+        if subscribers.is_empty() {
+            channels.remove(channel);
+        }
+        true
+    }
+
+    /// returns false if the client wasn't subscribed to the pattern
+    pub fn punsubscribe(&self, client: &Arc<Client>, pattern: &[u8]) -> bool {
+        let mut client_patterns = client.patterns.write().unwrap();
+        if !client_patterns.remove(pattern) {
+            return false;
+        }
+        let mut patterns = self.patterns.write().unwrap();
+        patterns.retain(|(subscribed_pattern, subscriber)| {
+            subscriber.id != client.id || subscribed_pattern != pattern
+        });
+        true
+    }
+
+    /// returns false if the client was already subscribed to the shard channel
+    pub fn ssubscribe(&self, client: &Arc<Client>, channel: &[u8]) -> bool {
+        let mut client_shard_channels = client.shard_channels.write().unwrap();
+        if !client_shard_channels.insert(channel.to_owned()) {
+            return false;
+        }
+        let mut shard_channels = self.shard_channels.write().unwrap();
+        shard_channels
+            .entry(key_hash_slot(channel))
+            .or_default()
+            .entry(channel.to_owned())
+            .or_default()
+            .push(Arc::clone(client));
+        true
+    }
+
+    /// returns false if the client wasn't subscribed to the shard channel
+    pub fn sunsubscribe(&self, client: &Arc<Client>, channel: &[u8]) -> bool {
+        let mut client_shard_channels = client.shard_channels.write().unwrap();
+        if !client_shard_channels.remove(channel) {
+            return false;
+        }
+        let mut shard_channels = self.shard_channels.write().unwrap();
+        remove_shard_subscriber(&mut shard_channels, channel, client.id);
+        true
+    }
+
+    /// active channels (ones with at least one subscriber), optionally filtered by a glob pattern
+    pub fn channels(&self, pattern: Option<&Vec<u8>>) -> Vec<Vec<u8>> {
+        let channels = self.channels.read().unwrap();
+        channels
+            .keys()
+            .filter(|channel| pattern.is_none() || glob_match(pattern.unwrap(), channel))
+            .cloned()
+            .collect()
+    }
+
+    pub fn numsub(&self, channel: &[u8]) -> usize {
+        let channels = self.channels.read().unwrap();
+        channels
+            .get(channel)
+            .map_or(0, |subscribers| subscribers.len())
+    }
+
+    /// active shard channels across every slot, optionally filtered by a glob pattern
+    pub fn shard_channels(&self, pattern: Option<&Vec<u8>>) -> Vec<Vec<u8>> {
+        let shard_channels = self.shard_channels.read().unwrap();
+        shard_channels
+            .values()
+            .flat_map(|channels| channels.keys())
+            .filter(|channel| pattern.is_none() || glob_match(pattern.unwrap(), channel))
+            .cloned()
+            .collect()
+    }
+
+    pub fn shard_numsub(&self, channel: &[u8]) -> usize {
+        let shard_channels = self.shard_channels.read().unwrap();
+        shard_channels
+            .get(&key_hash_slot(channel))
+            .and_then(|channels| channels.get(channel))
+            .map_or(0, |subscribers| subscribers.len())
+    }
+
+    /// number of unique patterns subscribed to across all clients
+    pub fn numpat(&self) -> usize {
+        let patterns = self.patterns.read().unwrap();
+        return patterns
+            .iter()
+            .map(|(pattern, _)| pattern)
+            .collect::<HashSet<&Vec<u8>>>()
+            .len();
+    }
+
+    /// drops every subscription the client holds, used when the connection goes away
+    pub fn remove_client(&self, client: &Arc<Client>) {
+        let mut client_channels = client.channels.write().unwrap();
+        let mut channels = self.channels.write().unwrap();
+        for channel in client_channels.drain() {
+            let subscribers = channels.get_mut(&channel).unwrap();
+            subscribers.retain(|subscriber| subscriber.id != client.id);
+            if subscribers.is_empty() {
+                channels.remove(&channel);
+            }
+        }
+        drop(channels);
+
+        let mut client_patterns = client.patterns.write().unwrap();
+        client_patterns.clear();
+        let mut patterns = self.patterns.write().unwrap();
+        patterns.retain(|(_, subscriber)| subscriber.id != client.id);
+        drop(patterns);
+
+        let mut client_shard_channels = client.shard_channels.write().unwrap();
+        let mut shard_channels = self.shard_channels.write().unwrap();
+        for channel in client_shard_channels.drain() {
+            remove_shard_subscriber(&mut shard_channels, &channel, client.id);
+        }
+    }
+
+    /// delivers the message to every matching channel and pattern subscriber, returning how many received it
+    pub fn publish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let mut receivers = 0;
+
+        let channels = self.channels.read().unwrap();
+        if let Some(subscribers) = channels.get(channel) {
+            let push = PushMessage::new(convert_to_redis_bulk_strings(vec![
+                b"message".as_slice(),
+                channel,
+                message,
+            ]));
+            for subscriber in subscribers {
+                subscriber.push(&push);
+                receivers += 1;
+            }
+        }
+        drop(channels);
+
+        let patterns = self.patterns.read().unwrap();
+        for (pattern, subscriber) in patterns.iter() {
+            if glob_match(pattern, channel) {
+                subscriber.push(&PushMessage::new(convert_to_redis_bulk_strings(vec![
+                    b"pmessage".as_slice(),
+                    pattern,
+                    channel,
+                    message,
+                ])));
+                receivers += 1;
+            }
+        }
+        drop(patterns);
+
+        receivers
+    }
+
+    /// delivers the message to the shard channel's subscribers only, patterns never match shard channels
+    pub fn spublish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let shard_channels = self.shard_channels.read().unwrap();
+        let subscribers_option = shard_channels
+            .get(&key_hash_slot(channel))
+            .and_then(|channels| channels.get(channel));
+        if subscribers_option.is_none() {
+            return 0;
+        }
+        let subscribers = subscribers_option.unwrap();
+        let push = PushMessage::new(convert_to_redis_bulk_strings(vec![
+            b"smessage".as_slice(),
+            channel,
+            message,
+        ]));
+        for subscriber in subscribers {
+            subscriber.push(&push);
+        }
+        subscribers.len()
+    }
+
+    pub fn keyspace_events(&self) -> u32 {
+        return *self.keyspace_events.read().unwrap();
+    }
+
+    pub fn set_keyspace_events(&self, flags: u32) {
+        let mut keyspace_events = self.keyspace_events.write().unwrap();
+        *keyspace_events = flags;
+    }
+
+    /// publishes `event` on `__keyspace@0__:<key>` and `key` on `__keyevent@0__:<event>`
+    /// when `class` is enabled in notify-keyspace-events
+    pub fn notify_keyspace_event(&self, class: u32, event: &str, key: &[u8]) {
+        let flags = self.keyspace_events();
+        if flags & class == 0 {
+            return;
+        }
+        if flags & NOTIFY_KEYSPACE != 0 {
+            let channel = [b"__keyspace@0__:".as_slice(), key].concat();
+            self.publish(&channel, event.as_bytes());
+        }
+        if flags & NOTIFY_KEYEVENT != 0 {
+            self.publish(format!("__keyevent@0__:{event}").as_bytes(), key);
+        }
+    }
+}
+
+fn remove_shard_subscriber(
+    shard_channels: &mut HashMap<u16, ChannelSubscribers>,
+    channel: &[u8],
+    client_id: u64,
+) {
+    let slot = key_hash_slot(channel);
+    let channels = shard_channels.get_mut(&slot).unwrap();
+    let subscribers = channels.get_mut(channel).unwrap();
+    subscribers.retain(|subscriber| subscriber.id != client_id);
+    if subscribers.is_empty() {
+        channels.remove(channel);
+    }
+    if channels.is_empty() {
+        shard_channels.remove(&slot);
+    }
+}
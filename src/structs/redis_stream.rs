@@ -1,34 +1,88 @@
 use std::{cmp::Ordering, collections::HashMap, time::SystemTime};
 
+use crate::error::{RedisError, RedisResult};
+
+/// a stream entry id as (milliseconds time, sequence number)
+pub type StreamId = (u64, u64);
+
+/// a message delivered to a consumer group but not acknowledged yet
+#[derive(Debug, Clone)]
+pub struct PendingEntry {
+    pub id: StreamId,
+    pub delivery_time: u64, // unix time in milliseconds
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Consumer {
+    pub name: Vec<u8>,
+    pub seen_time: u64,
+    pub active_time: u64,
+    pub pending: Vec<StreamId>, // the ids of its group's pending entries that belong to it
+}
+
+/// there are no commands for consumer groups yet, they are kept so loading and saving an RDB file
+/// doesn't lose them
+#[derive(Debug, Clone)]
+pub struct ConsumerGroup {
+    pub name: Vec<u8>,
+    pub last_id: StreamId,
+    pub entries_read: u64, // u64::MAX when it isn't known
+    pub pending: Vec<PendingEntry>,
+    pub consumers: Vec<Consumer>,
+}
+
+/// an entry's field value pairs, in the order they were added
+pub type StreamEntry = Vec<(Vec<u8>, Vec<u8>)>;
+
 #[derive(Debug, Clone)]
 pub struct RedisStream {
     last_milliseconds_time: u64,
     last_sequence_number: u64,
-    data: HashMap<String, Vec<(String, String)>>,
+    data: HashMap<String, StreamEntry>,
+    pub max_deleted_entry_id: StreamId,
+    pub entries_added: u64, // every entry ever added, including deleted ones
+    pub groups: Vec<ConsumerGroup>,
 }
 
 impl RedisStream {
     pub fn new() -> RedisStream {
-        return RedisStream {
+        RedisStream {
             last_milliseconds_time: 0,
             last_sequence_number: 0,
             data: HashMap::new(),
-        };
+            max_deleted_entry_id: (0, 0),
+            entries_added: 0,
+            groups: Vec::new(),
+        }
     }
 
     pub fn last_id(&self) -> String {
         let time = self.last_milliseconds_time;
         let sequence = self.last_sequence_number;
-        return format!("{time}-{sequence}");
+        format!("{time}-{sequence}")
+    }
+
+    /// the last id as (milliseconds time, sequence number), how RDB files store it
+    pub fn last_id_parts(&self) -> StreamId {
+        (self.last_milliseconds_time, self.last_sequence_number)
     }
 
-    pub fn insert(
-        &mut self,
-        mut id: String,
-        value: Vec<(String, String)>,
-    ) -> Result<String, String> {
+    /// the last id can be past the last entry when entries were deleted, RDB files remember it
+    pub fn set_last_id(&mut self, (time, sequence): StreamId) {
+        self.last_milliseconds_time = time;
+        self.last_sequence_number = sequence;
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn insert(&mut self, mut id: String, value: StreamEntry) -> RedisResult<String> {
         if id == "0-0" {
-            return Err("ERR The ID specified in XADD must be greater than 0-0".to_owned());
+            return Err(RedisError::Generic(
+                "The ID specified in XADD must be greater than 0-0".to_owned(),
+            ));
         }
 
         let mut generate_milliseconds_time = false;
@@ -41,14 +95,16 @@ impl RedisStream {
         } else {
             let parts: Vec<&str> = id.split('-').collect();
             if parts.len() != 2 {
-                return Err("ERR Id had the incorrect ammount of parts".to_owned());
+                return Err(RedisError::InvalidStreamId);
             }
 
-            milliseconds_time = str::parse::<u64>(parts[0]).unwrap();
+            milliseconds_time =
+                str::parse::<u64>(parts[0]).map_err(|_| RedisError::InvalidStreamId)?;
             if parts[1] == "*" {
                 generate_sequence_number = true;
             } else {
-                sequence_number = str::parse::<u64>(parts[1]).unwrap();
+                sequence_number =
+                    str::parse::<u64>(parts[1]).map_err(|_| RedisError::InvalidStreamId)?;
             }
         }
 
@@ -60,20 +116,20 @@ impl RedisStream {
         }
 
         if milliseconds_time < self.last_milliseconds_time {
-            return Err(
-                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+            return Err(RedisError::Generic(
+                "The ID specified in XADD is equal or smaller than the target stream top item"
                     .to_owned(),
-            );
+            ));
         }
 
         if milliseconds_time == self.last_milliseconds_time {
             if generate_sequence_number {
                 sequence_number = self.last_sequence_number + 1;
             } else if sequence_number <= self.last_sequence_number {
-                return Err(
-                    "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+                return Err(RedisError::Generic(
+                    "The ID specified in XADD is equal or smaller than the target stream top item"
                         .to_owned(),
-                );
+                ));
             }
         }
 
@@ -81,84 +137,92 @@ impl RedisStream {
         self.last_sequence_number = sequence_number;
         id = format!("{milliseconds_time}-{sequence_number}");
         self.data.insert(id.clone(), value);
+        self.entries_added += 1;
 
-        return Ok(id);
+        Ok(id)
     }
 
-    pub fn query_exclusive(
-        &self,
-        start: &String,
-        end: &String,
-    ) -> Vec<(&String, &Vec<(String, String)>)> {
+    pub fn query_exclusive(&self, start: &String, end: &String) -> Vec<(&String, &StreamEntry)> {
         let mut keys: Vec<&String> = self.data.keys().collect();
         keys.retain(|key| {
             if start != "-" {
-                match compare_ids(&key, &start) {
+                match compare_ids(key, &start) {
                     Ordering::Greater => {}
                     _ => return false,
                 }
             }
 
             if end != "+" {
-                match compare_ids(&key, &end) {
+                match compare_ids(key, &end) {
                     Ordering::Less => {}
                     _ => return false,
                 }
             }
 
-            return true;
+            true
         });
         keys.sort_unstable_by(compare_ids);
 
-        let mut result: Vec<(&String, &Vec<(String, String)>)> = Vec::new();
+        let mut result: Vec<(&String, &StreamEntry)> = Vec::new();
         for key in keys {
             result.push((key, self.data.get(key).unwrap()));
         }
 
-        return result;
+        result
     }
 
-    pub fn query_inclusive(
-        &self,
-        start: &String,
-        end: &String,
-    ) -> Vec<(&String, &Vec<(String, String)>)> {
+    pub fn query_inclusive(&self, start: &String, end: &String) -> Vec<(&String, &StreamEntry)> {
         let mut keys: Vec<&String> = self.data.keys().collect();
         keys.retain(|key| {
-            if start != "-" {
-                match compare_ids(&key, &start) {
-                    Ordering::Less => return false,
-                    _ => {}
-                }
+            if start != "-" && compare_ids(key, &start) == Ordering::Less {
+                return false;
             }
 
-            if end != "+" {
-                match compare_ids(&key, &end) {
-                    Ordering::Greater => return false,
-                    _ => {}
-                }
+            if end != "+" && compare_ids(key, &end) == Ordering::Greater {
+                return false;
             }
 
-            return true;
+            true
         });
         keys.sort_unstable_by(compare_ids);
 
-        let mut result: Vec<(&String, &Vec<(String, String)>)> = Vec::new();
+        let mut result: Vec<(&String, &StreamEntry)> = Vec::new();
         for key in keys {
             result.push((key, self.data.get(key).unwrap()));
         }
 
-        return result;
+        result
+    }
+}
+
+/// splits a full `<milliseconds>-<sequence>` id, the form every stored entry has
+pub fn parse_id(id: &str) -> RedisResult<StreamId> {
+    let (time, sequence) = id.split_once('-').ok_or(RedisError::InvalidStreamId)?;
+    Ok((
+        str::parse::<u64>(time).map_err(|_| RedisError::InvalidStreamId)?,
+        str::parse::<u64>(sequence).map_err(|_| RedisError::InvalidStreamId)?,
+    ))
+}
+
+/// checks an id given to XRANGE or XREAD, which can leave off the sequence number or be `-`/`+`
+pub fn validate_id(id: &str) -> RedisResult<()> {
+    if id == "-" || id == "+" {
+        return Ok(());
+    }
+    let parts: Vec<&str> = id.split('-').collect();
+    if parts.len() > 2 || parts.iter().any(|part| str::parse::<u64>(part).is_err()) {
+        return Err(RedisError::InvalidStreamId);
     }
+    Ok(())
 }
 
 fn compare_ids(a: &&String, b: &&String) -> Ordering {
     let a_parts: Vec<&str> = a.split('-').collect();
-    let a_time = str::parse::<u64>(a_parts[0]).unwrap();
+    let a_time = str::parse::<u64>(a_parts[0]).unwrap_or(0);
     let a_sequence = a_parts.get(1).and_then(|part| str::parse::<u64>(part).ok());
 
     let b_parts: Vec<&str> = b.split('-').collect();
-    let b_time = str::parse::<u64>(b_parts[0]).unwrap();
+    let b_time = str::parse::<u64>(b_parts[0]).unwrap_or(0);
     let b_sequence = b_parts.get(1).and_then(|part| str::parse::<u64>(part).ok());
 
     if a_time < b_time {
@@ -167,13 +231,13 @@ fn compare_ids(a: &&String, b: &&String) -> Ordering {
         return Ordering::Greater;
     }
 
-    if a_sequence.is_some() && b_sequence.is_some() {
-        if a_sequence.unwrap() < b_sequence.unwrap() {
+    if let (Some(a_sequence), Some(b_sequence)) = (a_sequence, b_sequence) {
+        if a_sequence < b_sequence {
             return Ordering::Less;
-        } else if a_sequence.unwrap() > b_sequence.unwrap() {
+        } else if a_sequence > b_sequence {
             return Ordering::Greater;
         }
     }
 
-    return Ordering::Equal;
+    Ordering::Equal
 }
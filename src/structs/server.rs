@@ -1,14 +1,88 @@
-use std::sync::{Mutex, RwLock};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
 
 use crate::{
+    error::{RedisError, RedisResult},
     handlers::replication_handler::Replication,
-    structs::{data_store::DataStore, xread_subscription::XreadSubscription},
+    structs::{
+        acl::Acl,
+        aof::Aof,
+        client::{Client, ClientRegistry},
+        data_store::{DataStore, Snapshot},
+        pub_sub::PubSub,
+        tracking::Tracking,
+        xread_subscription::XreadSubscription,
+    },
     utils::{
-        rdb,
-        resp_parser::{self, RedisType},
+        aof, arg_parse, rdb,
+        resp_parser::{self, PushMessage, RedisType},
     },
 };
 
+/// the redis version we report to clients, they use it to decide which features they can rely on
+pub const REDIS_VERSION: &str = "7.2.0";
+
+/// CLIENT PAUSE, `all` holds back every command instead of just writes
+pub struct ClientPause {
+    pub end: SystemTime,
+    pub all: bool,
+}
+
+/// What the server is started with, mostly the command line options. None leaves an option at
+/// redis' default.
+#[derive(Clone, Default)]
+pub struct ServerConfig {
+    pub replicaof: Option<String>, // "<host> <port>" of the master, None for a master
+    pub master_replid: Option<String>,
+    pub master_repl_offset: Option<u64>,
+    pub dir: Option<String>,
+    pub dbfilename: Option<String>,
+    pub keyspace_events: u32,
+    pub requirepass: Option<String>,
+    pub masterauth: Option<String>,
+    pub aclfile: Option<String>,
+    pub save: Option<String>,
+    pub rdbcompression: Option<String>,
+    pub rdbchecksum: Option<String>,
+    pub appendonly: Option<String>,
+    pub appendfilename: Option<String>,
+    pub appenddirname: Option<String>,
+    pub appendfsync: Option<String>,
+    pub aof_use_rdb_preamble: Option<String>,
+    pub auto_aof_rewrite_percentage: Option<String>,
+    pub auto_aof_rewrite_min_size: Option<String>,
+    pub repl_diskless_sync: Option<String>,
+}
+
+/// a `save <seconds> <changes>` point, a background save starts once both have been reached
+#[derive(Clone, Copy)]
+pub struct SavePoint {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// the default save points redis ships with
+pub const DEFAULT_SAVE_POINTS: &str = "3600 1 300 100 60 10000";
+
+/// how long a failed background save waits before save points can start another one
+const SAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// bookkeeping for SAVE and BGSAVE
+pub struct SaveState {
+    pub last_save: SystemTime, // the last successful save, LASTSAVE
+    pub saved_version: u64,    // the data store's version at that save
+    pub last_attempt: SystemTime,
+    pub last_save_ok: bool,
+    pub in_progress: bool,
+    pub scheduled: bool, // BGSAVE SCHEDULE asked for one while another was running
+}
+
 pub struct Server {
     pub role: String,
     pub replid: String,
@@ -18,47 +92,459 @@ pub struct Server {
     pub connected_replications: RwLock<Vec<Replication>>,
     pub dir: String,
     pub dbfilename: String,
+    pub requirepass: RwLock<Option<String>>, // the default user's password, kept for CONFIG GET
+    pub masterauth: Option<String>,          // what this replica authenticates to its master with
     pub xread_subscriptions: RwLock<Vec<XreadSubscription>>,
+    pub next_client_id: Mutex<u64>,
+    pub clients: Arc<ClientRegistry>,
+    pub monitors: RwLock<Vec<Arc<Client>>>, // clients that ran MONITOR
+    pub pause: RwLock<Option<ClientPause>>,
+    pub save_points: RwLock<Vec<SavePoint>>,
+    pub save_state: Mutex<SaveState>,
+    pub shutting_down: RwLock<bool>, // SHUTDOWN is waiting on background writers, no new ones start
+    pub rdb_compression: RwLock<bool>,
+    pub rdb_checksum: RwLock<bool>, // write and verify the CRC64 at the end of RDB files
+    pub repl_diskless_sync: RwLock<bool>, // stream full syncs to replicas that support it
+    pub aof: Aof,
+    pub acl: Acl,
+    pub pub_sub: Arc<PubSub>,
+    pub tracking: Arc<Tracking>,
     pub data_store: DataStore,
 }
 
 impl Server {
-    pub fn new(
-        role: Option<&String>,
-        master_replid: Option<String>,
-        master_repl_offset: Option<u64>,
-        dir: Option<&String>,
-        dbfilename: Option<&String>,
-    ) -> Server {
+    pub fn new(config: ServerConfig) -> RedisResult<Server> {
+        let dir = config.dir.as_ref().unwrap_or(&".".to_string()).to_owned();
+        let aof = Aof::new(&dir, &config)?;
+        let pub_sub = Arc::new(PubSub::new(config.keyspace_events));
+        let clients = Arc::new(RwLock::new(HashMap::new()));
+        let tracking = Arc::new(Tracking::new(Arc::clone(&clients)));
         let server = Server {
-            role: (if role.is_none() { "master" } else { "slave" }).to_owned(),
+            role: (if config.replicaof.is_none() {
+                "master"
+            } else {
+                "slave"
+            })
+            .to_owned(),
             replid: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_owned(), // TODO don't hardcode replid
-            master_replid: master_replid
+            master_replid: config
+                .master_replid
                 .unwrap_or("8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_owned()),
-            master_repl_offset: RwLock::new(master_repl_offset.unwrap_or(0)),
+            master_repl_offset: RwLock::new(config.master_repl_offset.unwrap_or(0)),
             master_repl_mutex: Mutex::new(()),
             connected_replications: RwLock::new(Vec::new()),
-            dir: dir.unwrap_or(&".".to_string()).to_owned(),
-            dbfilename: dbfilename.unwrap_or(&"empty.rdb".to_string()).to_owned(),
+            dir: dir.clone(),
+            dbfilename: config
+                .dbfilename
+                .as_ref()
+                .unwrap_or(&"dump.rdb".to_string())
+                .to_owned(),
+            requirepass: RwLock::new(
+                config
+                    .requirepass
+                    .as_ref()
+                    .filter(|password| !password.is_empty())
+                    .cloned(),
+            ),
+            masterauth: config.masterauth.clone(),
             xread_subscriptions: RwLock::new(Vec::new()),
-            data_store: DataStore::new(),
+            next_client_id: Mutex::new(1),
+            clients,
+            monitors: RwLock::new(Vec::new()),
+            pause: RwLock::new(None),
+            save_points: RwLock::new(parse_save_points(
+                config
+                    .save
+                    .as_ref()
+                    .map_or(DEFAULT_SAVE_POINTS, |save| save.as_str()),
+            )?),
+            save_state: Mutex::new(SaveState {
+                last_save: SystemTime::now(),
+                saved_version: 0,
+                last_attempt: SystemTime::now(),
+                last_save_ok: true,
+                in_progress: false,
+                scheduled: false,
+            }),
+            shutting_down: RwLock::new(false),
+            rdb_compression: RwLock::new(arg_parse::config_yes_no(
+                "rdbcompression",
+                config.rdbcompression.as_ref(),
+                true,
+            )?),
+            rdb_checksum: RwLock::new(arg_parse::config_yes_no(
+                "rdbchecksum",
+                config.rdbchecksum.as_ref(),
+                true,
+            )?),
+            repl_diskless_sync: RwLock::new(arg_parse::config_yes_no(
+                "repl-diskless-sync",
+                config.repl_diskless_sync.as_ref(),
+                true,
+            )?),
+            aof,
+            acl: Acl::new(config.aclfile.as_ref(), config.requirepass.as_ref())?,
+            pub_sub: Arc::clone(&pub_sub),
+            tracking: Arc::clone(&tracking),
+            data_store: DataStore::new(pub_sub, tracking),
         };
+        Ok(server)
+    }
+
+    pub fn rdb_path(&self) -> String {
+        self.dir.clone() + "/" + &self.dbfilename
+    }
+
+    /// appendonly turned on, the AOF starts out as a base of the keyspace as it is now. Nothing
+    /// else can be written until it is on disk, turning it on at runtime isn't meant to be common
+    pub fn start_aof(&self) -> RedisResult<()> {
+        let replication_lock = self.master_repl_mutex.lock().unwrap();
+        self.aof.enable();
+        let snapshot = self.data_store.snapshot();
+        let rdb_preamble = self.aof_base_uses_rdb(&snapshot);
+        let rewrite_result = self.aof.start_rewrite(rdb_preamble);
+        if rewrite_result.is_err() {
+            self.aof.close();
+            return Err(rewrite_result.err().unwrap());
+        }
+        let rewrite = rewrite_result.unwrap();
+        let result = self.save_aof_base(&rewrite.base_path, &snapshot, rdb_preamble);
+        self.aof.finish_rewrite(rewrite, result.is_ok())?;
+        drop(replication_lock);
+        result
+    }
+
+    /// BGREWRITEAOF, the snapshot is taken together with the switch to a new incremental file so
+    /// every write is either in the new base or after it, the base is written on another thread
+    pub fn background_rewrite_aof(self: &Arc<Self>) -> RedisResult<()> {
+        let replication_lock = self.master_repl_mutex.lock().unwrap();
+        if *self.shutting_down.read().unwrap() {
+            return Err(shutdown_in_progress());
+        }
+        let snapshot = self.data_store.snapshot();
+        let rdb_preamble = self.aof_base_uses_rdb(&snapshot);
+        let rewrite = self.aof.start_rewrite(rdb_preamble)?;
+        drop(replication_lock);
+
+        let server = Arc::clone(self);
+        thread::spawn(move || {
+            let mut result = server.save_aof_base(&rewrite.base_path, &snapshot, rdb_preamble);
+            result = server
+                .aof
+                .finish_rewrite(rewrite, result.is_ok())
+                .and(result);
+            if result.is_err() {
+                println!("background AOF rewrite error: {}", result.err().unwrap());
+            }
+        });
+        Ok(())
+    }
+
+    fn aof_base_uses_rdb(&self, snapshot: &Snapshot) -> bool {
+        return *self.aof.use_rdb_preamble.read().unwrap()
+            || !aof::can_write_as_commands(&snapshot.entries);
+    }
+
+    fn save_aof_base(
+        &self,
+        path: &String,
+        snapshot: &Snapshot,
+        rdb_preamble: bool,
+    ) -> RedisResult<()> {
+        let compression = *self.rdb_compression.read().unwrap();
+        let checksum = *self.rdb_checksum.read().unwrap();
+        aof::save_base(path, &snapshot.entries, rdb_preamble, compression, checksum)
+    }
+
+    /// SAVE, the caller waits until the dump is on disk
+    pub fn save(&self) -> RedisResult<()> {
+        let mut save_state = self.save_state.lock().unwrap();
+        if save_state.in_progress {
+            return Err(RedisError::Generic(
+                "Background save already in progress".to_owned(),
+            ));
+        }
+        save_state.last_attempt = SystemTime::now();
+        let snapshot = self.data_store.snapshot();
+        let compression = *self.rdb_compression.read().unwrap();
+        let checksum = *self.rdb_checksum.read().unwrap();
+        let result = rdb::save_rdb(&self.rdb_path(), &snapshot.entries, compression, checksum);
+        finish_save(&mut save_state, snapshot.version, result.is_ok());
+        result
+    }
+
+    /// BGSAVE, the snapshot is taken right away so the dump shows the keyspace as of now, writing
+    /// it out happens on another thread without holding anything clients need
+    pub fn background_save(self: &Arc<Self>) -> RedisResult<()> {
+        let mut save_state = self.save_state.lock().unwrap();
+        if save_state.in_progress {
+            return Err(RedisError::Generic(
+                "Background save already in progress".to_owned(),
+            ));
+        }
+        if *self.shutting_down.read().unwrap() {
+            return Err(shutdown_in_progress());
+        }
+        save_state.in_progress = true;
+        save_state.scheduled = false;
+        save_state.last_attempt = SystemTime::now();
+        drop(save_state);
+
+        let snapshot = self.data_store.snapshot();
+        let compression = *self.rdb_compression.read().unwrap();
+        let checksum = *self.rdb_checksum.read().unwrap();
+        let server = Arc::clone(self);
+        thread::spawn(move || {
+            let result =
+                rdb::save_rdb(&server.rdb_path(), &snapshot.entries, compression, checksum);
+            if result.is_err() {
+                println!(
+                    "background saving error: {}",
+                    result.as_ref().err().unwrap()
+                );
+            }
+            let mut save_state = server.save_state.lock().unwrap();
+            save_state.in_progress = false;
+            finish_save(&mut save_state, snapshot.version, result.is_ok());
+        });
+        Ok(())
+    }
+
+    /// SHUTDOWN, waits for a background save or AOF rewrite to rename its file into place, syncs
+    /// the AOF and then does the final save. It only returns when that save fails
+    pub fn shutdown(&self, save: bool) -> RedisError {
+        *self.shutting_down.write().unwrap() = true;
+        while self.save_state.lock().unwrap().in_progress {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // rewrites start under this lock, and holding it to the end keeps writes out of the AOF
+        // once it has been synced
+        let mut replication_lock = self.master_repl_mutex.lock().unwrap();
+        while self.aof.rewrite_in_progress() {
+            drop(replication_lock);
+            thread::sleep(Duration::from_millis(10));
+            replication_lock = self.master_repl_mutex.lock().unwrap();
+        }
+        self.aof.fsync();
+        if save {
+            if let Err(error) = self.save() {
+                *self.shutting_down.write().unwrap() = false;
+                drop(replication_lock);
+                return error;
+            }
+        }
+        self.aof.close();
+        std::process::exit(0);
+    }
+
+    /// whether a save point (or BGSAVE SCHEDULE) calls for a background save now
+    pub fn should_background_save(&self) -> bool {
+        let save_state = self.save_state.lock().unwrap();
+        if save_state.in_progress {
+            return false;
+        }
+        if save_state.scheduled {
+            return true;
+        }
+        let now = SystemTime::now();
+        if !save_state.last_save_ok
+            && now
+                .duration_since(save_state.last_attempt)
+                .unwrap_or_default()
+                < SAVE_RETRY_DELAY
+        {
+            return false;
+        }
+        let changes = self
+            .data_store
+            .last_version()
+            .saturating_sub(save_state.saved_version);
+        let elapsed = now
+            .duration_since(save_state.last_save)
+            .unwrap_or_default()
+            .as_secs();
+        let save_points = self.save_points.read().unwrap();
+        save_points
+            .iter()
+            .any(|point| changes >= point.changes && elapsed >= point.seconds)
+    }
 
-        let filepath = server.dir.clone() + "/" + &server.dbfilename;
-        rdb::load_rdb(&filepath, &server.data_store);
-        return server;
+    pub fn allocate_client_id(&self) -> u64 {
+        let mut next_client_id = self.next_client_id.lock().unwrap();
+        let id = *next_client_id;
+        *next_client_id += 1;
+        id
     }
 
+    pub fn add_client(&self, client: &Arc<Client>) {
+        let mut clients = self.clients.write().unwrap();
+        clients.insert(client.id, Arc::clone(client));
+    }
+
+    pub fn remove_client(&self, client: &Arc<Client>) {
+        let mut clients = self.clients.write().unwrap();
+        clients.remove(&client.id);
+        drop(clients);
+        self.pub_sub.remove_client(client);
+        self.tracking.remove_client(client);
+        self.remove_monitor(client);
+    }
+
+    /// new connections start logged in as the default user unless it has a password
+    pub fn requires_auth(&self) -> bool {
+        self.acl.requires_auth()
+    }
+
+    /// overlapping pauses last until the later one ends, and stay ALL if either was
+    pub fn pause_clients(&self, end: SystemTime, all: bool) {
+        let mut pause = self.pause.write().unwrap();
+        if pause.is_some() && SystemTime::now().lt(&pause.as_ref().unwrap().end) {
+            let current = pause.as_ref().unwrap();
+            *pause = Some(ClientPause {
+                end: end.max(current.end),
+                all: all || current.all,
+            });
+        } else {
+            *pause = Some(ClientPause { end, all });
+        }
+    }
+
+    pub fn unpause_clients(&self) {
+        *self.pause.write().unwrap() = None;
+    }
+
+    /// whether a command (a write one or not) has to wait for CLIENT PAUSE to end
+    pub fn is_paused(&self, write: bool) -> bool {
+        let pause = self.pause.read().unwrap();
+        pause
+            .as_ref()
+            .is_some_and(|pause| (pause.all || write) && SystemTime::now().lt(&pause.end))
+    }
+
+    pub fn wait_while_paused(&self, write: bool) {
+        while self.is_paused(write) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    pub fn add_monitor(&self, client: &Arc<Client>) {
+        let mut monitors = self.monitors.write().unwrap();
+        if !monitors.iter().any(|monitor| monitor.id == client.id) {
+            monitors.push(Arc::clone(client));
+        }
+    }
+
+    pub fn remove_monitor(&self, client: &Arc<Client>) {
+        let mut monitors = self.monitors.write().unwrap();
+        monitors.retain(|monitor| monitor.id != client.id);
+    }
+
+    /// shows a command to every MONITOR client, in the same format redis uses
+    pub fn feed_monitors(&self, client: &Client, arguments: &[Vec<u8>]) {
+        let monitors = self.monitors.read().unwrap();
+        if monitors.is_empty() {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let quoted: Vec<String> = arguments.iter().map(|argument| quote(argument)).collect();
+        let line = format!("{timestamp:.6} [0 {}] {}", client.addr, quoted.join(" "));
+        let message = PushMessage::with_resp2(
+            &RedisType::SimpleString(line.clone()),
+            vec![
+                RedisType::BulkString(Some("monitor".into())),
+                RedisType::SimpleString(line),
+            ],
+        );
+        for monitor in monitors.iter() {
+            monitor.push(&message);
+        }
+    }
+
+    /// a write command, replicas and the AOF both get it with the same encoding
+    pub fn propagate(&self, command: RedisType) {
+        let command_bytes = resp_parser::encode(&command);
+        self.aof.append(&command_bytes);
+        self.send_to_replications(command_bytes);
+    }
+
+    /// for what replicas need but the AOF doesn't, PUBLISH and REPLCONF GETACK
     pub fn queue_send_to_replications(&self, command: RedisType) {
-        let command_string = resp_parser::encode(&command);
+        self.send_to_replications(resp_parser::encode(&command));
+    }
+
+    fn send_to_replications(&self, command_bytes: Bytes) {
         let mut master_repl_offset = self.master_repl_offset.write().unwrap();
-        *master_repl_offset += command_string.as_bytes().len() as u64;
+        *master_repl_offset += command_bytes.len() as u64;
         drop(master_repl_offset);
 
         let replication_vec = self.connected_replications.read().unwrap();
         for replication in replication_vec.iter() {
             let mut send_buffer = replication.send_buffer.write().unwrap();
-            send_buffer.push(command_string.clone());
+            send_buffer.push(command_bytes.clone()); // shares the same allocation
+        }
+    }
+}
+
+fn shutdown_in_progress() -> RedisError {
+    RedisError::Generic("Shutdown in progress".to_owned())
+}
+
+fn finish_save(save_state: &mut SaveState, version: u64, ok: bool) {
+    save_state.last_save_ok = ok;
+    if ok {
+        save_state.last_save = SystemTime::now();
+        save_state.saved_version = version;
+    }
+}
+
+/// `<seconds> <changes>` pairs separated by spaces, an empty string turns saving off
+pub fn parse_save_points(save: &str) -> RedisResult<Vec<SavePoint>> {
+    let numbers: Vec<&str> = save.split_whitespace().collect();
+    if !numbers.len().is_multiple_of(2) {
+        return Err(RedisError::Generic("Invalid save parameters".to_owned()));
+    }
+    let mut save_points = Vec::new();
+    for pair in numbers.chunks_exact(2) {
+        let seconds = str::parse::<u64>(pair[0]);
+        let changes = str::parse::<u64>(pair[1]);
+        if seconds.is_err() || changes.is_err() {
+            return Err(RedisError::Generic("Invalid save parameters".to_owned()));
+        }
+        save_points.push(SavePoint {
+            seconds: seconds.unwrap(),
+            changes: changes.unwrap(),
+        });
+    }
+    Ok(save_points)
+}
+
+pub fn save_points_to_string(save_points: &[SavePoint]) -> String {
+    save_points
+        .iter()
+        .map(|point| format!("{} {}", point.seconds, point.changes))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// double quotes an argument, escaping anything that isn't printable
+fn quote(argument: &[u8]) -> String {
+    let mut result = String::from("\"");
+    for &byte in argument {
+        match byte {
+            b'\\' => result.push_str("\\\\"),
+            b'"' => result.push_str("\\\""),
+            b'\n' => result.push_str("\\n"),
+            b'\r' => result.push_str("\\r"),
+            b'\t' => result.push_str("\\t"),
+            0x07 => result.push_str("\\a"),
+            0x08 => result.push_str("\\b"),
+            b' '..=b'~' => result.push(byte as char),
+            _ => result.push_str(&format!("\\x{byte:02x}")),
         }
     }
+    result.push('"');
+    result
 }
@@ -0,0 +1,218 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    handlers::utils::convert_to_redis_bulk_strings,
+    structs::client::{Client, ClientRegistry},
+    utils::resp_parser::{PushMessage, RedisType},
+};
+
+const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// The server side of client side caching. Remembers which clients read which keys (or which
+/// prefixes BCAST clients care about) so a modification can tell them to drop their copy.
+pub struct Tracking {
+    keys: RwLock<HashMap<Vec<u8>, HashSet<u64>>>, // key to the clients that read it since it last changed
+    prefixes: RwLock<HashMap<Vec<u8>, HashSet<u64>>>, // BCAST prefix to its clients
+    clients: Arc<ClientRegistry>,
+}
+
+impl Tracking {
+    pub fn new(clients: Arc<ClientRegistry>) -> Tracking {
+        Tracking {
+            keys: RwLock::new(HashMap::new()),
+            prefixes: RwLock::new(HashMap::new()),
+            clients,
+        }
+    }
+
+    /// records the keys a read only command looked at, if the client is tracking them
+    pub fn track_read(&self, client: &Client, keys: Vec<&Vec<u8>>) {
+        let state = client.tracking.read().unwrap();
+        if !state.enabled || state.bcast || keys.is_empty() {
+            return;
+        }
+        let cache = if state.optin {
+            state.caching == Some(true)
+        } else if state.optout {
+            state.caching != Some(false)
+        } else {
+            true
+        };
+        drop(state);
+        if !cache {
+            return;
+        }
+
+        let mut tracked_keys = self.keys.write().unwrap();
+        for key in keys {
+            tracked_keys
+                .entry(key.clone())
+                .or_default()
+                .insert(client.id);
+        }
+    }
+
+    pub fn add_prefixes(&self, client: &Client, prefixes: &[Vec<u8>]) {
+        let mut tracked_prefixes = self.prefixes.write().unwrap();
+        for prefix in prefixes {
+            tracked_prefixes
+                .entry(prefix.clone())
+                .or_default()
+                .insert(client.id);
+        }
+    }
+
+    /// forgets a client's BCAST prefixes, the keys it read are dropped lazily as they change
+    pub fn remove_client(&self, client: &Client) {
+        let mut tracked_prefixes = self.prefixes.write().unwrap();
+        tracked_prefixes.retain(|_, ids| {
+            ids.remove(&client.id);
+            !ids.is_empty()
+        });
+    }
+
+    /// tells everyone who might have `key` cached that it changed
+    pub fn invalidate(&self, key: &[u8]) {
+        let mut ids: HashSet<u64> = HashSet::new();
+
+        let tracked_keys = self.keys.read().unwrap();
+        let key_tracked = tracked_keys.contains_key(key);
+        drop(tracked_keys);
+        if key_tracked {
+            // default mode only tells a client once, it has to read the key again to hear about the next change
+            let mut tracked_keys = self.keys.write().unwrap();
+            ids.extend(tracked_keys.remove(key).unwrap_or_default());
+        }
+
+        let tracked_prefixes = self.prefixes.read().unwrap();
+        for (prefix, prefix_ids) in tracked_prefixes.iter() {
+            if key.starts_with(prefix) {
+                ids.extend(prefix_ids);
+            }
+        }
+        drop(tracked_prefixes);
+
+        for id in ids {
+            self.send_invalidation(id, Some(key));
+        }
+    }
+
+    /// a flush changes every key, so every tracking client gets a null invalidation
+    pub fn invalidate_all(&self) {
+        let mut tracked_keys = self.keys.write().unwrap();
+        tracked_keys.clear();
+        drop(tracked_keys);
+
+        let clients = self.clients.read().unwrap();
+        let ids: Vec<u64> = clients
+            .values()
+            .filter(|client| client.tracking.read().unwrap().enabled)
+            .map(|client| client.id)
+            .collect();
+        drop(clients);
+        for id in ids {
+            self.send_invalidation(id, None);
+        }
+    }
+
+    /// `None` invalidates every key
+    fn send_invalidation(&self, id: u64, key: Option<&[u8]>) {
+        let clients = self.clients.read().unwrap();
+        let client_option = clients.get(&id);
+        if client_option.is_none() {
+            return; // disconnected
+        }
+        let client = client_option.unwrap();
+        let mut state = client.tracking.write().unwrap();
+        if !state.enabled {
+            return;
+        }
+
+        let mut target = client;
+        if state.redirect.is_some() {
+            let target_option = clients.get(&state.redirect.unwrap());
+            if target_option.is_none() {
+                if !state.broken_redirect && client.protover() >= 3 {
+                    client.push(&PushMessage::new(convert_to_redis_bulk_strings(vec![
+                        "tracking-redir-broken",
+                    ])));
+                }
+                state.broken_redirect = true;
+                return;
+            }
+            target = target_option.unwrap();
+        }
+        drop(state);
+
+        let keys = match key {
+            Some(key) => RedisType::Array(convert_to_redis_bulk_strings(vec![key])),
+            None => RedisType::Null,
+        };
+        if target.protover() >= 3 {
+            target.push(&PushMessage::new(vec![
+                RedisType::BulkString(Some(b"invalidate".to_vec())),
+                keys,
+            ]));
+        } else if target
+            .channels
+            .read()
+            .unwrap()
+            .contains(INVALIDATE_CHANNEL.as_bytes())
+        {
+            // RESP2 has no pushes, so the redirect client gets a pub/sub message instead
+            target.push(&PushMessage::new(vec![
+                RedisType::BulkString(Some(b"message".to_vec())),
+                RedisType::BulkString(Some(INVALIDATE_CHANNEL.into())),
+                keys,
+            ]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::client::tests::connected_client;
+    use std::{io::Read, time::Duration};
+
+    #[test]
+    fn resp2_redirect_invalidations_are_queued_like_pub_sub_messages() {
+        let clients = Arc::new(RwLock::new(HashMap::new()));
+        let tracking = Tracking::new(Arc::clone(&clients));
+        let (reader, _reader_peer) = connected_client(1);
+        let (redirect, mut redirect_peer) = connected_client(2);
+        reader.tracking.write().unwrap().enabled = true;
+        reader.tracking.write().unwrap().redirect = Some(2);
+        redirect
+            .channels
+            .write()
+            .unwrap()
+            .insert(INVALIDATE_CHANNEL.into());
+        clients.write().unwrap().insert(1, Arc::clone(&reader));
+        clients.write().unwrap().insert(2, Arc::clone(&redirect));
+
+        // 40MB of invalidations, past the output limit of a redirect client that never reads
+        let key = vec![b'k'; 1 << 20];
+        for _ in 0..40 {
+            tracking.track_read(&reader, vec![&key]);
+            tracking.invalidate(&key);
+        }
+
+        let expected = PushMessage::new(vec![
+            RedisType::BulkString(Some(b"message".to_vec())),
+            RedisType::BulkString(Some(INVALIDATE_CHANNEL.into())),
+            RedisType::Array(convert_to_redis_bulk_strings(vec![key])),
+        ])
+        .resp2;
+        let mut received = Vec::new();
+        redirect_peer
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        redirect_peer.read_to_end(&mut received).unwrap(); // ends because the connection was closed
+        assert!(received.starts_with(&expected));
+        assert!(received.len() < 40 * expected.len());
+    }
+}
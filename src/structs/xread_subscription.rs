@@ -6,28 +6,28 @@ use std::{
 use crate::Server;
 
 pub struct XreadSubscription {
-    wakeup_keys: HashSet<String>,
+    wakeup_keys: HashSet<Vec<u8>>,
     wakeup_sender: Sender<()>,
 }
 
 impl XreadSubscription {
-    pub fn new(wakeup_keys: HashSet<String>, wakeup_sender: Sender<()>) -> XreadSubscription {
-        return XreadSubscription {
+    pub fn new(wakeup_keys: HashSet<Vec<u8>>, wakeup_sender: Sender<()>) -> XreadSubscription {
+        XreadSubscription {
             wakeup_keys,
             wakeup_sender,
-        };
+        }
     }
 
-    pub fn attempt_wakeup(&self, key: &String) -> bool {
+    pub fn attempt_wakeup(&self, key: &[u8]) -> bool {
         if self.wakeup_keys.contains(key) {
-            self.wakeup_sender.send(()).unwrap();
+            let _ = self.wakeup_sender.send(()); // the waiting client may have disconnected
             return true;
         }
-        return false;
+        false
     }
 }
 
-pub fn wakeup_subscribers(server_info: &Arc<Server>, key: &String) {
+pub fn wakeup_subscribers(server_info: &Arc<Server>, key: &[u8]) {
     let mut subscribers = server_info.xread_subscriptions.write().unwrap();
     subscribers.retain(|subscriber| !subscriber.attempt_wakeup(key));
 }
@@ -0,0 +1,253 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    time::SystemTime,
+};
+
+use crate::{
+    error::{RedisError, RedisResult},
+    structs::data_store::{DataType, Entry},
+    utils::{
+        rdb,
+        resp_parser::{self, RedisType},
+    },
+};
+
+// an AOF is a directory of files a manifest lists, like redis 7. The base file is the keyspace as
+// of the last rewrite, as an RDB file or as commands, and the incremental files hold the writes
+// since then in order. A rewrite starts a new incremental file right away and only drops the old
+// files from the manifest once the new base is on disk, so a crash at any point leaves a manifest
+// that loads
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum AofFileType {
+    Base,
+    Incremental,
+}
+
+#[derive(Clone)]
+pub struct AofFile {
+    pub name: String,
+    pub seq: u64,
+    pub file_type: AofFileType,
+}
+
+#[derive(Clone)]
+pub struct Manifest {
+    pub base: Option<AofFile>,
+    pub incrementals: Vec<AofFile>,
+}
+
+impl Manifest {
+    pub fn new() -> Manifest {
+        Manifest {
+            base: None,
+            incrementals: Vec::new(),
+        }
+    }
+
+    /// `file <name> seq <seq> type <b|h|i>` lines, history files are ones a rewrite replaced
+    pub fn parse(contents: &str) -> RedisResult<Manifest> {
+        let mut manifest = Manifest::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if !words.len().is_multiple_of(2) {
+                return Err(invalid_manifest(line));
+            }
+            let mut name: Option<&str> = None;
+            let mut seq: Option<u64> = None;
+            let mut file_type: Option<&str> = None;
+            for pair in words.chunks_exact(2) {
+                match pair[0] {
+                    "file" => name = Some(pair[1]),
+                    "seq" => seq = str::parse::<u64>(pair[1]).ok(),
+                    "type" => file_type = Some(pair[1]),
+                    _ => {} // newer versions can add fields
+                }
+            }
+            if name.is_none() || seq.is_none() || file_type.is_none() {
+                return Err(invalid_manifest(line));
+            }
+            let file = AofFile {
+                name: name.unwrap().to_owned(),
+                seq: seq.unwrap(),
+                file_type: AofFileType::Base,
+            };
+            match file_type.unwrap() {
+                "b" if manifest.base.is_none() => manifest.base = Some(file),
+                "h" => {}
+                "i" if manifest
+                    .incrementals
+                    .last()
+                    .is_none_or(|last| last.seq < file.seq) =>
+                {
+                    manifest.incrementals.push(AofFile {
+                        file_type: AofFileType::Incremental,
+                        ..file
+                    })
+                }
+                _ => return Err(invalid_manifest(line)),
+            }
+        }
+        Ok(manifest)
+    }
+
+    pub fn encode(&self) -> String {
+        let mut contents = String::new();
+        for file in self.base.iter().chain(self.incrementals.iter()) {
+            let file_type = if file.file_type == AofFileType::Base {
+                "b"
+            } else {
+                "i"
+            };
+            contents.push_str(&format!(
+                "file {} seq {} type {file_type}\n",
+                file.name, file.seq
+            ));
+        }
+        contents
+    }
+
+    /// the base first, then the incremental files in the order they were written
+    pub fn files(&self) -> Vec<&AofFile> {
+        self.base.iter().chain(self.incrementals.iter()).collect()
+    }
+
+    pub fn next_base_seq(&self) -> u64 {
+        self.base.as_ref().map_or(1, |base| base.seq + 1)
+    }
+
+    pub fn next_incremental_seq(&self) -> u64 {
+        self.incrementals.last().map_or(1, |last| last.seq + 1)
+    }
+}
+
+fn invalid_manifest(line: &str) -> RedisError {
+    RedisError::Aof(format!("invalid AOF manifest line '{line}'"))
+}
+
+/// `None` when there is no manifest yet
+pub fn read_manifest(path: &String) -> RedisResult<Option<Manifest>> {
+    let contents = fs::read_to_string(path);
+    if contents.is_err() {
+        if contents.as_ref().err().unwrap().kind() == std::io::ErrorKind::NotFound {
+            return Ok(None);
+        }
+        return Err(contents.err().unwrap().into());
+    }
+    Ok(Some(Manifest::parse(&contents.unwrap())?))
+}
+
+/// replaces the manifest in one rename, loading sees either the old one or the new one
+pub fn write_manifest(path: &String, manifest: &Manifest) -> RedisResult<()> {
+    write_atomically(path, |writer| {
+        writer.write_all(manifest.encode().as_bytes())?;
+        Ok(())
+    })
+}
+
+/// writes a base file, an RDB preamble or commands that recreate the keyspace
+pub fn save_base(
+    path: &String,
+    entries: &[Entry],
+    rdb_preamble: bool,
+    compression: bool,
+    checksum: bool,
+) -> RedisResult<()> {
+    if rdb_preamble {
+        return rdb::save_rdb(path, entries, compression, checksum);
+    }
+    write_atomically(path, |writer| {
+        for (key, value, expire_time) in entries {
+            write_as_commands(writer, key, value, expire_time)?;
+        }
+        Ok(())
+    })
+}
+
+/// lists, sets, hashes and sorted sets have no commands here to recreate them, and neither do a
+/// stream's consumer groups or deleted entries, bases with those always get an RDB preamble
+pub fn can_write_as_commands(entries: &[Entry]) -> bool {
+    entries.iter().all(|(_, value, expire_time)| match value {
+        DataType::String(_) => true,
+        DataType::Stream(stream) => {
+            let last_entry = stream
+                .query_inclusive(&"-".to_owned(), &"+".to_owned())
+                .pop();
+            expire_time.is_none()
+                && stream.groups.is_empty()
+                && last_entry.is_some_and(|(id, _)| *id == stream.last_id())
+        }
+        _ => false,
+    })
+}
+
+fn write_as_commands(
+    writer: &mut impl Write,
+    key: &[u8],
+    value: &DataType,
+    expire_time: &Option<SystemTime>,
+) -> RedisResult<()> {
+    let mut commands: Vec<Vec<&[u8]>> = Vec::new();
+    let deadline = expire_time.map(|expire_time| {
+        expire_time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+            .to_string()
+    });
+    match value {
+        DataType::String(string) => {
+            let mut command = vec![b"SET".as_slice(), key, string];
+            if let Some(deadline) = deadline.as_ref() {
+                command.extend([b"PXAT".as_slice(), deadline.as_bytes()]);
+            }
+            commands.push(command);
+        }
+        DataType::Stream(stream) => {
+            for (id, fields) in stream.query_inclusive(&"-".to_owned(), &"+".to_owned()) {
+                let mut command = vec![b"XADD".as_slice(), key, id.as_bytes()];
+                for (field, value) in fields {
+                    command.extend([field.as_slice(), value.as_slice()]);
+                }
+                commands.push(command);
+            }
+        }
+        _ => unreachable!(), // can_write_as_commands checked for these
+    }
+    for command in commands {
+        let command = RedisType::Array(
+            command
+                .into_iter()
+                .map(|argument| RedisType::BulkString(Some(argument.to_vec())))
+                .collect(),
+        );
+        writer.write_all(&resp_parser::encode(&command))?;
+    }
+    Ok(())
+}
+
+fn write_atomically(
+    path: &String,
+    write: impl FnOnce(&mut BufWriter<File>) -> RedisResult<()>,
+) -> RedisResult<()> {
+    let temporary_path = format!("{path}.tmp-{}", std::process::id());
+    let result = File::create(&temporary_path)
+        .map_err(RedisError::from)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            let file = writer.into_inner().map_err(|error| error.into_error())?;
+            file.sync_all()?;
+            Ok(())
+        })
+        .and_then(|_| Ok(fs::rename(&temporary_path, path)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary_path);
+    }
+    result
+}
@@ -1,36 +1,80 @@
-use crate::macros::option_get_or_return_none;
+use std::str::{from_utf8, FromStr};
 
-pub fn get_n_strings<'a>(
+use crate::{
+    error::{RedisError, RedisResult},
+    macros::option_get_or_return_none,
+};
+
+/// takes command arguments as bytes, or the process arguments as strings
+pub fn get_n_strings<'a, T: AsRef<[u8]>>(
     token: &str,
-    arguments: &'a Vec<String>,
+    arguments: &'a [T],
     n: u64,
-) -> Option<Vec<&'a String>> {
+) -> Option<Vec<&'a T>> {
     let position = arguments
         .iter()
-        .position(|s| s.to_ascii_lowercase() == token);
-    if position.is_none() {
-        return None;
-    }
-    let mut result: Vec<&String> = Vec::new();
+        .position(|s| s.as_ref().eq_ignore_ascii_case(token.as_bytes()))?;
+    let mut result: Vec<&T> = Vec::new();
     for i in 0..n {
-        let argument = arguments.get(position.unwrap() + 1 + i as usize);
-        if argument.is_none() {
-            return None;
-        }
-        result.push(argument.unwrap());
+        result.push(arguments.get(position + 1 + i as usize)?);
     }
-    return Some(result);
+    Some(result)
 }
 
-pub fn get_string<'a>(token: &str, arguments: &'a Vec<String>) -> Option<&'a String> {
+pub fn get_string<'a, T: AsRef<[u8]>>(token: &str, arguments: &'a [T]) -> Option<&'a T> {
     option_get_or_return_none!(argument_vec, get_n_strings(token, arguments, 1));
-    return Some(argument_vec[0]);
+    Some(argument_vec[0])
+}
+
+pub fn get_u64<T: AsRef<[u8]>>(token: &str, arguments: &[T]) -> Option<u64> {
+    parse::<u64>(get_string(token, arguments)?.as_ref())
 }
 
-pub fn get_u64(token: &str, arguments: &Vec<String>) -> Option<u64> {
-    let string_option = get_string(token, arguments);
-    if string_option.is_none() {
-        return None;
+/// a number (or anything else with a FromStr) out of an argument, which could be any bytes
+pub fn parse<T: FromStr>(argument: &[u8]) -> Option<T> {
+    from_utf8(argument).ok()?.parse::<T>().ok()
+}
+
+/// an argument as text for error messages and names, with anything that isn't utf-8 replaced
+pub fn to_text(argument: &[u8]) -> String {
+    String::from_utf8_lossy(argument).into_owned()
+}
+
+/// the yes/no form redis config booleans take
+pub fn parse_yes_no(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// a yes/no option given on the command line, `default` when it wasn't
+pub fn config_yes_no(name: &str, value: Option<&String>, default: bool) -> RedisResult<bool> {
+    match value {
+        None => Ok(default),
+        Some(value) => parse_yes_no(value)
+            .ok_or_else(|| RedisError::Generic(format!("argument '{name}' must be 'yes' or 'no'"))),
     }
-    return str::parse::<u64>(string_option.unwrap()).ok();
+}
+
+/// a byte count with an optional unit the way redis configs take them, `k` is 1000 and `kb` 1024
+pub fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_ascii_lowercase();
+    let digits_end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let multiplier: u64 = match &value[digits_end..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    str::parse::<u64>(&value[..digits_end])
+        .ok()?
+        .checked_mul(multiplier)
 }
@@ -1,14 +1,19 @@
-use std::io::Read;
+use std::io::{Error, ErrorKind, Read};
 
-use crate::macros::{option_get_or_return_none, result_get_or_return_none};
+use crate::error::RedisResult;
 
-pub fn read_n_bytes(reader: &mut impl Read, n: usize) -> Option<Vec<u8>> {
-    let mut buffer = vec![0u8; n];
-    result_get_or_return_none!(_unused, reader.read_exact(&mut buffer));
-    return Some(buffer);
+pub fn read_n_bytes(reader: &mut impl Read, n: usize) -> RedisResult<Vec<u8>> {
+    // grow as data arrives rather than trusting a length prefix that could be garbage
+    let mut buffer = Vec::new();
+    reader.take(n as u64).read_to_end(&mut buffer)?;
+    if buffer.len() < n {
+        return Err(Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(buffer)
 }
 
-pub fn read_byte(reader: &mut impl Read) -> Option<u8> {
-    option_get_or_return_none!(byte_vec, read_n_bytes(reader, 1));
-    return Some(byte_vec[0]);
+pub fn read_byte(reader: &mut impl Read) -> RedisResult<u8> {
+    let mut buffer = [0u8; 1];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer[0])
 }
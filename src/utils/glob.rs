@@ -0,0 +1,88 @@
+/// glob-style matching with the same rules redis uses for KEYS and PSUBSCRIBE
+/// (`*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` to escape)
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let mut p = 0;
+    let mut s = 0;
+    while p < pattern.len() && s < string.len() {
+        match pattern[p] {
+            b'*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == b'*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true; // trailing star matches everything left
+                }
+                for i in s..string.len() {
+                    if glob_match(&pattern[p + 1..], &string[i..]) {
+                        return true;
+                    }
+                }
+                return false;
+            }
+            b'?' => {
+                s += 1;
+            }
+            b'[' => {
+                p += 1;
+                let not = p < pattern.len() && pattern[p] == b'^';
+                if not {
+                    p += 1;
+                }
+                let mut matched = false;
+                loop {
+                    if p >= pattern.len() {
+                        p -= 1; // unterminated class, treat the end of the pattern as the closing bracket
+                        break;
+                    } else if pattern[p] == b'\\' && p + 1 < pattern.len() {
+                        p += 1;
+                        if pattern[p] == string[s] {
+                            matched = true;
+                        }
+                    } else if pattern[p] == b']' {
+                        break;
+                    } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' {
+                        let mut start = pattern[p];
+                        let mut end = pattern[p + 2];
+                        if start > end {
+                            (start, end) = (end, start);
+                        }
+                        if string[s] >= start && string[s] <= end {
+                            matched = true;
+                        }
+                        p += 2;
+                    } else if pattern[p] == string[s] {
+                        matched = true;
+                    }
+                    p += 1;
+                }
+                if not {
+                    matched = !matched;
+                }
+                if !matched {
+                    return false;
+                }
+                s += 1;
+            }
+            b'\\' if p + 1 < pattern.len() => {
+                p += 1;
+                if pattern[p] != string[s] {
+                    return false;
+                }
+                s += 1;
+            }
+            byte => {
+                if byte != string[s] {
+                    return false;
+                }
+                s += 1;
+            }
+        }
+        p += 1;
+    }
+
+    // only stars can match an exhausted string
+    while p < pattern.len() && pattern[p] == b'*' {
+        p += 1;
+    }
+    p == pattern.len() && s == string.len()
+}
@@ -0,0 +1,28 @@
+/// CRC16-CCITT (XMODEM), the checksum redis cluster uses to map keys to slots
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// the cluster hash slot (0..16384) for a key, only hashing the `{hashtag}` part if there is a non empty one
+pub fn key_hash_slot(bytes: &[u8]) -> u16 {
+    let mut hashed = bytes;
+    if let Some(start) = bytes.iter().position(|b| *b == b'{') {
+        if let Some(length) = bytes[start + 1..].iter().position(|b| *b == b'}') {
+            if length > 0 {
+                hashed = &bytes[start + 1..start + 1 + length];
+            }
+        }
+    }
+    crc16(hashed) & 0x3fff
+}
@@ -0,0 +1,114 @@
+use std::{io::Read, str::from_utf8};
+
+use crate::error::{RedisError, RedisResult};
+use crate::utils::byte_stream;
+
+// same limit redis uses for inline requests, anything longer is almost certainly not a person typing
+const MAX_INLINE_LENGTH: usize = 64 * 1024;
+
+/// reads the rest of an inline command (`PING\r\n`, what telnet and nc send) whose first byte has
+/// already been consumed, and splits it into arguments
+pub fn read_inline(reader: &mut impl Read, first_byte: u8) -> RedisResult<(Vec<Vec<u8>>, u64)> {
+    let mut line = vec![first_byte];
+    let mut bytes_read = 1;
+    while line.last() != Some(&b'\n') {
+        if line.len() > MAX_INLINE_LENGTH {
+            return Err(RedisError::Protocol("too big inline request".to_owned()));
+        }
+        line.push(byte_stream::read_byte(reader)?);
+        bytes_read += 1;
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok((split_arguments(&line)?, bytes_read))
+}
+
+/// splits a line on whitespace, with the same quoting rules as redis-cli. Double quoted arguments
+/// understand `\n`, `\r`, `\t`, `\b`, `\a` and `\xHH` escapes, single quoted ones only `\'`.
+pub fn split_arguments(line: &[u8]) -> RedisResult<Vec<Vec<u8>>> {
+    let mut arguments = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Ok(arguments);
+        }
+
+        let mut argument = Vec::new();
+        let mut in_double_quotes = false;
+        let mut in_single_quotes = false;
+        loop {
+            if in_double_quotes {
+                if i == line.len() {
+                    return Err(unbalanced_quotes());
+                }
+                if line[i] == b'\\' && i + 3 < line.len() && line[i + 1] == b'x' {
+                    let hex_option = from_utf8(&line[i + 2..i + 4])
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                    if let Some(byte) = hex_option {
+                        argument.push(byte);
+                        i += 4;
+                        continue;
+                    }
+                }
+                if line[i] == b'\\' && i + 1 < line.len() {
+                    i += 1;
+                    argument.push(match line[i] {
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'b' => 0x08,
+                        b'a' => 0x07,
+                        byte => byte,
+                    });
+                } else if line[i] == b'"' {
+                    // the closing quote has to end the argument
+                    if i + 1 < line.len() && !line[i + 1].is_ascii_whitespace() {
+                        return Err(unbalanced_quotes());
+                    }
+                    i += 1;
+                    break;
+                } else {
+                    argument.push(line[i]);
+                }
+            } else if in_single_quotes {
+                if i == line.len() {
+                    return Err(unbalanced_quotes());
+                }
+                if line[i] == b'\\' && i + 1 < line.len() && line[i + 1] == b'\'' {
+                    i += 1;
+                    argument.push(b'\'');
+                } else if line[i] == b'\'' {
+                    if i + 1 < line.len() && !line[i + 1].is_ascii_whitespace() {
+                        return Err(unbalanced_quotes());
+                    }
+                    i += 1;
+                    break;
+                } else {
+                    argument.push(line[i]);
+                }
+            } else {
+                if i == line.len() || line[i].is_ascii_whitespace() {
+                    break;
+                }
+                match line[i] {
+                    b'"' => in_double_quotes = true,
+                    b'\'' => in_single_quotes = true,
+                    byte => argument.push(byte),
+                }
+            }
+            i += 1;
+        }
+
+        arguments.push(argument);
+    }
+}
+
+fn unbalanced_quotes() -> RedisError {
+    RedisError::Protocol("unbalanced quotes in request".to_owned())
+}
@@ -0,0 +1,172 @@
+use std::str::from_utf8;
+
+use crate::error::{RedisError, RedisResult};
+
+/// listpacks are what redis keeps small aggregates and stream nodes in, each element carries its
+/// own encoding and a trailing back length so the list can be walked in both directions
+pub struct Listpack {
+    elements: Vec<u8>,
+    count: usize,
+}
+
+impl Listpack {
+    pub fn new() -> Listpack {
+        Listpack {
+            elements: Vec::new(),
+            count: 0,
+        }
+    }
+
+    pub fn push_integer(&mut self, integer: i64) {
+        let mut element = Vec::new();
+        if (0..=127).contains(&integer) {
+            element.push(integer as u8);
+        } else if (-4096..=4095).contains(&integer) {
+            let value = (integer as u16) & 0x1fff;
+            element.extend_from_slice(&[0xc0 | (value >> 8) as u8, value as u8]);
+        } else if let Ok(integer) = i16::try_from(integer) {
+            element.push(0xf1);
+            element.extend_from_slice(&integer.to_le_bytes());
+        } else if (-(1 << 23)..(1 << 23)).contains(&integer) {
+            element.push(0xf2);
+            element.extend_from_slice(&(integer as i32).to_le_bytes()[..3]);
+        } else if let Ok(integer) = i32::try_from(integer) {
+            element.push(0xf3);
+            element.extend_from_slice(&integer.to_le_bytes());
+        } else {
+            element.push(0xf4);
+            element.extend_from_slice(&integer.to_le_bytes());
+        }
+        self.push_element(element);
+    }
+
+    pub fn push_string(&mut self, string: &[u8]) {
+        let integer_option = from_utf8(string)
+            .ok()
+            .and_then(|string| str::parse::<i64>(string).ok())
+            .filter(|integer| integer.to_string().as_bytes() == string);
+        if let Some(integer) = integer_option {
+            return self.push_integer(integer);
+        }
+
+        let length = string.len();
+        let mut element = Vec::with_capacity(length + 5);
+        if length < 1 << 6 {
+            element.push(0x80 | length as u8);
+        } else if length < 1 << 12 {
+            element.extend_from_slice(&[0xe0 | (length >> 8) as u8, length as u8]);
+        } else {
+            element.push(0xf0);
+            element.extend_from_slice(&(length as u32).to_le_bytes());
+        }
+        element.extend_from_slice(string);
+        self.push_element(element);
+    }
+
+    fn push_element(&mut self, element: Vec<u8>) {
+        self.elements.extend_from_slice(&element);
+        self.elements.extend_from_slice(&back_length(element.len()));
+        self.count += 1;
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let total_bytes = 6 + self.elements.len() + 1;
+        let mut bytes = Vec::with_capacity(total_bytes);
+        bytes.extend_from_slice(&(total_bytes as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.count.min(u16::MAX as usize) as u16).to_le_bytes()); // u16::MAX means count them
+        bytes.extend_from_slice(&self.elements);
+        bytes.push(0xff);
+        bytes
+    }
+}
+
+/// the element size in 7 bit groups, most significant first, every group except the first has its
+/// top bit set
+fn back_length(mut length: usize) -> Vec<u8> {
+    let mut bytes = vec![(length & 127) as u8];
+    length >>= 7;
+    while length > 0 {
+        bytes[0] |= 128;
+        bytes.insert(0, (length & 127) as u8);
+        length >>= 7;
+    }
+    bytes
+}
+
+fn corrupt() -> RedisError {
+    RedisError::Rdb("corrupt listpack".to_owned())
+}
+
+fn take<'a>(bytes: &'a [u8], position: &mut usize, n: usize) -> RedisResult<&'a [u8]> {
+    let end = position.checked_add(n).ok_or_else(corrupt)?;
+    let slice = bytes.get(*position..end).ok_or_else(corrupt)?;
+    *position = end;
+    Ok(slice)
+}
+
+fn little_endian_signed(bytes: &[u8]) -> i64 {
+    let mut value: i64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        value |= (*byte as i64) << (i * 8);
+    }
+    let bits = bytes.len() * 8;
+    (value << (64 - bits)) >> (64 - bits) // sign extend
+}
+
+/// every element of a listpack, integers as their decimal form
+pub fn parse(bytes: &[u8]) -> RedisResult<Vec<Vec<u8>>> {
+    let mut position = 0;
+    let header = take(bytes, &mut position, 6)?;
+    let total_bytes = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    if total_bytes != bytes.len() {
+        return Err(corrupt());
+    }
+
+    let mut elements = Vec::new();
+    loop {
+        let start = position;
+        let encoding = take(bytes, &mut position, 1)?[0];
+        let element = match encoding {
+            0xff => return Ok(elements),
+            0x00..=0x7f => (encoding as i64).to_string().into_bytes(),
+            0x80..=0xbf => take(bytes, &mut position, (encoding & 0x3f) as usize)?.to_vec(),
+            0xc0..=0xdf => {
+                let low = take(bytes, &mut position, 1)?[0];
+                let value = (((encoding & 0x1f) as i64) << 8) | low as i64;
+                (if value >= 1 << 12 {
+                    value - (1 << 13)
+                } else {
+                    value
+                })
+                .to_string()
+                .into_bytes()
+            }
+            0xe0..=0xef => {
+                let low = take(bytes, &mut position, 1)?[0];
+                let length = (((encoding & 0x0f) as usize) << 8) | low as usize;
+                take(bytes, &mut position, length)?.to_vec()
+            }
+            0xf0 => {
+                let length = take(bytes, &mut position, 4)?;
+                let length = u32::from_le_bytes(length.try_into().unwrap()) as usize;
+                take(bytes, &mut position, length)?.to_vec()
+            }
+            0xf1 => little_endian_signed(take(bytes, &mut position, 2)?)
+                .to_string()
+                .into_bytes(),
+            0xf2 => little_endian_signed(take(bytes, &mut position, 3)?)
+                .to_string()
+                .into_bytes(),
+            0xf3 => little_endian_signed(take(bytes, &mut position, 4)?)
+                .to_string()
+                .into_bytes(),
+            0xf4 => little_endian_signed(take(bytes, &mut position, 8)?)
+                .to_string()
+                .into_bytes(),
+            _ => return Err(corrupt()),
+        };
+        let element_length = position - start;
+        take(bytes, &mut position, back_length(element_length).len())?;
+        elements.push(element);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::error::{RedisError, RedisResult};
+
+const MAX_LITERAL: usize = 1 << 5;
+const MAX_OFFSET: usize = 1 << 13;
+const MAX_REFERENCE: usize = (1 << 8) + (1 << 3); // the longest back reference, in bytes
+const HASH_BITS: u32 = 14;
+
+// LZF, what redis compresses long strings in RDB files with. The output is a series of runs, a
+// control byte below 32 is a literal run of that many bytes plus one, anything else is a back
+// reference whose top 3 bits are the length minus 2 (7 means a length byte follows) and whose low 5
+// bits plus the next byte are the distance back minus 1.
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32;
+    (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn push_literals(output: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERAL) {
+        output.push((chunk.len() - 1) as u8);
+        output.extend_from_slice(chunk);
+    }
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut table = vec![usize::MAX; 1 << HASH_BITS]; // the last position each 3 byte prefix was seen at
+    let mut literal_start = 0;
+    let mut position = 0;
+    while position + 2 < input.len() {
+        let slot = hash(&input[position..]);
+        let candidate = table[slot];
+        table[slot] = position;
+        if candidate == usize::MAX
+            || position - candidate > MAX_OFFSET
+            || input[candidate..candidate + 3] != input[position..position + 3]
+        {
+            position += 1;
+            continue;
+        }
+
+        push_literals(&mut output, &input[literal_start..position]);
+        let max_length = (input.len() - position).min(MAX_REFERENCE);
+        let mut length = 3;
+        while length < max_length && input[candidate + length] == input[position + length] {
+            length += 1;
+        }
+        let offset = position - candidate - 1;
+        let encoded_length = length - 2;
+        if encoded_length < 7 {
+            output.push(((encoded_length << 5) | (offset >> 8)) as u8);
+        } else {
+            output.push(((7 << 5) | (offset >> 8)) as u8);
+            output.push((encoded_length - 7) as u8);
+        }
+        output.push(offset as u8);
+        position += length;
+        literal_start = position;
+    }
+    push_literals(&mut output, &input[literal_start..]);
+    output
+}
+
+fn corrupt() -> RedisError {
+    RedisError::Rdb("invalid LZF compressed string".to_owned())
+}
+
+/// `length` is the uncompressed length the RDB file recorded, anything else means corruption
+pub fn decompress(input: &[u8], length: usize) -> RedisResult<Vec<u8>> {
+    let mut output = Vec::with_capacity(length.min(1 << 20)); // don't trust a corrupt length with an allocation
+    let mut position = 0;
+    while position < input.len() {
+        let control = input[position] as usize;
+        position += 1;
+        if control < MAX_LITERAL {
+            let literals = input
+                .get(position..position + control + 1)
+                .ok_or_else(corrupt)?;
+            output.extend_from_slice(literals);
+            position += control + 1;
+        } else {
+            let mut reference_length = control >> 5;
+            if reference_length == 7 {
+                reference_length += *input.get(position).ok_or_else(corrupt)? as usize;
+                position += 1;
+            }
+            let low = *input.get(position).ok_or_else(corrupt)? as usize;
+            position += 1;
+            let distance = ((control & 0x1f) << 8) + low + 1;
+            if distance > output.len() || output.len() + reference_length + 2 > length {
+                return Err(corrupt());
+            }
+            let start = output.len() - distance;
+            for i in 0..reference_length + 2 {
+                output.push(output[start + i]); // byte by byte, the reference can overlap what it produces
+            }
+        }
+        if output.len() > length {
+            return Err(corrupt());
+        }
+    }
+
+    if output.len() != length {
+        return Err(corrupt());
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift, enough to get bytes that don't compress without pulling in a crate
+    fn random_bytes(length: usize, mut state: u64) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(length);
+        for _ in 0..length {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            bytes.push(state as u8);
+        }
+        bytes
+    }
+
+    fn round_trip(input: &[u8]) {
+        let compressed = compress(input);
+        assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+    }
+
+    #[test]
+    fn decompress_literal_run() {
+        assert_eq!(decompress(b"\x04hello", 5).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn decompress_short_back_reference() {
+        // "abc" as literals, then 3 bytes from 3 back
+        assert_eq!(decompress(b"\x02abc\x20\x02", 6).unwrap(), b"abcabc");
+    }
+
+    #[test]
+    fn decompress_long_overlapping_back_reference() {
+        // "hello " as literals, then 11 bytes from 6 back with the length in its own byte
+        let compressed = b"\x05hello \xe0\x02\x05";
+        assert_eq!(decompress(compressed, 17).unwrap(), b"hello hello hello");
+        // a run of one byte, 264 bytes back to back from 1 back, the longest a reference can be
+        let compressed = b"\x00x\xe0\xff\x00";
+        assert_eq!(decompress(compressed, 265).unwrap(), vec![b'x'; 265]);
+    }
+
+    #[test]
+    fn decompress_rejects_corrupt_input() {
+        let cases: &[(&[u8], usize)] = &[
+            (b"\x20\x00", 3),        // a back reference with nothing before it
+            (b"\x00a\x20\x05", 4),   // a back reference further back than the output
+            (b"\x05hel", 6),         // a literal run cut short
+            (b"\x00a\xe0", 10),      // a long back reference missing its length byte
+            (b"\x00a\x20", 4),       // a back reference missing its distance byte
+            (b"\x04hello", 4),       // more output than the recorded length
+            (b"\x04hello", 6),       // less output than the recorded length
+            (b"\x02abc\x20\x02", 5), // a back reference running past the recorded length
+        ];
+        for (compressed, length) in cases {
+            assert!(
+                matches!(decompress(compressed, *length), Err(RedisError::Rdb(_))),
+                "{compressed:?} with length {length}"
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip_repetitive() {
+        round_trip(&b"abc".repeat(1000));
+        round_trip(&b"hello world, ".repeat(200));
+        // runs longer than a back reference can cover have to be split into several
+        let run = vec![b'x'; 10_000];
+        let compressed = compress(&run);
+        assert!(compressed.len() < 200);
+        assert_eq!(decompress(&compressed, run.len()).unwrap(), run);
+    }
+
+    #[test]
+    fn round_trip_random() {
+        for (length, seed) in [(1, 1), (100, 2), (4096, 3), (100_000, 4)] {
+            round_trip(&random_bytes(length, seed));
+        }
+        // matches further back than a reference can reach
+        let mut input = random_bytes(MAX_OFFSET + 100, 5);
+        input.extend_from_within(..200);
+        round_trip(&input);
+    }
+
+    #[test]
+    fn round_trip_short() {
+        let input = b"aaaaabbbbbaaaaabbbbb";
+        for length in 0..=input.len() {
+            round_trip(&input[..length]);
+            round_trip(&random_bytes(length, length as u64 + 10));
+        }
+    }
+}
@@ -1,4 +1,13 @@
+pub mod aof;
 pub mod arg_parse;
 pub mod byte_stream;
+pub mod crc64;
+pub mod glob;
+pub mod hash_slot;
+pub mod inline_parser;
+pub mod listpack;
+pub mod lzf;
 pub mod rdb;
 pub mod resp_parser;
+pub mod sha256;
+pub mod ziplist;
@@ -1,153 +1,1026 @@
 use std::{
-    fs::File,
-    io::Read,
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, File},
+    io::{BufWriter, ErrorKind, Read, Write},
     str::from_utf8,
     time::{Duration, SystemTime},
 };
 
 use crate::{
-    structs::data_store::{DataStore, DataType},
-    utils::byte_stream,
+    error::{RedisError, RedisResult},
+    structs::{
+        data_store::{DataStore, DataType, Entry},
+        redis_stream::{self, Consumer, ConsumerGroup, PendingEntry, RedisStream, StreamId},
+        server::REDIS_VERSION,
+    },
+    utils::{
+        arg_parse, byte_stream,
+        crc64::crc64,
+        listpack::{self, Listpack},
+        lzf, ziplist,
+    },
 };
 
-fn read_length(stream: &mut impl Read) -> (u32, bool) {
-    let first_byte = byte_stream::read_byte(stream).unwrap();
+/// the version we write, the one redis 7.2 uses, and the newest we can read
+const RDB_VERSION: u32 = 11;
+const MIN_RDB_VERSION: u32 = 1;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE_PRE_GA: u8 = 6;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const OPCODE_FUNCTION2: u8 = 0xf5;
+const OPCODE_FUNCTION_PRE_GA: u8 = 0xf6;
+const OPCODE_MODULE_AUX: u8 = 0xf7;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+/// quicklist nodes are either a listpack of small elements or one big element on its own
+const QUICKLIST_NODE_CONTAINER_PLAIN: u64 = 1;
+
+/// how many entries redis puts in one stream listpack by default (stream-node-max-entries)
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
+fn read_length(stream: &mut impl Read) -> RedisResult<(u64, bool)> {
+    let first_byte = byte_stream::read_byte(stream)?;
     match (first_byte >> 6) & 0b11 {
         0b00 => {
-            return (first_byte as u32, false);
+            return Ok((first_byte as u64, false));
         }
         0b01 => {
-            let second_byte = byte_stream::read_byte(stream).unwrap();
-            let length = (((first_byte & 0b111111) as u32) << 8) | second_byte as u32;
-            return (length, false);
+            let second_byte = byte_stream::read_byte(stream)?;
+            let length = (((first_byte & 0b111111) as u64) << 8) | second_byte as u64;
+            return Ok((length, false));
         }
-        0b10 => {
-            let bytes = byte_stream::read_n_bytes(stream, 4).unwrap();
-
-            let length = ((bytes[0] as u32) << 24)
-                | ((bytes[1] as u32) << 16)
-                | ((bytes[2] as u32) << 8)
-                | (bytes[3] as u32);
-            return (length, false);
+        0b10 if first_byte == 0x80 => {
+            let bytes = byte_stream::read_n_bytes(stream, 4)?;
+            return Ok((u32::from_be_bytes(bytes.try_into().unwrap()) as u64, false));
         }
-        0b11 => return (first_byte as u32 & 0b111111, true),
+        0b10 if first_byte == 0x81 => {
+            let bytes = byte_stream::read_n_bytes(stream, 8)?;
+            return Ok((u64::from_be_bytes(bytes.try_into().unwrap()), false));
+        }
+        0b11 => return Ok((first_byte as u64 & 0b111111, true)),
         _ => {}
     }
-    return (0, false);
+    Err(RedisError::Rdb(format!(
+        "unknown length encoding {first_byte:#04x}"
+    )))
+}
+
+/// a length that isn't allowed to be one of the special string encodings
+fn read_plain_length(stream: &mut impl Read) -> RedisResult<u64> {
+    let (length, encoded) = read_length(stream)?;
+    if encoded {
+        return Err(RedisError::Rdb("unexpected encoded length".to_owned()));
+    }
+    Ok(length)
 }
 
-fn read_string(stream: &mut impl Read) -> String {
-    let (length, encoded) = read_length(stream);
+fn read_bytes(stream: &mut impl Read) -> RedisResult<Vec<u8>> {
+    let (length, encoded) = read_length(stream)?;
     if encoded {
         match length {
-            0 => return (byte_stream::read_byte(stream).unwrap() as i8).to_string(),
+            0 => {
+                return Ok((byte_stream::read_byte(stream)? as i8)
+                    .to_string()
+                    .into_bytes())
+            }
             1 => {
-                let bytes = byte_stream::read_n_bytes(stream, 2).unwrap();
+                let bytes = byte_stream::read_n_bytes(stream, 2)?;
                 let mut integer: u16 = 0;
                 for (i, byte) in bytes.iter().enumerate() {
                     integer |= (*byte as u16) << (i * 8);
                 }
-                return (integer as i16).to_string();
+                return Ok((integer as i16).to_string().into_bytes());
             }
             2 => {
-                let bytes = byte_stream::read_n_bytes(stream, 4).unwrap();
+                let bytes = byte_stream::read_n_bytes(stream, 4)?;
                 let mut integer: u32 = 0;
                 for (i, byte) in bytes.iter().enumerate() {
                     integer |= (*byte as u32) << (i * 8);
                 }
-                return (integer as i32).to_string();
+                return Ok((integer as i32).to_string().into_bytes());
             }
-            _ => return "".to_owned(),
+            3 => {
+                let (compressed_length, _) = read_length(stream)?;
+                let (length, _) = read_length(stream)?;
+                let compressed = byte_stream::read_n_bytes(stream, compressed_length as usize)?;
+                return lzf::decompress(&compressed, length as usize);
+            }
+            _ => return Err(RedisError::Rdb(format!("unknown string encoding {length}"))),
         }
     }
-    let bytes = byte_stream::read_n_bytes(stream, length as usize).unwrap();
-    return from_utf8(&bytes).unwrap().to_owned();
+    byte_stream::read_n_bytes(stream, length as usize)
 }
 
-fn read_key_value_pair(
-    stream: &mut impl Read,
-    value_type_option: Option<u8>,
-) -> Option<(String, String)> {
-    let value_type = value_type_option.unwrap_or_else(|| byte_stream::read_byte(stream).unwrap());
+fn read_millisecond_time(stream: &mut impl Read) -> RedisResult<u64> {
+    let bytes = byte_stream::read_n_bytes(stream, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// the score encoding of the first sorted set type, its length byte doubles as the special values
+fn read_string_double(stream: &mut impl Read) -> RedisResult<f64> {
+    let length = byte_stream::read_byte(stream)?;
+    match length {
+        253 => Ok(f64::NAN),
+        254 => Ok(f64::INFINITY),
+        255 => Ok(f64::NEG_INFINITY),
+        _ => parse_score(&byte_stream::read_n_bytes(stream, length as usize)?),
+    }
+}
+
+fn read_binary_double(stream: &mut impl Read) -> RedisResult<f64> {
+    let bytes = byte_stream::read_n_bytes(stream, 8)?;
+    Ok(f64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn parse_score(score: &[u8]) -> RedisResult<f64> {
+    match score {
+        b"inf" | b"+inf" => Ok(f64::INFINITY),
+        b"-inf" => Ok(f64::NEG_INFINITY),
+        _ => arg_parse::parse::<f64>(score).ok_or_else(|| {
+            RedisError::Rdb(format!(
+                "invalid sorted set score {:?}",
+                arg_parse::to_text(score)
+            ))
+        }),
+    }
+}
+
+/// reads `count` strings, not trusting a corrupt count with an allocation
+fn read_strings(stream: &mut impl Read, count: u64) -> RedisResult<Vec<Vec<u8>>> {
+    let mut strings = Vec::with_capacity((count as usize).min(1 << 16));
+    for _ in 0..count {
+        strings.push(read_bytes(stream)?);
+    }
+    Ok(strings)
+}
+
+fn pairs(elements: Vec<Vec<u8>>) -> RedisResult<Vec<(Vec<u8>, Vec<u8>)>> {
+    if !elements.len().is_multiple_of(2) {
+        return Err(RedisError::Rdb(
+            "odd number of elements in a pair encoding".to_owned(),
+        ));
+    }
+    let mut iterator = elements.into_iter();
+    let mut result = Vec::new();
+    while let (Some(first), Some(second)) = (iterator.next(), iterator.next()) {
+        result.push((first, second));
+    }
+    Ok(result)
+}
+
+fn sorted_set_from_pairs(elements: Vec<Vec<u8>>) -> RedisResult<DataType> {
+    let mut sorted_set = HashMap::new();
+    for (member, score) in pairs(elements)? {
+        sorted_set.insert(member, parse_score(&score)?);
+    }
+    Ok(DataType::SortedSet(sorted_set))
+}
+
+fn read_value(stream: &mut impl Read, value_type: u8) -> RedisResult<DataType> {
     match value_type {
-        // string encoding
-        0 => {
-            let key = read_string(stream);
-            let value = read_string(stream);
-            return Some((key, value));
+        TYPE_STRING => Ok(DataType::String(read_bytes(stream)?)),
+        TYPE_LIST => {
+            let length = read_plain_length(stream)?;
+            Ok(DataType::List(VecDeque::from(read_strings(
+                stream, length,
+            )?)))
+        }
+        TYPE_SET => {
+            let length = read_plain_length(stream)?;
+            Ok(DataType::Set(HashSet::from_iter(read_strings(
+                stream, length,
+            )?)))
+        }
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let length = read_plain_length(stream)?;
+            let mut sorted_set = HashMap::new();
+            for _ in 0..length {
+                let member = read_bytes(stream)?;
+                let score = if value_type == TYPE_ZSET {
+                    read_string_double(stream)?
+                } else {
+                    read_binary_double(stream)?
+                };
+                sorted_set.insert(member, score);
+            }
+            Ok(DataType::SortedSet(sorted_set))
+        }
+        TYPE_HASH => {
+            let length = read_plain_length(stream)?;
+            let mut hash = HashMap::new();
+            for _ in 0..length {
+                let field = read_bytes(stream)?;
+                hash.insert(field, read_bytes(stream)?);
+            }
+            Ok(DataType::Hash(hash))
+        }
+        TYPE_HASH_ZIPMAP => {
+            let pairs = ziplist::parse_zipmap(&read_bytes(stream)?)?;
+            Ok(DataType::Hash(HashMap::from_iter(pairs)))
+        }
+        TYPE_LIST_ZIPLIST => {
+            let elements = ziplist::parse_ziplist(&read_bytes(stream)?)?;
+            Ok(DataType::List(VecDeque::from(elements)))
+        }
+        TYPE_SET_INTSET => {
+            let members = ziplist::parse_intset(&read_bytes(stream)?)?;
+            Ok(DataType::Set(HashSet::from_iter(members)))
+        }
+        TYPE_SET_LISTPACK => {
+            let members = listpack::parse(&read_bytes(stream)?)?;
+            Ok(DataType::Set(HashSet::from_iter(members)))
+        }
+        TYPE_ZSET_ZIPLIST => sorted_set_from_pairs(ziplist::parse_ziplist(&read_bytes(stream)?)?),
+        TYPE_ZSET_LISTPACK => sorted_set_from_pairs(listpack::parse(&read_bytes(stream)?)?),
+        TYPE_HASH_ZIPLIST => {
+            let pairs = pairs(ziplist::parse_ziplist(&read_bytes(stream)?)?)?;
+            Ok(DataType::Hash(HashMap::from_iter(pairs)))
+        }
+        TYPE_HASH_LISTPACK => {
+            let pairs = pairs(listpack::parse(&read_bytes(stream)?)?)?;
+            Ok(DataType::Hash(HashMap::from_iter(pairs)))
+        }
+        TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+            let node_count = read_plain_length(stream)?;
+            let mut list = VecDeque::new();
+            for _ in 0..node_count {
+                let mut container = 2; // packed
+                if value_type == TYPE_LIST_QUICKLIST_2 {
+                    container = read_plain_length(stream)?;
+                }
+                let bytes = read_bytes(stream)?;
+                if value_type == TYPE_LIST_QUICKLIST {
+                    list.extend(ziplist::parse_ziplist(&bytes)?);
+                } else if container == QUICKLIST_NODE_CONTAINER_PLAIN {
+                    list.push_back(bytes);
+                } else {
+                    list.extend(listpack::parse(&bytes)?);
+                }
+            }
+            Ok(DataType::List(list))
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            Ok(DataType::Stream(read_stream(stream, value_type)?))
+        }
+        TYPE_MODULE_PRE_GA | TYPE_MODULE_2 => {
+            Err(RedisError::Rdb("module values aren't supported".to_owned()))
+        }
+        _ => Err(RedisError::Rdb(format!(
+            "unsupported value type {value_type}"
+        ))),
+    }
+}
+
+fn read_stream_id(stream: &mut impl Read) -> RedisResult<StreamId> {
+    Ok((read_plain_length(stream)?, read_plain_length(stream)?))
+}
+
+/// the 16 byte big endian form ids take as radix tree keys and in consumer group PELs
+fn raw_stream_id(bytes: &[u8]) -> RedisResult<StreamId> {
+    if bytes.len() != 16 {
+        return Err(RedisError::Rdb("stream id is not 16 bytes".to_owned()));
+    }
+    Ok((
+        u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+        u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+    ))
+}
+
+fn raw_stream_id_bytes((time, sequence): StreamId) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    bytes[0..8].copy_from_slice(&time.to_be_bytes());
+    bytes[8..16].copy_from_slice(&sequence.to_be_bytes());
+    bytes
+}
+
+/// adds the entries of one listpack node, see `write_stream` for the layout
+fn read_stream_node(
+    elements: &[Vec<u8>],
+    (master_time, master_sequence): StreamId,
+    value: &mut RedisStream,
+) -> RedisResult<()> {
+    let integer = |index: usize| -> RedisResult<i64> {
+        elements
+            .get(index)
+            .and_then(|element| arg_parse::parse::<i64>(element))
+            .ok_or_else(|| RedisError::Rdb("corrupt stream node".to_owned()))
+    };
+    let element = |index: usize| -> RedisResult<Vec<u8>> {
+        elements
+            .get(index)
+            .cloned()
+            .ok_or_else(|| RedisError::Rdb("corrupt stream node".to_owned()))
+    };
+
+    let master_field_count = integer(2)? as usize;
+    let mut master_fields = Vec::new();
+    for i in 0..master_field_count {
+        master_fields.push(element(3 + i)?);
+    }
+    let mut position = 3 + master_field_count + 1; // past the master entry's terminator
+
+    while position < elements.len() {
+        let flags = integer(position)?;
+        let time = master_time.wrapping_add(integer(position + 1)? as u64);
+        let sequence = master_sequence.wrapping_add(integer(position + 2)? as u64);
+        position += 3;
+
+        let mut fields = Vec::new();
+        if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            for field in &master_fields {
+                fields.push((field.clone(), element(position)?));
+                position += 1;
+            }
+        } else {
+            let field_count = integer(position)? as usize;
+            position += 1;
+            for _ in 0..field_count {
+                fields.push((element(position)?, element(position + 1)?));
+                position += 2;
+            }
+        }
+        position += 1; // the element count kept for walking backwards
+
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            value
+                .insert(format!("{time}-{sequence}"), fields)
+                .map_err(|_| RedisError::Rdb("stream entries out of order".to_owned()))?;
+        }
+    }
+    Ok(())
+}
+
+fn read_stream(stream: &mut impl Read, value_type: u8) -> RedisResult<RedisStream> {
+    let mut value = RedisStream::new();
+    let node_count = read_plain_length(stream)?;
+    for _ in 0..node_count {
+        let master_id = raw_stream_id(&read_bytes(stream)?)?;
+        let elements = listpack::parse(&read_bytes(stream)?)?;
+        read_stream_node(&elements, master_id, &mut value)?;
+    }
+
+    let length = read_plain_length(stream)?;
+    if length != value.len() as u64 {
+        return Err(RedisError::Rdb(
+            "stream length doesn't match its entries".to_owned(),
+        ));
+    }
+    value.set_last_id(read_stream_id(stream)?);
+    value.entries_added = length;
+    if value_type >= TYPE_STREAM_LISTPACKS_2 {
+        read_stream_id(stream)?; // first id, which the entries already tell us
+        value.max_deleted_entry_id = read_stream_id(stream)?;
+        value.entries_added = read_plain_length(stream)?;
+    }
+
+    let group_count = read_plain_length(stream)?;
+    for _ in 0..group_count {
+        let name = read_bytes(stream)?;
+        let last_id = read_stream_id(stream)?;
+        let mut entries_read = u64::MAX;
+        if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            entries_read = read_plain_length(stream)?;
+        }
+
+        let mut pending = Vec::new();
+        for _ in 0..read_plain_length(stream)? {
+            let id = raw_stream_id(&byte_stream::read_n_bytes(stream, 16)?)?;
+            let delivery_time = read_millisecond_time(stream)?;
+            let delivery_count = read_plain_length(stream)?;
+            pending.push(PendingEntry {
+                id,
+                delivery_time,
+                delivery_count,
+            });
+        }
+
+        let mut consumers = Vec::new();
+        for _ in 0..read_plain_length(stream)? {
+            let name = read_bytes(stream)?;
+            let seen_time = read_millisecond_time(stream)?;
+            let mut active_time = seen_time;
+            if value_type >= TYPE_STREAM_LISTPACKS_3 {
+                active_time = read_millisecond_time(stream)?;
+            }
+            let mut consumer_pending = Vec::new();
+            for _ in 0..read_plain_length(stream)? {
+                consumer_pending.push(raw_stream_id(&byte_stream::read_n_bytes(stream, 16)?)?);
+            }
+            consumers.push(Consumer {
+                name,
+                seen_time,
+                active_time,
+                pending: consumer_pending,
+            });
+        }
+
+        value.groups.push(ConsumerGroup {
+            name,
+            last_id,
+            entries_read,
+            pending,
+            consumers,
+        });
+    }
+    Ok(value)
+}
+
+/// counts and checksums everything read through it, so errors can say where they happened
+struct RdbReader<R: Read> {
+    inner: R,
+    offset: u64,
+    crc: u64,
+}
+
+impl<R: Read> Read for RdbReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+        self.offset += read as u64;
+        self.crc = crc64(self.crc, &buffer[..read]);
+        Ok(read)
+    }
+}
+
+fn reason(error: RedisError) -> String {
+    match error {
+        RedisError::Io(error) if error.kind() == ErrorKind::UnexpectedEof => {
+            "unexpected end of file".to_owned()
+        }
+        RedisError::Rdb(reason) => reason,
+        error => error.to_string(),
+    }
+}
+
+/// handles one opcode, returns whether it was the end of the file
+fn read_opcode(
+    stream: &mut impl Read,
+    opcode: u8,
+    expire_time: &mut Option<SystemTime>,
+    entries: &mut Vec<Entry>,
+) -> RedisResult<bool> {
+    match opcode {
+        OPCODE_EOF => {
+            return Ok(true);
+        }
+        OPCODE_SELECTDB => {
+            read_length(stream)?; // db id
+        }
+        OPCODE_EXPIRETIME => {
+            let bytes = byte_stream::read_n_bytes(stream, 4)?;
+            let mut expire_time_s: u32 = 0;
+            for (i, byte) in bytes.iter().enumerate() {
+                expire_time_s |= (*byte as u32) << (i * 8);
+            }
+            *expire_time = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(expire_time_s as u64));
+        }
+        OPCODE_EXPIRETIME_MS => {
+            let expire_time_ms = read_millisecond_time(stream)?;
+            *expire_time = Some(SystemTime::UNIX_EPOCH + Duration::from_millis(expire_time_ms));
+        }
+        OPCODE_RESIZEDB => {
+            let (map_size, _) = read_length(stream)?;
+            read_length(stream)?; // expire size
+            entries.reserve((map_size as usize).min(1 << 20)); // don't trust a corrupt size with an allocation
         }
-        // TODO support more than string encoding
+        OPCODE_AUX => {
+            read_bytes(stream)?; // key
+            read_bytes(stream)?; // value
+        }
+        OPCODE_IDLE => {
+            read_length(stream)?; // LRU idle time, there is no eviction to use it for
+        }
+        OPCODE_FREQ => {
+            byte_stream::read_byte(stream)?; // LFU counter
+        }
+        OPCODE_FUNCTION2 => {
+            read_bytes(stream)?; // a function library, there is no scripting to load it into
+        }
+        OPCODE_FUNCTION_PRE_GA | OPCODE_MODULE_AUX => {
+            return Err(RedisError::Rdb("unsupported opcode".to_owned()));
+        }
+        // key value pair
         _ => {
-            return None;
+            let key = read_bytes(stream)?;
+            let value = read_value(stream, opcode).map_err(|error| {
+                RedisError::Rdb(format!(
+                    "{} in the value of key {:?}",
+                    reason(error),
+                    arg_parse::to_text(&key)
+                ))
+            })?;
+            if expire_time.is_none() || SystemTime::now().lt(&expire_time.unwrap()) {
+                entries.push((key, value, *expire_time));
+            }
+            *expire_time = None;
         }
     }
+    Ok(false)
 }
 
-pub fn load_rdb(filepath: &String, data_store: &DataStore) {
-    let file_result = File::open(filepath);
-    if file_result.is_err() {
-        return;
+/// parses a whole RDB file. Every database in it ends up in the one we have, and keys that already
+/// expired are skipped like redis does. A checksum of 0 means the writer didn't compute one.
+pub fn read_rdb(stream: &mut impl Read, verify_checksum: bool) -> RedisResult<Vec<Entry>> {
+    let mut stream = RdbReader {
+        inner: stream,
+        offset: 0,
+        crc: 0,
+    };
+    let header = byte_stream::read_n_bytes(&mut stream, 9)
+        .map_err(|error| RedisError::Rdb(format!("{} in the header", reason(error))))?;
+    if &header[0..5] != b"REDIS" {
+        return Err(RedisError::Rdb(
+            "wrong signature, not an RDB file".to_owned(),
+        ));
+    }
+    let version = from_utf8(&header[5..9])
+        .ok()
+        .and_then(|version| str::parse::<u32>(version).ok())
+        .ok_or_else(|| RedisError::Rdb("invalid RDB version".to_owned()))?;
+    if !(MIN_RDB_VERSION..=RDB_VERSION).contains(&version) {
+        return Err(RedisError::Rdb(format!(
+            "can't handle RDB format version {version}"
+        )));
     }
-    let mut file = file_result.unwrap();
-    byte_stream::read_n_bytes(&mut file, 9).unwrap(); // REDISvvvv (v) version
 
+    let mut entries = Vec::new();
+    let mut expire_time: Option<SystemTime> = None;
     loop {
-        let opcode = byte_stream::read_byte(&mut file).unwrap();
-        match opcode {
-            // EOF
-            0xff => {
-                return;
-            }
-            // SELECTDB
-            0xfe => {
-                read_length(&mut file); // db id
-            }
-            // EXPIRETIME
-            0xfd => {
-                let bytes = byte_stream::read_n_bytes(&mut file, 4).unwrap();
-                let mut expire_time_s: u32 = 0;
-                for (i, byte) in bytes.iter().enumerate() {
-                    expire_time_s |= (*byte as u32) << (i * 8);
+        let opcode_offset = stream.offset;
+        let opcode = byte_stream::read_byte(&mut stream).map_err(|error| {
+            RedisError::Rdb(format!("{} at offset {opcode_offset}", reason(error)))
+        })?;
+        let result = read_opcode(&mut stream, opcode, &mut expire_time, &mut entries);
+        if result.is_err() {
+            return Err(RedisError::Rdb(format!(
+                "{} at offset {}, in opcode {opcode:#04x} at offset {opcode_offset}",
+                reason(result.err().unwrap()),
+                stream.offset
+            )));
+        }
+        if result.unwrap() {
+            break;
+        }
+    }
+
+    // checksums were added in version 5
+    if version >= 5 {
+        let expected = stream.crc;
+        let bytes = byte_stream::read_n_bytes(&mut stream.inner, 8)
+            .map_err(|error| RedisError::Rdb(format!("{} in the checksum", reason(error))))?;
+        let checksum = u64::from_le_bytes(bytes.try_into().unwrap());
+        if verify_checksum && checksum != 0 && checksum != expected {
+            return Err(RedisError::Rdb(format!(
+                "wrong RDB checksum, expected {expected:016x} got {checksum:016x}"
+            )));
+        }
+    }
+    Ok(entries)
+}
+
+/// loads an RDB file or a full resync's snapshot into the data store. Nothing is loaded unless all
+/// of it is valid, so a corrupt one never leaves half of itself behind.
+pub fn load_rdb(
+    stream: &mut impl Read,
+    data_store: &DataStore,
+    verify_checksum: bool,
+) -> RedisResult<()> {
+    let entries = read_rdb(stream, verify_checksum)?;
+    data_store.reserve(entries.len());
+    for (key, value, expire_time) in entries {
+        data_store.insert(&key, value, expire_time);
+    }
+    Ok(())
+}
+
+/// checksums everything written through it, the CRC64 goes at the very end of the file
+struct RdbWriter<W: Write> {
+    inner: W,
+    crc: u64,
+    compression: bool, // LZF compress long strings, rdbcompression
+}
+
+impl<W: Write> Write for RdbWriter<W> {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buffer)?;
+        self.crc = crc64(self.crc, &buffer[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_length(stream: &mut impl Write, length: u64) -> RedisResult<()> {
+    if length < 1 << 6 {
+        stream.write_all(&[length as u8])?;
+    } else if length < 1 << 14 {
+        stream.write_all(&[0b01000000 | (length >> 8) as u8, length as u8])?;
+    } else if length <= u32::MAX as u64 {
+        stream.write_all(&[0x80])?;
+        stream.write_all(&(length as u32).to_be_bytes())?;
+    } else {
+        stream.write_all(&[0x81])?;
+        stream.write_all(&length.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+/// strings that are exactly an integer's canonical form are stored as that integer, like redis does
+fn write_string(stream: &mut RdbWriter<impl Write>, string: &[u8]) -> RedisResult<()> {
+    let integer_option =
+        arg_parse::parse::<i32>(string).filter(|integer| integer.to_string().as_bytes() == string);
+    if let Some(integer) = integer_option {
+        if let Ok(integer) = i8::try_from(integer) {
+            stream.write_all(&[0xc0, integer as u8])?;
+        } else if let Ok(integer) = i16::try_from(integer) {
+            stream.write_all(&[0xc1])?;
+            stream.write_all(&integer.to_le_bytes())?;
+        } else {
+            stream.write_all(&[0xc2])?;
+            stream.write_all(&integer.to_le_bytes())?;
+        }
+        return Ok(());
+    }
+    write_bytes(stream, string)
+}
+
+/// redis only bothers compressing strings longer than 20 bytes, and only keeps the result if it
+/// saves at least 4 bytes
+fn write_bytes(stream: &mut RdbWriter<impl Write>, bytes: &[u8]) -> RedisResult<()> {
+    if stream.compression && bytes.len() > 20 {
+        let compressed = lzf::compress(bytes);
+        if compressed.len() + 4 <= bytes.len() {
+            stream.write_all(&[0xc3])?;
+            write_length(stream, compressed.len() as u64)?;
+            write_length(stream, bytes.len() as u64)?;
+            stream.write_all(&compressed)?;
+            return Ok(());
+        }
+    }
+    write_length(stream, bytes.len() as u64)?;
+    stream.write_all(bytes)?;
+    Ok(())
+}
+
+fn write_aux(stream: &mut RdbWriter<impl Write>, key: &str, value: &str) -> RedisResult<()> {
+    stream.write_all(&[OPCODE_AUX])?;
+    write_string(stream, key.as_bytes())?;
+    write_string(stream, value.as_bytes())?;
+    Ok(())
+}
+
+/// a stream is a radix tree of listpacks keyed by each node's first id. A node starts with a
+/// master entry holding the first entry's field names, entries with the same fields only store values.
+fn write_stream(stream: &mut RdbWriter<impl Write>, value: &RedisStream) -> RedisResult<()> {
+    let entries = value.query_inclusive(&"-".to_owned(), &"+".to_owned());
+    let nodes: Vec<_> = entries.chunks(STREAM_NODE_MAX_ENTRIES).collect();
+    write_length(stream, nodes.len() as u64)?;
+    for node in nodes {
+        let (master_time, master_sequence) = redis_stream::parse_id(node[0].0)?;
+        let master_fields: Vec<&Vec<u8>> = node[0].1.iter().map(|(field, _)| field).collect();
+
+        let mut listpack = Listpack::new();
+        listpack.push_integer(node.len() as i64); // count
+        listpack.push_integer(0); // deleted
+        listpack.push_integer(master_fields.len() as i64);
+        for field in &master_fields {
+            listpack.push_string(field);
+        }
+        listpack.push_integer(0); // master entry terminator
+
+        for (id, fields) in node {
+            let (time, sequence) = redis_stream::parse_id(id)?;
+            let same_fields = fields.len() == master_fields.len()
+                && fields
+                    .iter()
+                    .zip(master_fields.iter())
+                    .all(|((field, _), master_field)| field == *master_field);
+            listpack.push_integer(if same_fields {
+                STREAM_ITEM_FLAG_SAMEFIELDS
+            } else {
+                0
+            });
+            listpack.push_integer((time - master_time) as i64);
+            listpack.push_integer(sequence.wrapping_sub(master_sequence) as i64);
+            if same_fields {
+                for (_, value) in fields.iter() {
+                    listpack.push_string(value);
                 }
-                let (key, value) = read_key_value_pair(&mut file, None).unwrap();
-
-                data_store.insert(
-                    &key,
-                    DataType::String(value),
-                    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(expire_time_s as u64)),
-                );
-            }
-            // EXPIRETIMEMS
-            0xfc => {
-                let bytes = byte_stream::read_n_bytes(&mut file, 8).unwrap();
-                let mut expire_time_ms: u64 = 0;
-                for (i, byte) in bytes.iter().enumerate() {
-                    expire_time_ms |= (*byte as u64) << (i * 8);
+            } else {
+                listpack.push_integer(fields.len() as i64);
+                for (field, value) in fields.iter() {
+                    listpack.push_string(field);
+                    listpack.push_string(value);
                 }
-                let (key, value) = read_key_value_pair(&mut file, None).unwrap();
+            }
+            let mut listpack_count = fields.len() as i64 + 3; // flags, time and sequence
+            if !same_fields {
+                listpack_count += fields.len() as i64 + 1;
+            }
+            listpack.push_integer(listpack_count);
+        }
+
+        write_bytes(stream, &raw_stream_id_bytes((master_time, master_sequence)))?;
+        write_bytes(stream, &listpack.into_bytes())?;
+    }
+
+    let first_id = match entries.first() {
+        Some((id, _)) => redis_stream::parse_id(id)?,
+        None => (0, 0),
+    };
+    write_length(stream, value.len() as u64)?;
+    write_stream_id(stream, value.last_id_parts())?;
+    write_stream_id(stream, first_id)?;
+    write_stream_id(stream, value.max_deleted_entry_id)?;
+    write_length(stream, value.entries_added)?;
 
-                data_store.insert(
-                    &key,
-                    DataType::String(value),
-                    Some(SystemTime::UNIX_EPOCH + Duration::from_millis(expire_time_ms)),
-                );
+    write_length(stream, value.groups.len() as u64)?;
+    for group in &value.groups {
+        write_string(stream, &group.name)?;
+        write_stream_id(stream, group.last_id)?;
+        write_length(stream, group.entries_read)?;
+        write_length(stream, group.pending.len() as u64)?;
+        for entry in &group.pending {
+            stream.write_all(&raw_stream_id_bytes(entry.id))?;
+            stream.write_all(&entry.delivery_time.to_le_bytes())?;
+            write_length(stream, entry.delivery_count)?;
+        }
+        write_length(stream, group.consumers.len() as u64)?;
+        for consumer in &group.consumers {
+            write_string(stream, &consumer.name)?;
+            stream.write_all(&consumer.seen_time.to_le_bytes())?;
+            stream.write_all(&consumer.active_time.to_le_bytes())?;
+            write_length(stream, consumer.pending.len() as u64)?;
+            for id in &consumer.pending {
+                stream.write_all(&raw_stream_id_bytes(*id))?;
             }
-            // RESIZEDB
-            0xfb => {
-                let (map_size, _) = read_length(&mut file);
-                read_length(&mut file); // expire size
-                data_store.reserve(map_size as usize);
+        }
+    }
+    Ok(())
+}
+
+fn write_stream_id(stream: &mut impl Write, (time, sequence): StreamId) -> RedisResult<()> {
+    write_length(stream, time)?;
+    write_length(stream, sequence)?;
+    Ok(())
+}
+
+/// the other aggregates use the plain encodings, every redis version reads those
+fn write_key_value_pair(
+    stream: &mut RdbWriter<impl Write>,
+    key: &[u8],
+    value: &DataType,
+) -> RedisResult<()> {
+    match value {
+        DataType::String(value) => {
+            stream.write_all(&[TYPE_STRING])?;
+            write_string(stream, key)?;
+            write_string(stream, value)?;
+        }
+        DataType::List(value) => {
+            stream.write_all(&[TYPE_LIST])?;
+            write_string(stream, key)?;
+            write_length(stream, value.len() as u64)?;
+            for element in value {
+                write_string(stream, element)?;
+            }
+        }
+        DataType::Set(value) => {
+            stream.write_all(&[TYPE_SET])?;
+            write_string(stream, key)?;
+            write_length(stream, value.len() as u64)?;
+            for member in value {
+                write_string(stream, member)?;
             }
-            // AUX
-            0xfa => {
-                read_string(&mut file); // key
-                read_string(&mut file); // value
+        }
+        DataType::Hash(value) => {
+            stream.write_all(&[TYPE_HASH])?;
+            write_string(stream, key)?;
+            write_length(stream, value.len() as u64)?;
+            for (field, value) in value {
+                write_string(stream, field)?;
+                write_string(stream, value)?;
             }
-            // key value pair
-            _ => {
-                let (key, value) = read_key_value_pair(&mut file, Some(opcode)).unwrap();
-                data_store.insert(&key, DataType::String(value), None);
+        }
+        DataType::SortedSet(value) => {
+            stream.write_all(&[TYPE_ZSET_2])?;
+            write_string(stream, key)?;
+            write_length(stream, value.len() as u64)?;
+            for (member, score) in value {
+                write_string(stream, member)?;
+                stream.write_all(&score.to_le_bytes())?;
             }
         }
+        DataType::Stream(value) => {
+            stream.write_all(&[TYPE_STREAM_LISTPACKS_3])?;
+            write_string(stream, key)?;
+            write_stream(stream, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// serializes a snapshot of the keyspace, everything goes in database 0 since that's the only one
+pub fn write_rdb(
+    stream: &mut impl Write,
+    entries: &[Entry],
+    compression: bool,
+    checksum: bool,
+) -> RedisResult<()> {
+    let mut stream = RdbWriter {
+        inner: stream,
+        crc: 0,
+        compression,
+    };
+    stream.write_all(format!("REDIS{RDB_VERSION:04}").as_bytes())?;
+    let ctime = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    write_aux(&mut stream, "redis-ver", REDIS_VERSION)?;
+    write_aux(&mut stream, "redis-bits", &(usize::BITS).to_string())?;
+    write_aux(&mut stream, "ctime", &ctime.to_string())?;
+    write_aux(&mut stream, "used-mem", "0")?;
+
+    stream.write_all(&[OPCODE_SELECTDB])?;
+    write_length(&mut stream, 0)?;
+    stream.write_all(&[OPCODE_RESIZEDB])?;
+    write_length(&mut stream, entries.len() as u64)?;
+    write_length(
+        &mut stream,
+        entries
+            .iter()
+            .filter(|(_, _, expire_time)| expire_time.is_some())
+            .count() as u64,
+    )?;
+
+    for (key, value, expire_time) in entries {
+        if expire_time.is_some() {
+            let expire_time_ms = expire_time
+                .unwrap()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            stream.write_all(&[OPCODE_EXPIRETIME_MS])?;
+            stream.write_all(&expire_time_ms.to_le_bytes())?;
+        }
+        write_key_value_pair(&mut stream, key, value)?;
+    }
+
+    stream.write_all(&[OPCODE_EOF])?;
+    let crc = if checksum { stream.crc } else { 0 }; // 0 tells readers not to check
+    stream.inner.write_all(&crc.to_le_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// writes to a temporary file next to the target and renames it over, so a crash midway never
+/// leaves a half written dump where the old one was
+pub fn save_rdb(
+    filepath: &String,
+    entries: &[Entry],
+    compression: bool,
+    checksum: bool,
+) -> RedisResult<()> {
+    let temporary_path = format!("{filepath}.tmp-{}", std::process::id());
+    let result = File::create(&temporary_path)
+        .map_err(RedisError::from)
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write_rdb(&mut writer, entries, compression, checksum)?;
+            let file = writer.into_inner().map_err(|error| error.into_error())?;
+            file.sync_all()?;
+            Ok(())
+        })
+        .and_then(|_| Ok(fs::rename(&temporary_path, filepath)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(bytes: &[u8], compression: bool) -> Vec<u8> {
+        let mut stream = RdbWriter {
+            inner: Vec::new(),
+            crc: 0,
+            compression,
+        };
+        write_bytes(&mut stream, bytes).unwrap();
+        stream.inner
+    }
+
+    #[test]
+    fn read_lzf_string_fixture() {
+        // 0xc3, the compressed length, the uncompressed length and "hello hello hello" in LZF
+        let mut fixture: &[u8] = b"\xc3\x0a\x11\x05hello \xe0\x02\x05";
+        assert_eq!(read_bytes(&mut fixture).unwrap(), b"hello hello hello");
+        assert!(fixture.is_empty());
+    }
+
+    #[test]
+    fn read_lzf_string_rejects_corrupt_fixtures() {
+        let cases: &[&[u8]] = &[
+            b"\xc3\x0a\x12\x05hello \xe0\x02\x05", // the wrong uncompressed length
+            b"\xc3\x0a\x11\x05hello \xe0\x02\x09", // a back reference before the start
+            b"\xc3\x0a\x11\x05hello",              // cut short
+        ];
+        for fixture in cases {
+            assert!(read_bytes(&mut &fixture[..]).is_err(), "{fixture:?}");
+        }
+    }
+
+    #[test]
+    fn write_and_read_strings() {
+        let long = b"0123456789".repeat(50);
+        let cases: Vec<&[u8]> = vec![b"", b"a", b"twenty bytes exactly", &long];
+        for bytes in cases {
+            for compression in [false, true] {
+                let encoded = written(bytes, compression);
+                assert_eq!(read_bytes(&mut encoded.as_slice()).unwrap(), bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn only_long_compressible_strings_are_compressed() {
+        let long = b"0123456789".repeat(50);
+        assert_eq!(written(&long, true)[0], 0xc3);
+        assert_ne!(written(&long, false)[0], 0xc3);
+        assert_eq!(written(b"aaaaaaaaaaaaaaaaaaaa", true)[0], 20); // 20 bytes stay plain
+        let mut state: u32 = 1;
+        let random: Vec<u8> = (0..100)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        assert_ne!(written(&random, true)[0], 0xc3); // compressing wouldn't save anything
+    }
+
+    #[test]
+    fn binary_values_round_trip() {
+        let mut hash = HashMap::new();
+        hash.insert(b"\xfe field".to_vec(), b"\x00\xff".to_vec());
+        let entries = vec![
+            (
+                b"k\xff\x00".to_vec(),
+                DataType::String(b"\x80\xc3(".to_vec()),
+                None,
+            ),
+            (b"hash".to_vec(), DataType::Hash(hash), None),
+        ];
+        let mut dump = Vec::new();
+        write_rdb(&mut dump, &entries, false, true).unwrap();
+        let loaded = read_rdb(&mut dump.as_slice(), true).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].0, b"k\xff\x00");
+        assert!(matches!(&loaded[0].1, DataType::String(value) if value == b"\x80\xc3("));
+        assert!(
+            matches!(&loaded[1].1, DataType::Hash(hash) if hash[b"\xfe field".as_slice()] == b"\x00\xff")
+        );
+    }
+
+    #[test]
+    fn binary_listpack_elements() {
+        let mut listpack = Listpack::new();
+        listpack.push_string(b"\xff\xfe");
+        listpack.push_string(b"12");
+        let elements = listpack::parse(&listpack.into_bytes()).unwrap();
+        assert_eq!(elements, vec![b"\xff\xfe".to_vec(), b"12".to_vec()]);
     }
 }
@@ -1,21 +1,21 @@
+use bytes::{BufMut, Bytes, BytesMut};
 use num_bigint::BigInt;
 use std::str::FromStr;
 use std::{
-    // collections::{HashMap, HashSet},
+    fmt::{Display, Write},
     io::Read,
     str::from_utf8,
 };
 
-use crate::macros::{option_get_or_return_none, result_get_or_return_none};
+use crate::error::{RedisError, RedisResult};
 use crate::utils::byte_stream;
 
-// #[derive(Eq, Hash, PartialEq)]
 #[derive(Debug)]
 pub enum RedisType {
     SimpleString(String),
     SimpleError(String),
     Integer(i64),
-    BulkString(Option<String>),
+    BulkString(Option<Vec<u8>>), // binary safe, unlike the line based types
     Array(Vec<RedisType>),
     Null,
     Boolean(bool),
@@ -23,17 +23,22 @@ pub enum RedisType {
     BigNumber(BigInt),
     BulkError(String),
     VerbatimString(String),
-    // Map(HashMap<RedisType, RedisType>),
-    // Set(HashSet<RedisType>),
+    Map(Vec<(RedisType, RedisType)>), // kept in order instead of hashed, replies are built in a set order anyway
+    Set(Vec<RedisType>),
+    Attribute(Vec<(RedisType, RedisType)>, Box<RedisType>), // the attributes and the reply they annotate
     Push(Vec<RedisType>),
 }
 
-fn read_to_next_crlf(reader: &mut impl Read) -> Option<(Vec<u8>, u64)> {
+// same limits redis uses (proto-max-bulk-len and the multibulk length cap)
+const MAX_BULK_LENGTH: i64 = 512 * 1024 * 1024;
+const MAX_AGGREGATE_LENGTH: i64 = 1024 * 1024 * 1024;
+
+fn read_to_next_crlf(reader: &mut impl Read) -> RedisResult<(Vec<u8>, u64)> {
     let mut result = Vec::new();
     let mut cr_found = false;
     let mut bytes_read = 0;
     loop {
-        option_get_or_return_none!(byte, byte_stream::read_byte(reader));
+        let byte = byte_stream::read_byte(reader)?;
         bytes_read += 1;
         match byte {
             b'\r' => {
@@ -41,7 +46,7 @@ fn read_to_next_crlf(reader: &mut impl Read) -> Option<(Vec<u8>, u64)> {
             }
             b'\n' => {
                 if cr_found {
-                    return Some((result, bytes_read));
+                    return Ok((result, bytes_read));
                 } else {
                     result.push(byte);
                 }
@@ -54,257 +59,654 @@ fn read_to_next_crlf(reader: &mut impl Read) -> Option<(Vec<u8>, u64)> {
                 cr_found = false;
             }
         }
+        if result.len() as i64 > MAX_BULK_LENGTH {
+            return Err(RedisError::Protocol("too big inline request".to_owned()));
+        }
     }
 }
 
-fn scan_string(reader: &mut impl Read) -> Option<(String, u64)> {
-    option_get_or_return_none!(bytes_and_count, read_to_next_crlf(reader));
-    let (bytes, bytes_read) = bytes_and_count;
-    result_get_or_return_none!(string, from_utf8(&bytes));
-    return Some((string.to_string(), bytes_read));
+fn scan_string(reader: &mut impl Read) -> RedisResult<(String, u64)> {
+    let (bytes, bytes_read) = read_to_next_crlf(reader)?;
+    let string =
+        from_utf8(&bytes).map_err(|_| RedisError::Protocol("invalid utf-8 in line".to_owned()))?;
+    Ok((string.to_string(), bytes_read))
 }
 
-fn scan_int(reader: &mut impl Read) -> Option<(i64, u64)> {
-    option_get_or_return_none!(string_and_count, scan_string(reader));
-    let (string, bytes_read) = string_and_count;
-    result_get_or_return_none!(integer, str::parse::<i64>(&string));
-    return Some((integer, bytes_read));
+fn scan_int(reader: &mut impl Read) -> RedisResult<(i64, u64)> {
+    let (string, bytes_read) = scan_string(reader)?;
+    let integer = str::parse::<i64>(&string)
+        .map_err(|_| RedisError::Protocol(format!("invalid integer '{string}'")))?;
+    Ok((integer, bytes_read))
 }
 
-fn bulk_string(reader: &mut impl Read) -> Option<(Option<String>, u64)> {
-    option_get_or_return_none!(length_and_count, scan_int(reader));
-    let (length, bytes_read) = length_and_count;
+fn bulk_string(reader: &mut impl Read) -> RedisResult<(Option<Vec<u8>>, u64)> {
+    let (length, bytes_read) = scan_int(reader)?;
     if length < 0 {
-        return Some((None, bytes_read));
+        return Ok((None, bytes_read));
+    }
+    if length > MAX_BULK_LENGTH {
+        return Err(RedisError::Protocol("invalid bulk length".to_owned()));
+    }
+    let mut bytes = byte_stream::read_n_bytes(reader, length as usize + 2)?; // +2 to get the extra crlf
+    if &bytes[length as usize..] != b"\r\n" {
+        return Err(RedisError::Protocol(
+            "bulk string is missing its CRLF".to_owned(),
+        ));
     }
-    option_get_or_return_none!(
-        bytes,
-        byte_stream::read_n_bytes(reader, length as usize + 2)
-    ); // +2 to get the extra crlf
-    result_get_or_return_none!(string, from_utf8(&bytes[..length as usize]));
-    return Some((Some(string.to_string()), bytes_read + length as u64 + 2));
+    bytes.truncate(length as usize);
+    Ok((Some(bytes), bytes_read + length as u64 + 2))
 }
 
-pub fn decode(reader: &mut impl Read) -> Option<(RedisType, u64)> {
-    option_get_or_return_none!(type_byte, byte_stream::read_byte(reader));
+fn pairs(reader: &mut impl Read) -> RedisResult<(Vec<(RedisType, RedisType)>, u64)> {
+    let (length, mut bytes_read) = scan_int(reader)?;
+    if length > MAX_AGGREGATE_LENGTH {
+        return Err(RedisError::Protocol("invalid map length".to_owned()));
+    }
+    let length = length.max(0) as usize;
+    let mut pairs = Vec::with_capacity(length.min(1024));
+    for _ in 0..length {
+        let (key, bytes_read_for_key) = decode(reader)?;
+        let (value, bytes_read_for_value) = decode(reader)?;
+        bytes_read += bytes_read_for_key + bytes_read_for_value;
+        pairs.push((key, value));
+    }
+    Ok((pairs, bytes_read))
+}
+
+fn aggregate(reader: &mut impl Read) -> RedisResult<(Vec<RedisType>, u64)> {
+    let (length, mut bytes_read) = scan_int(reader)?;
+    if length > MAX_AGGREGATE_LENGTH {
+        return Err(RedisError::Protocol("invalid multibulk length".to_owned()));
+    }
+    let length = length.max(0) as usize; // null arrays decode as empty
+    let mut array: Vec<RedisType> = Vec::with_capacity(length.min(1024));
+    for _ in 0..length {
+        let (value, bytes_read_for_value) = decode(reader)?;
+        bytes_read += bytes_read_for_value;
+        array.push(value);
+    }
+    Ok((array, bytes_read))
+}
+
+/// a client request, which is always an array of bulk strings. Read flat rather than through
+/// `decode`, so nested arrays can't recurse until the stack runs out
+pub fn decode_command(reader: &mut impl Read) -> RedisResult<(Vec<Vec<u8>>, u64)> {
+    if byte_stream::read_byte(reader)? != b'*' {
+        return Err(RedisError::Protocol("expected '*'".to_owned()));
+    }
+    let (length, mut bytes_read) = scan_int(reader)?;
+    if length > MAX_AGGREGATE_LENGTH {
+        return Err(RedisError::Protocol("invalid multibulk length".to_owned()));
+    }
+    let length = length.max(0) as usize;
+    let mut arguments = Vec::with_capacity(length.min(1024));
+    for _ in 0..length {
+        if byte_stream::read_byte(reader)? != b'$' {
+            return Err(RedisError::Protocol("expected '$'".to_owned()));
+        }
+        let (string, bytes_read_for_string) = bulk_string(reader)?;
+        if string.is_none() {
+            return Err(RedisError::Protocol("expected '$'".to_owned()));
+        }
+        bytes_read += bytes_read_for_string + 1;
+        arguments.push(string.unwrap());
+    }
+    Ok((arguments, bytes_read + 1))
+}
+
+pub fn decode(reader: &mut impl Read) -> RedisResult<(RedisType, u64)> {
+    let type_byte = byte_stream::read_byte(reader)?;
 
     match type_byte {
         // simple strings
         b'+' => {
-            option_get_or_return_none!(string_and_count, scan_string(reader));
-            let (string, bytes_read) = string_and_count;
-            return Some((RedisType::SimpleString(string), bytes_read + 1));
+            let (string, bytes_read) = scan_string(reader)?;
+            Ok((RedisType::SimpleString(string), bytes_read + 1))
         }
         // simple errors
         b'-' => {
-            option_get_or_return_none!(string_and_count, scan_string(reader));
-            let (string, bytes_read) = string_and_count;
-            return Some((RedisType::SimpleError(string), bytes_read + 1));
+            let (string, bytes_read) = scan_string(reader)?;
+            Ok((RedisType::SimpleError(string), bytes_read + 1))
         }
         // integers
         b':' => {
-            option_get_or_return_none!(integer_and_count, scan_int(reader));
-            let (integer, bytes_read) = integer_and_count;
-            return Some((RedisType::Integer(integer), bytes_read + 1));
+            let (integer, bytes_read) = scan_int(reader)?;
+            Ok((RedisType::Integer(integer), bytes_read + 1))
         }
         // bulk strings
         b'$' => {
-            option_get_or_return_none!(string_and_count, bulk_string(reader));
-            let (string, bytes_read) = string_and_count;
-            return Some((RedisType::BulkString(string), bytes_read + 1));
+            let (string, bytes_read) = bulk_string(reader)?;
+            Ok((RedisType::BulkString(string), bytes_read + 1))
         }
         // arrays
         b'*' => {
-            option_get_or_return_none!(length_and_count, scan_int(reader));
-            let (length, mut bytes_read) = length_and_count;
-            let mut array: Vec<RedisType> = Vec::with_capacity(length as usize);
-            for _ in 0..length {
-                option_get_or_return_none!(value_and_count, decode(reader));
-                let (value, bytes_read_for_value) = value_and_count;
-                bytes_read += bytes_read_for_value;
-                array.push(value);
-            }
-            return Some((RedisType::Array(array), bytes_read + 1));
+            let (array, bytes_read) = aggregate(reader)?;
+            Ok((RedisType::Array(array), bytes_read + 1))
         }
         // nulls
         b'_' => {
-            option_get_or_return_none!(_unused, byte_stream::read_n_bytes(reader, 2)); // skip the crlf for the next decode
-            return Some((RedisType::Null, 3));
+            byte_stream::read_n_bytes(reader, 2)?; // skip the crlf for the next decode
+            Ok((RedisType::Null, 3))
         }
         // booleans
         b'#' => {
-            option_get_or_return_none!(boolean_byte, byte_stream::read_byte(reader));
-            match boolean_byte {
-                b't' => return Some((RedisType::Boolean(true), 4)),
-                b'f' => return Some((RedisType::Boolean(false), 4)),
-                _ => return None,
+            let (string, bytes_read) = scan_string(reader)?;
+            match string.as_str() {
+                "t" => Ok((RedisType::Boolean(true), bytes_read + 1)),
+                "f" => Ok((RedisType::Boolean(false), bytes_read + 1)),
+                _ => Err(RedisError::Protocol(format!("invalid boolean '{string}'"))),
             }
         }
         // doubles
         b',' => {
-            option_get_or_return_none!(string_and_count, scan_string(reader));
-            let (string, bytes_read) = string_and_count;
-            result_get_or_return_none!(double, str::parse::<f64>(&string));
-            return Some((RedisType::Double(double), bytes_read + 1));
+            let (string, bytes_read) = scan_string(reader)?;
+            let double = str::parse::<f64>(&string)
+                .map_err(|_| RedisError::Protocol(format!("invalid double '{string}'")))?;
+            Ok((RedisType::Double(double), bytes_read + 1))
         }
         // big numbers
         b'(' => {
-            option_get_or_return_none!(string_and_count, scan_string(reader));
-            let (string, bytes_read) = string_and_count;
-            result_get_or_return_none!(big_int, BigInt::from_str(&string));
-            return Some((RedisType::BigNumber(big_int), bytes_read + 1));
+            let (string, bytes_read) = scan_string(reader)?;
+            let big_int = BigInt::from_str(&string)
+                .map_err(|_| RedisError::Protocol(format!("invalid big number '{string}'")))?;
+            Ok((RedisType::BigNumber(big_int), bytes_read + 1))
         }
         // bulk errors
         b'!' => {
-            option_get_or_return_none!(error_option_and_count, bulk_string(reader));
-            let (error_option, bytes_read) = error_option_and_count;
-            option_get_or_return_none!(error, error_option);
-            return Some((RedisType::BulkError(error), bytes_read + 1));
+            let (error_option, bytes_read) = bulk_string(reader)?;
+            let error =
+                error_option.ok_or_else(|| RedisError::Protocol("null bulk error".to_owned()))?;
+            Ok((
+                RedisType::BulkError(String::from_utf8_lossy(&error).into_owned()),
+                bytes_read + 1,
+            ))
         }
         // verbatim strings
         b'=' => {
-            option_get_or_return_none!(string_option_and_count, bulk_string(reader));
-            let (string_option, bytes_read) = string_option_and_count;
-            option_get_or_return_none!(string, string_option);
-            return Some((RedisType::VerbatimString(string), bytes_read + 1));
+            let (string_option, bytes_read) = bulk_string(reader)?;
+            let string = string_option
+                .ok_or_else(|| RedisError::Protocol("null verbatim string".to_owned()))?;
+            Ok((
+                RedisType::VerbatimString(String::from_utf8_lossy(&string).into_owned()),
+                bytes_read + 1,
+            ))
         }
-        // TODO fix hashing and equals issues on RedisType
-        /*// maps
+        // maps
         b'%' => {
-            option_get_or_return_none!(size, scan_int(reader));
-            let mut map: HashMap<RedisType, RedisType> = HashMap::with_capacity(size as usize);
-            for _ in 0..size {
-                option_get_or_return_none!(key, decode(reader));
-                option_get_or_return_none!(value, decode(reader));
-                map.insert(key, value);
-            }
-            return Some(RedisType::Map(map));
+            let (map, bytes_read) = pairs(reader)?;
+            Ok((RedisType::Map(map), bytes_read + 1))
         }
         // sets
         b'~' => {
-            option_get_or_return_none!(size, scan_int(reader));
-            let mut set: HashSet<RedisType> = HashSet::with_capacity(size as usize);
-            for _ in 0..size {
-                option_get_or_return_none!(value, decode(reader));
-                set.insert(value);
-            }
-            return Some(RedisType::Set(set));
-        }*/
+            let (set, bytes_read) = aggregate(reader)?;
+            Ok((RedisType::Set(set), bytes_read + 1))
+        }
+        // attributes, which always come right before the reply they describe
+        b'|' => {
+            let (attributes, bytes_read) = pairs(reader)?;
+            let (value, bytes_read_for_value) = decode(reader)?;
+            Ok((
+                RedisType::Attribute(attributes, Box::new(value)),
+                bytes_read + bytes_read_for_value + 1,
+            ))
+        }
         // pushes
         b'>' => {
-            option_get_or_return_none!(length_and_count, scan_int(reader));
-            let (length, mut bytes_read) = length_and_count;
-            let mut array: Vec<RedisType> = Vec::with_capacity(length as usize);
-            for _ in 0..length {
-                option_get_or_return_none!(value_and_count, decode(reader));
-                let (value, bytes_read_for_value) = value_and_count;
-                bytes_read += bytes_read_for_value;
-                array.push(value);
+            let (array, bytes_read) = aggregate(reader)?;
+            Ok((RedisType::Push(array), bytes_read + 1))
+        }
+        _ => Err(RedisError::Protocol(format!(
+            "unexpected type byte {:?}",
+            type_byte as char
+        ))),
+    }
+}
+
+/// encodes into a fresh buffer, frozen so it can be cheaply shared between several receivers
+pub fn encode(data: &RedisType) -> Bytes {
+    let mut buffer = BytesMut::new();
+    encode_into(data, &mut buffer);
+    buffer.freeze()
+}
+
+/// appends the encoding of `data` to `buffer`, so a connection can keep reusing the same one
+pub fn encode_into(data: &RedisType, buffer: &mut BytesMut) {
+    match data {
+        RedisType::SimpleString(string) => put_simple_string(buffer, string),
+        RedisType::SimpleError(error) => put_simple_error(buffer, error),
+        RedisType::Integer(integer) => put_integer(buffer, *integer),
+        RedisType::BulkString(string) => put_bulk_string(buffer, string.as_deref()),
+        RedisType::Array(array) => put_aggregate(buffer, '*', array),
+        RedisType::Null => put_null(buffer),
+        RedisType::Boolean(boolean) => put_boolean(buffer, *boolean),
+        RedisType::Double(double) => put_double(buffer, *double),
+        RedisType::BigNumber(big_number) => put_big_number(buffer, big_number),
+        RedisType::BulkError(bulk_error) => put_bulk_error(buffer, bulk_error.as_bytes()),
+        RedisType::VerbatimString(string) => put_verbatim_string(buffer, string.as_bytes()),
+        RedisType::Map(map) => put_pairs(buffer, '%', map),
+        RedisType::Set(set) => put_aggregate(buffer, '~', set),
+        RedisType::Attribute(attributes, value) => {
+            put_pairs(buffer, '|', attributes);
+            encode_into(value, buffer);
+        }
+        RedisType::Push(push) => put_push(buffer, push),
+    };
+}
+
+/// rewrites a reply for the protocol version the client negotiated. Handlers always build the
+/// RESP3 shape, RESP2 clients get the types it has no syntax for folded into the ones it does.
+pub fn for_protocol(data: RedisType, protover: u8) -> RedisType {
+    if protover >= 3 {
+        return match data {
+            RedisType::BulkString(None) => RedisType::Null,
+            RedisType::Array(array) => RedisType::Array(
+                array
+                    .into_iter()
+                    .map(|item| for_protocol(item, protover))
+                    .collect(),
+            ),
+            RedisType::Map(map) => RedisType::Map(
+                map.into_iter()
+                    .map(|(key, value)| {
+                        (for_protocol(key, protover), for_protocol(value, protover))
+                    })
+                    .collect(),
+            ),
+            RedisType::Set(set) => RedisType::Set(
+                set.into_iter()
+                    .map(|item| for_protocol(item, protover))
+                    .collect(),
+            ),
+            RedisType::Push(push) => RedisType::Push(
+                push.into_iter()
+                    .map(|item| for_protocol(item, protover))
+                    .collect(),
+            ),
+            data => data,
+        };
+    }
+
+    match data {
+        RedisType::Null => RedisType::BulkString(None),
+        RedisType::Boolean(boolean) => RedisType::Integer(boolean as i64),
+        RedisType::Double(double) => RedisType::BulkString(Some(double.to_string().into())),
+        RedisType::BigNumber(big_number) => {
+            RedisType::BulkString(Some(big_number.to_string().into()))
+        }
+        RedisType::BulkError(error) => RedisType::SimpleError(error),
+        // drop the 3 character format and the colon
+        RedisType::VerbatimString(string) => {
+            RedisType::BulkString(Some(string.get(4..).unwrap_or_default().into()))
+        }
+        RedisType::Map(map) => {
+            let mut array = Vec::with_capacity(map.len() * 2);
+            for (key, value) in map {
+                array.push(for_protocol(key, protover));
+                array.push(for_protocol(value, protover));
             }
-            return Some((RedisType::Push(array), bytes_read + 1));
+            RedisType::Array(array)
         }
-        _ => {
-            println!("data type {:?} not handled", type_byte);
-            return None;
+        RedisType::Attribute(_, value) => for_protocol(*value, protover),
+        RedisType::Array(array) | RedisType::Set(array) | RedisType::Push(array) => {
+            RedisType::Array(
+                array
+                    .into_iter()
+                    .map(|item| for_protocol(item, protover))
+                    .collect(),
+            )
         }
+        data => data,
     }
 }
 
-pub fn encode(data: &RedisType) -> String {
-    return match data {
-        RedisType::SimpleString(string) => encode_simple_string(string),
-        RedisType::SimpleError(error) => encode_simple_error(error),
-        RedisType::Integer(integer) => encode_integer(*integer),
-        RedisType::BulkString(string) => encode_bulk_string(string.as_ref().map(|x| x.as_str())),
-        RedisType::Array(array) => encode_array(array),
-        RedisType::Null => encode_null(),
-        RedisType::Boolean(boolean) => encode_boolean(*boolean),
-        RedisType::Double(double) => encode_double(*double),
-        RedisType::BigNumber(big_number) => encode_big_number(big_number),
-        RedisType::BulkError(bulk_error) => encode_bulk_error(bulk_error),
-        RedisType::VerbatimString(string) => encode_verbatim_string(string),
-        RedisType::Push(push) => encode_push(push),
-    };
+/// RESP2 null array, used for aborted transactions and timed out blocking reads
+pub const NULL_ARRAY: &[u8] = b"*-1\r\n";
+
+// BytesMut implements fmt::Write, and writing into it can't fail
+fn put_line(buffer: &mut BytesMut, prefix: char, value: impl Display) {
+    let _ = write!(buffer, "{prefix}{value}\r\n");
+}
+
+fn put_blob(buffer: &mut BytesMut, prefix: char, payload: &[u8]) {
+    put_line(buffer, prefix, payload.len());
+    buffer.put_slice(payload);
+    buffer.put_slice(b"\r\n");
+}
+
+pub fn put_simple_string(buffer: &mut BytesMut, string: &str) {
+    put_line(buffer, '+', string);
 }
 
-pub fn encode_simple_string(string: &str) -> String {
-    return format!("+{string}\r\n");
+pub fn put_simple_error(buffer: &mut BytesMut, error: &str) {
+    put_line(buffer, '-', error);
 }
 
-pub fn encode_simple_error(error: &str) -> String {
-    return format!("-{error}\r\n");
+pub fn put_integer(buffer: &mut BytesMut, integer: i64) {
+    put_line(buffer, ':', integer);
 }
 
-pub fn encode_integer(integer: i64) -> String {
-    return format!(":{integer}\r\n");
+/// bulk strings are binary safe, so this takes raw bytes
+pub fn put_bulk_string(buffer: &mut BytesMut, payload_option: Option<&[u8]>) {
+    match payload_option {
+        Some(payload) => put_blob(buffer, '$', payload),
+        None => buffer.put_slice(b"$-1\r\n"), // null bulk string
+    }
 }
 
-pub fn encode_bulk_string(string_option: Option<&str>) -> String {
-    if string_option.is_none() {
-        return "$-1\r\n".to_owned(); // null bulk string
+fn put_aggregate(buffer: &mut BytesMut, prefix: char, items: &Vec<RedisType>) {
+    put_line(buffer, prefix, items.len());
+    for item in items {
+        encode_into(item, buffer);
     }
-    let string = string_option.unwrap();
-    let length = string.len();
-    return format!("${length}\r\n{string}\r\n");
 }
 
-pub fn encode_array(array: &Vec<RedisType>) -> String {
-    let length = array.len();
-    let mut result = format!("*{length}\r\n");
-    for item in array {
-        result += &encode(&item);
+pub fn put_null(buffer: &mut BytesMut) {
+    buffer.put_slice(b"_\r\n");
+}
+
+pub fn put_boolean(buffer: &mut BytesMut, boolean: bool) {
+    buffer.put_slice(if boolean { b"#t\r\n" } else { b"#f\r\n" });
+}
+
+pub fn put_double(buffer: &mut BytesMut, double: f64) {
+    if double.is_nan() {
+        buffer.put_slice(b",nan\r\n"); // rust would write NaN
+    } else {
+        put_line(buffer, ',', double); // infinities already come out as inf and -inf
     }
-    return result;
 }
 
-pub fn encode_null() -> String {
-    return "_\r\n".to_string();
+pub fn put_big_number(buffer: &mut BytesMut, big_number: &BigInt) {
+    put_line(buffer, '(', big_number);
 }
 
-pub fn encode_boolean(boolean: bool) -> String {
-    let boolean_char = if boolean { "t" } else { "f" };
-    return format!("#{boolean_char}\r\n");
+pub fn put_bulk_error(buffer: &mut BytesMut, bulk_error: &[u8]) {
+    put_blob(buffer, '!', bulk_error);
 }
 
-pub fn encode_double(double: f64) -> String {
-    return format!(",{double}\r\n");
+pub fn put_verbatim_string(buffer: &mut BytesMut, payload: &[u8]) {
+    put_blob(buffer, '=', payload);
 }
 
-pub fn encode_big_number(big_number: &BigInt) -> String {
-    let big_number_string = big_number.to_string();
-    return format!("({big_number_string}\r\n");
+fn put_pairs(buffer: &mut BytesMut, prefix: char, pairs: &Vec<(RedisType, RedisType)>) {
+    put_line(buffer, prefix, pairs.len());
+    for (key, value) in pairs {
+        encode_into(key, buffer);
+        encode_into(value, buffer);
+    }
 }
 
-pub fn encode_bulk_error(bulk_error: &str) -> String {
-    let length = bulk_error.len();
-    return format!("!{length}\r\n{bulk_error}\r\n");
+pub fn put_push(buffer: &mut BytesMut, push: &Vec<RedisType>) {
+    put_aggregate(buffer, '>', push);
 }
 
-pub fn encode_verbatim_string(string: &str) -> String {
-    let length = string.len();
-    return format!("={length}\r\n{string}\r\n");
+/// An out of band message (pub/sub, invalidations, MONITOR) encoded once for each protocol
+/// version, so it can be fanned out to many clients without encoding it again for each.
+pub struct PushMessage {
+    pub resp2: Bytes,
+    pub resp3: Bytes,
 }
 
-pub fn encode_push(push: &Vec<RedisType>) -> String {
-    let length = push.len();
-    let mut result = format!("*{length}\r\n");
-    for item in push {
-        result += &encode(&item);
+impl PushMessage {
+    /// RESP2 connections get the same items as a plain array
+    pub fn new(items: Vec<RedisType>) -> PushMessage {
+        let push = RedisType::Push(items);
+        let resp3 = encode(&push);
+        PushMessage {
+            resp2: encode(&for_protocol(push, 2)),
+            resp3,
+        }
+    }
+
+    /// for messages that RESP2 connections receive in some other shape
+    pub fn with_resp2(resp2: &RedisType, items: Vec<RedisType>) -> PushMessage {
+        PushMessage {
+            resp2: encode(resp2),
+            resp3: encode(&RedisType::Push(items)),
+        }
     }
-    return result;
 }
 
 // RDB data is special and shares a signifier byte with bulk strings, so I'm keeping these out of the generic encode/decode methods
-pub fn decode_rdb(reader: &mut impl Read) -> Vec<u8> {
-    byte_stream::read_byte(reader); // discard type bit
-    let length = scan_int(reader).unwrap().0;
-    return byte_stream::read_n_bytes(reader, length as usize).unwrap();
+/// the random marker a diskless sync puts before and after an RDB file of unknown length
+pub const EOF_MARKER_LENGTH: usize = 40;
+
+/// `$<length>\r\n` and the RDB file, or `$EOF:<marker>\r\n`, the file and the marker again
+pub fn decode_rdb(reader: &mut impl Read) -> RedisResult<Vec<u8>> {
+    let type_byte = byte_stream::read_byte(reader)?;
+    if type_byte != b'$' {
+        return Err(RedisError::Protocol(format!(
+            "expected '$' before rdb, got {:?}",
+            type_byte as char
+        )));
+    }
+    let (line, _) = scan_string(reader)?;
+    if let Some(marker) = line.strip_prefix("EOF:") {
+        let marker = marker.as_bytes();
+        if marker.len() != EOF_MARKER_LENGTH {
+            return Err(RedisError::Protocol("invalid rdb eof marker".to_owned()));
+        }
+        // the marker could start anywhere in what was read so far, never reading more than what
+        // would complete it keeps the commands after it in the stream
+        let mut rdb = Vec::new();
+        let mut needed = EOF_MARKER_LENGTH;
+        loop {
+            rdb.extend(byte_stream::read_n_bytes(reader, needed)?);
+            if rdb.ends_with(marker) {
+                break;
+            }
+            let overlap = (1..EOF_MARKER_LENGTH)
+                .rev()
+                .find(|n| rdb.ends_with(&marker[..*n]))
+                .unwrap_or(0);
+            needed = EOF_MARKER_LENGTH - overlap;
+        }
+        rdb.truncate(rdb.len() - EOF_MARKER_LENGTH);
+        return Ok(rdb);
+    }
+    let length = str::parse::<i64>(&line)
+        .map_err(|_| RedisError::Protocol(format!("invalid integer '{line}'")))?;
+    if length < 0 {
+        return Err(RedisError::Protocol("invalid rdb length".to_owned()));
+    }
+    byte_stream::read_n_bytes(reader, length as usize)
 }
 
-pub fn encode_rdb(stream: &mut impl Read) -> Vec<u8> {
+pub fn encode_rdb(stream: &mut impl Read) -> RedisResult<Bytes> {
     let mut rdb_contents = Vec::new();
-    let size = stream.read_to_end(&mut rdb_contents).unwrap();
-    let mut result_bytes = Vec::from(format!("${size}\r\n").as_bytes());
-    result_bytes.append(&mut rdb_contents);
-    return result_bytes;
+    stream.read_to_end(&mut rdb_contents)?;
+    let mut buffer = BytesMut::with_capacity(rdb_contents.len() + 16);
+    put_line(&mut buffer, '$', rdb_contents.len()); // no trailing crlf, unlike a bulk string
+    buffer.put_slice(&rdb_contents);
+    Ok(buffer.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// hands out at most `chunk` bytes per read, like a socket delivering a payload in pieces
+    struct ChunkedReader<'a> {
+        bytes: &'a [u8],
+        chunk: usize,
+    }
+
+    impl Read for ChunkedReader<'_> {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.chunk.min(buffer.len()).min(self.bytes.len());
+            buffer[..n].copy_from_slice(&self.bytes[..n]);
+            self.bytes = &self.bytes[n..];
+            Ok(n)
+        }
+    }
+
+    const MARKER: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn eof_payload(rdb: &[u8]) -> Vec<u8> {
+        let mut payload = format!("$EOF:{MARKER}\r\n").into_bytes();
+        payload.extend_from_slice(rdb);
+        payload.extend_from_slice(MARKER.as_bytes());
+        payload
+    }
+
+    #[test]
+    fn decode_rdb_with_length() {
+        let mut payload: &[u8] = b"$9\r\nREDIS0011*3\r\n";
+        assert_eq!(decode_rdb(&mut payload).unwrap(), b"REDIS0011");
+        assert_eq!(payload, b"*3\r\n"); // the command stream that follows is left alone
+    }
+
+    #[test]
+    fn decode_rdb_with_eof_marker() {
+        let rdb = b"REDIS0011\xfa\x00\xff\x01\x02\x03";
+        let mut payload = eof_payload(rdb);
+        payload.extend_from_slice(b"*1\r\n");
+        let mut reader = payload.as_slice();
+        assert_eq!(decode_rdb(&mut reader).unwrap(), rdb);
+        assert_eq!(reader, b"*1\r\n");
+    }
+
+    #[test]
+    fn decode_rdb_with_eof_marker_split_across_reads() {
+        let rdb = b"REDIS0011 some keys and values";
+        let payload = eof_payload(rdb);
+        for chunk in [1, 3, 7, 16, 41] {
+            let mut reader = ChunkedReader {
+                bytes: &payload,
+                chunk,
+            };
+            assert_eq!(decode_rdb(&mut reader).unwrap(), rdb, "chunk {chunk}");
+        }
+    }
+
+    #[test]
+    fn decode_rdb_with_part_of_the_marker_in_the_rdb() {
+        let rdb = format!("REDIS0011{}", &MARKER[..10]).into_bytes();
+        let mut payload = eof_payload(&rdb);
+        payload.extend_from_slice(b"+PING\r\n");
+        let mut reader = payload.as_slice();
+        assert_eq!(decode_rdb(&mut reader).unwrap(), rdb);
+        assert_eq!(reader, b"+PING\r\n");
+    }
+
+    #[test]
+    fn decode_rdb_with_eof_marker_rejects_bad_input() {
+        let mut short_marker: &[u8] = b"$EOF:abc\r\nREDISabc";
+        assert!(matches!(
+            decode_rdb(&mut short_marker),
+            Err(RedisError::Protocol(_))
+        ));
+        let mut payload = eof_payload(b"REDIS0011");
+        payload.truncate(payload.len() - 1); // the connection closed before the closing marker
+        assert!(decode_rdb(&mut payload.as_slice()).is_err());
+    }
+
+    /// a small deterministic generator so failures can be reproduced
+    fn pseudo_random_bytes(seed: u32, length: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decode_rejects_malformed_input() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"$abc\r\n", "ERR Protocol error: invalid integer 'abc'"),
+            (b"$\r\n", "ERR Protocol error: invalid integer ''"),
+            (
+                b"$999999999999\r\n",
+                "ERR Protocol error: invalid bulk length",
+            ),
+            (
+                b"*9999999999999\r\n",
+                "ERR Protocol error: invalid multibulk length",
+            ),
+            (
+                b"%9999999999999\r\n",
+                "ERR Protocol error: invalid map length",
+            ),
+            (
+                b"$3\r\nabcde",
+                "ERR Protocol error: bulk string is missing its CRLF",
+            ),
+            (
+                b"*1\r\n$3\r\nabcde",
+                "ERR Protocol error: bulk string is missing its CRLF",
+            ),
+            (b":12x\r\n", "ERR Protocol error: invalid integer '12x'"),
+            (b"#x\r\n", "ERR Protocol error: invalid boolean 'x'"),
+            (b",abc\r\n", "ERR Protocol error: invalid double 'abc'"),
+            (b"(12a\r\n", "ERR Protocol error: invalid big number '12a'"),
+            (b"!-1\r\n", "ERR Protocol error: null bulk error"),
+            (b"=-1\r\n", "ERR Protocol error: null verbatim string"),
+            (b"+\xff\r\n", "ERR Protocol error: invalid utf-8 in line"),
+            (b"?\r\n", "ERR Protocol error: unexpected type byte '?'"),
+            (
+                b"*1\r\n?\r\n",
+                "ERR Protocol error: unexpected type byte '?'",
+            ),
+        ];
+        for (input, expected) in cases {
+            let result = decode(&mut &input[..]);
+            assert_eq!(
+                result.err().map(|error| error.to_string()).as_deref(),
+                Some(*expected),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn decode_keeps_bulk_strings_binary() {
+        let mut input: &[u8] = b"*2\r\n$3\r\n\xff\x00\r\r\n$-1\r\n";
+        let (value, bytes_read) = decode(&mut input).unwrap();
+        assert_eq!(bytes_read, 18);
+        assert!(input.is_empty());
+        assert!(matches!(
+            value,
+            RedisType::Array(values) if matches!(
+                values.as_slice(),
+                [RedisType::BulkString(Some(bytes)), RedisType::BulkString(None)]
+                    if bytes == b"\xff\x00\r"
+            )
+        ));
+    }
+
+    #[test]
+    fn decode_never_panics_on_garbage() {
+        let valid: &[&[u8]] = &[
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n",
+            b"%1\r\n+a\r\n:1\r\n",
+            b">2\r\n#t\r\n,1.5\r\n",
+            b"=8\r\ntxt:abcd\r\n",
+            b"!5\r\nERR x\r\n",
+        ];
+        for frame in valid {
+            assert!(decode(&mut &frame[..]).is_ok());
+            // every truncation of a valid frame, and every single byte of it replaced
+            for end in 0..frame.len() {
+                assert!(decode(&mut &frame[..end]).is_err());
+            }
+            for i in 0..frame.len() {
+                for byte in [b'\r', b'\n', b'*', b'$', b'-', b'9', 0xff] {
+                    let mut mutated = frame.to_vec();
+                    mutated[i] = byte;
+                    let _ = decode(&mut mutated.as_slice());
+                }
+            }
+        }
+        for seed in 0..2000 {
+            let mut bytes = pseudo_random_bytes(seed, (seed % 64) as usize);
+            let _ = decode(&mut bytes.as_slice());
+            if !bytes.is_empty() {
+                bytes[0] = b"*$%>~:+-=!#,(_"[seed as usize % 14];
+                let _ = decode(&mut bytes.as_slice());
+            }
+        }
+    }
 }
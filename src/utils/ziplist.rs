@@ -0,0 +1,131 @@
+use crate::error::{RedisError, RedisResult};
+
+// the encodings older redis versions kept small aggregates in, RDB files from them still have these
+// so they can be read, but nothing writes them anymore
+
+fn corrupt(encoding: &str) -> RedisError {
+    RedisError::Rdb(format!("corrupt {encoding}"))
+}
+
+fn take<'a>(
+    bytes: &'a [u8],
+    position: &mut usize,
+    n: usize,
+    encoding: &str,
+) -> RedisResult<&'a [u8]> {
+    let end = position.checked_add(n).ok_or_else(|| corrupt(encoding))?;
+    let slice = bytes.get(*position..end).ok_or_else(|| corrupt(encoding))?;
+    *position = end;
+    Ok(slice)
+}
+
+fn little_endian_signed(bytes: &[u8]) -> i64 {
+    let mut value: i64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        value |= (*byte as i64) << (i * 8);
+    }
+    let bits = bytes.len() * 8;
+    (value << (64 - bits)) >> (64 - bits) // sign extend
+}
+
+/// every entry of a ziplist, integers as their decimal form
+pub fn parse_ziplist(bytes: &[u8]) -> RedisResult<Vec<Vec<u8>>> {
+    let mut position = 10; // total bytes, tail offset, entry count
+    let mut entries = Vec::new();
+    loop {
+        let previous_length = take(bytes, &mut position, 1, "ziplist")?[0];
+        if previous_length == 0xff {
+            return Ok(entries);
+        }
+        if previous_length == 0xfe {
+            take(bytes, &mut position, 4, "ziplist")?;
+        }
+
+        let encoding = take(bytes, &mut position, 1, "ziplist")?[0];
+        let entry = match encoding >> 6 {
+            0b00 => {
+                let length = (encoding & 0x3f) as usize;
+                take(bytes, &mut position, length, "ziplist")?.to_vec()
+            }
+            0b01 => {
+                let low = take(bytes, &mut position, 1, "ziplist")?[0];
+                let length = (((encoding & 0x3f) as usize) << 8) | low as usize;
+                take(bytes, &mut position, length, "ziplist")?.to_vec()
+            }
+            0b10 => {
+                let length = take(bytes, &mut position, 4, "ziplist")?;
+                let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+                take(bytes, &mut position, length, "ziplist")?.to_vec()
+            }
+            _ => {
+                let size = match encoding {
+                    0xc0 => 2,
+                    0xd0 => 4,
+                    0xe0 => 8,
+                    0xf0 => 3,
+                    0xfe => 1,
+                    0xf1..=0xfd => 0, // the value is in the encoding itself
+                    _ => return Err(corrupt("ziplist")),
+                };
+                if size == 0 {
+                    ((encoding & 0x0f) as i64 - 1).to_string().into_bytes()
+                } else {
+                    little_endian_signed(take(bytes, &mut position, size, "ziplist")?)
+                        .to_string()
+                        .into_bytes()
+                }
+            }
+        };
+        entries.push(entry);
+    }
+}
+
+/// a zipmap's field value pairs, the hash encoding from before ziplists
+pub fn parse_zipmap(bytes: &[u8]) -> RedisResult<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut position = 1; // entry count, only accurate below 254
+    let read_length = |position: &mut usize| -> RedisResult<Option<usize>> {
+        let length = take(bytes, position, 1, "zipmap")?[0];
+        match length {
+            0xff => Ok(None),
+            0xfe => {
+                let length = take(bytes, position, 4, "zipmap")?;
+                Ok(Some(u32::from_le_bytes(length.try_into().unwrap()) as usize))
+            }
+            _ => Ok(Some(length as usize)),
+        }
+    };
+
+    let mut pairs = Vec::new();
+    loop {
+        let field_length = read_length(&mut position)?;
+        if field_length.is_none() {
+            return Ok(pairs);
+        }
+        let field = take(bytes, &mut position, field_length.unwrap(), "zipmap")?.to_vec();
+        let value_length = read_length(&mut position)?.ok_or_else(|| corrupt("zipmap"))?;
+        let free = take(bytes, &mut position, 1, "zipmap")?[0] as usize;
+        let value = take(bytes, &mut position, value_length, "zipmap")?.to_vec();
+        take(bytes, &mut position, free, "zipmap")?;
+        pairs.push((field, value));
+    }
+}
+
+/// an intset's members, sorted integers of 2, 4 or 8 bytes each
+pub fn parse_intset(bytes: &[u8]) -> RedisResult<Vec<Vec<u8>>> {
+    let mut position = 0;
+    let header = take(bytes, &mut position, 8, "intset")?;
+    let size = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let length = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    if ![2, 4, 8].contains(&size) {
+        return Err(corrupt("intset"));
+    }
+    let mut members = Vec::new();
+    for _ in 0..length {
+        members.push(
+            little_endian_signed(take(bytes, &mut position, size, "intset")?)
+                .to_string()
+                .into_bytes(),
+        );
+    }
+    Ok(members)
+}